@@ -1,20 +1,30 @@
-use clap::Parser;
+use clap::{ArgGroup, Parser, Subcommand, ValueEnum};
+use porcel8::cartridge::CartridgeOptions;
+use porcel8::device::font::Font;
+use porcel8::device::framebuffer::Palette;
+use porcel8::device::frontend::ScriptedKeyEvent;
+use porcel8::disassembler::Syntax;
+use porcel8::platform::Platform;
+use porcel8::util::QuirkConfig;
 
 #[derive(Parser, Debug, Clone)]
-#[command(version, about, author)]
+#[command(version, about, author, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+#[command(group(ArgGroup::new("headless_length").args(["frames", "cycles"])))]
 pub struct Porcel8ProgramArgs {
+    #[command(subcommand)]
+    pub command: Option<Porcel8Command>,
     /// CHIP-8 rom file to load
-    pub filename: String,
+    #[arg(required = true)]
+    pub filename: Option<String>,
     #[arg(short, long, help = "Draw scale of window", default_value_t = 8f32)]
     pub draw_scale: f32,
     #[arg(
         short,
         long,
-        help = "Emulate new behaviour of instructions (As seen in Chip-48 and SuperChip8)",
-        default_value_t = true
+        help = "Emulate new behaviour of instructions (As seen in Chip-48 and SuperChip8), or the COSMAC VIP's with -n false. Defaults to true"
     )]
     /// Use updated CHIP-8 behaviours.
-    pub new_chip8_behaviour: bool,
+    pub new_chip8_behaviour: Option<bool>,
     #[arg(
         short='i',
         long,
@@ -23,10 +33,370 @@ pub struct Porcel8ProgramArgs {
     )]
     /// Halt on finding invalid instruction
     pub halt_on_invalid: bool,
-    /// Enable Instruction Throttling 
+    /// Limit emulation to 60 frames per second
     #[arg(short='t', default_value_t=true)]
     pub do_instruction_throttling: bool,
-    /// Target Instructions per second, if throttling is enabled
-    #[arg(short='r',long,default_value_t=750u64)]
-    pub ips_throttling_rate: u64
+    /// Target Instructions per second, executed as a fixed number of instructions per frame.
+    /// Defaults to the rate of the platform, or 750
+    #[arg(short='r',long)]
+    pub ips_throttling_rate: Option<u64>,
+    /// Instructions executed in each 60 Hz frame, instead of an instructions per second rate
+    #[arg(long, conflicts_with = "ips_throttling_rate")]
+    pub instructions_per_frame: Option<u32>,
+    /// Seed for the random number generator, to reproduce a run. A random seed is used otherwise
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// Emulate a specific CHIP-8 implementation. Quirks given on the command line override those of the platform
+    #[arg(short = 'p', long, value_enum, conflicts_with = "new_chip8_behaviour")]
+    pub platform: Option<PlatformArg>,
+    /// Scroll by half the pixels in low resolution mode, as SUPER-CHIP 1.1 did
+    #[arg(long, help_heading = "Quirks")]
+    pub lores_half_scroll: Option<bool>,
+    /// Set VF to the number of collided rows in high resolution mode, as SUPER-CHIP 1.1 did
+    #[arg(long, help_heading = "Quirks")]
+    pub count_collision_rows: Option<bool>,
+    /// Reset VF after 8XY1, 8XY2 and 8XY3
+    #[arg(long, help_heading = "Quirks")]
+    pub vf_reset: Option<bool>,
+    /// Increment the index register after FX55 and FX65
+    #[arg(long, help_heading = "Quirks")]
+    pub memory_increment: Option<bool>,
+    /// Shift VX in place with 8XY6 and 8XYE, instead of shifting VY into VX
+    #[arg(long, help_heading = "Quirks")]
+    pub shift_in_place: Option<bool>,
+    /// Jump to XNN + VX with BXNN, instead of NNN + V0
+    #[arg(long, help_heading = "Quirks")]
+    pub jump_with_vx: Option<bool>,
+    /// Set VF when FX1E overflows past the end of memory
+    #[arg(long, help_heading = "Quirks")]
+    pub index_overflow_flag: Option<bool>,
+    /// Wrap sprites around the edges of the screen, instead of clipping them
+    #[arg(long, help_heading = "Quirks")]
+    pub wrap_sprites: Option<bool>,
+    /// Wait for the next 60 Hz frame before drawing with DXYN
+    #[arg(long, help_heading = "Quirks")]
+    pub display_wait: Option<bool>,
+    /// Raw font file with 16 small glyphs, replacing the default font
+    #[arg(long)]
+    pub small_font: Option<String>,
+    /// Raw font file with 10 byte high big glyphs, replacing the default SUPER-CHIP font
+    #[arg(long)]
+    pub big_font: Option<String>,
+    /// Directory to persist RPL user flags in, defaults to the user data directory
+    #[arg(long)]
+    pub flags_dir: Option<String>,
+    /// Directory for the save state slots, defaults to the user data directory.
+    /// F1 to F4 save to slots 1 to 4 and F5 to F8 load them
+    #[arg(long)]
+    pub states_dir: Option<String>,
+    /// Start paused with a debugger reading commands from the standard input, such as step, continue and break
+    #[arg(long, default_value_t = false, conflicts_with = "headless")]
+    pub debug: bool,
+    /// Start paused with a GDB remote protocol server on this localhost port, for attaching a debugger
+    #[arg(long, conflicts_with_all = ["headless", "debug"])]
+    pub gdb: Option<u16>,
+    /// Start paused with a Debug Adapter Protocol server on this localhost port, for debugging from editors
+    #[arg(long, conflicts_with_all = ["headless", "debug", "gdb"])]
+    pub dap: Option<u16>,
+    /// Symbol map for the debug adapter, with ADDRESS LINE FILE entries for setting breakpoints by source line
+    #[arg(long, requires = "dap")]
+    pub symbols: Option<String>,
+    /// Seconds of play kept for rewinding with Backspace held down, 0 to disable rewinding
+    #[arg(long, default_value_t = 30)]
+    pub rewind_seconds: u32,
+    /// Colours for the background, plane 1, plane 2 and both planes, as comma separated hex values.
+    /// Defaults to the palette of an Octo cartridge, or 000000,ffffff,aaaaaa,555555
+    #[arg(long)]
+    pub palette: Option<Palette>,
+    /// Enable XO-CHIP extensions, including 64 KiB of memory
+    #[arg(long, default_value_t = false)]
+    pub xo_chip: bool,
+    /// Run without a window, audio or keyboard as fast as possible, then print the registers and display.
+    /// RPL flags are only loaded and saved if --flags-dir is given
+    #[arg(long, default_value_t = false, requires = "headless_length", help_heading = "Headless")]
+    pub headless: bool,
+    /// Number of frames to run in headless mode
+    #[arg(long, requires = "headless", help_heading = "Headless")]
+    pub frames: Option<u64>,
+    /// Number of instructions to run in headless mode
+    #[arg(long, requires = "headless", help_heading = "Headless")]
+    pub cycles: Option<u64>,
+    /// Key press (FRAME:+KEY) or release (FRAME:-KEY) in headless mode, with a hex key. May be repeated
+    #[arg(long, requires = "headless", help_heading = "Headless")]
+    pub key_event: Vec<ScriptedKeyEvent>,
+    /// File to write the final registers and display to in headless mode, instead of the standard output
+    #[arg(long, requires = "headless", help_heading = "Headless")]
+    pub dump: Option<String>,
+    /// Options of the Octo cartridge being run, used where the command line gives none
+    #[arg(skip)]
+    pub cartridge_options: Option<CartridgeOptions>,
+}
+
+/// Tools run instead of the emulator
+#[derive(Subcommand, Debug, Clone)]
+pub enum Porcel8Command {
+    /// Print the instructions of a ROM, separating code from data by following jumps and calls from the start
+    Disasm {
+        /// CHIP-8 rom file to disassemble
+        rom: String,
+        /// Assembly syntax of the mnemonics
+        #[arg(long, value_enum, default_value_t = SyntaxArg::Octo)]
+        syntax: SyntaxArg,
+    },
+    /// Assemble an Octo source file into a ROM
+    Asm {
+        /// Octo source file to assemble
+        source: String,
+        /// ROM file to write
+        #[arg(short, long)]
+        output: String,
+        /// Symbol map file to write, for debugging by source line with --dap and --symbols
+        #[arg(long)]
+        symbols: Option<String>,
+    },
+}
+
+/// Platform as given on the command line
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum PlatformArg {
+    /// CHIP-8 on the COSMAC VIP
+    #[value(name = "vip")]
+    CosmacVip,
+    /// CHIP-48 on the HP-48
+    #[value(name = "chip-48")]
+    Chip48,
+    /// SUPER-CHIP 1.0 on the HP-48
+    #[value(name = "schip-1.0")]
+    SuperChip10,
+    /// SUPER-CHIP 1.1 on the HP-48
+    #[value(name = "schip-1.1")]
+    SuperChip11,
+    /// SUPER-CHIP as implemented by modern interpreters such as Octo
+    #[value(name = "schip-modern")]
+    SuperChipModern,
+    /// XO-CHIP as implemented by Octo
+    #[value(name = "xo-chip")]
+    XoChip,
+}
+
+impl From<PlatformArg> for Platform {
+    fn from(platform: PlatformArg) -> Self {
+        match platform {
+            PlatformArg::CosmacVip => Platform::CosmacVip,
+            PlatformArg::Chip48 => Platform::Chip48,
+            PlatformArg::SuperChip10 => Platform::SuperChip10,
+            PlatformArg::SuperChip11 => Platform::SuperChip11,
+            PlatformArg::SuperChipModern => Platform::SuperChipModern,
+            PlatformArg::XoChip => Platform::XoChip,
+        }
+    }
+}
+
+/// Disassembly syntax as given on the command line
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum SyntaxArg {
+    /// The syntax of the Octo assembler, such as `v0 := 5`
+    Octo,
+    /// The classic syntax of Cowgod's CHIP-8 reference, such as `LD V0, #05`
+    Cowgod,
+}
+
+impl From<SyntaxArg> for Syntax {
+    fn from(syntax: SyntaxArg) -> Self {
+        match syntax {
+            SyntaxArg::Octo => Syntax::Octo,
+            SyntaxArg::Cowgod => Syntax::Cowgod,
+        }
+    }
+}
+
+impl Porcel8ProgramArgs {
+    const DEFAULT_IPS_THROTTLING_RATE: u64 = 750;
+
+    pub fn get_platform(&self) -> Option<Platform> {
+        self.platform.map(Platform::from)
+    }
+
+    /// Quirks of the selected platform or CHIP-8 behaviour, then of the Octo cartridge, then the new CHIP-8 behaviour,
+    /// with any quirks given on the command line overriding them
+    pub fn get_quirk_config(&self) -> QuirkConfig {
+        let base_quirks = match (self.get_platform(), self.new_chip8_behaviour) {
+            (Some(platform), _) => platform.get_quirks(),
+            (None, Some(true)) => QuirkConfig::new_chip8(),
+            (None, Some(false)) => QuirkConfig::original_chip8(),
+            (None, None) => self
+                .cartridge_options
+                .as_ref()
+                .map_or(QuirkConfig::new_chip8(), |cartridge_options| cartridge_options.quirks),
+        };
+        QuirkConfig {
+            vf_reset: self.vf_reset.unwrap_or(base_quirks.vf_reset),
+            memory_increment: self.memory_increment.unwrap_or(base_quirks.memory_increment),
+            shift_in_place: self.shift_in_place.unwrap_or(base_quirks.shift_in_place),
+            jump_with_vx: self.jump_with_vx.unwrap_or(base_quirks.jump_with_vx),
+            index_overflow_flag: self.index_overflow_flag.unwrap_or(base_quirks.index_overflow_flag),
+            lores_half_scroll: self.lores_half_scroll.unwrap_or(base_quirks.lores_half_scroll),
+            count_collision_rows: self.count_collision_rows.unwrap_or(base_quirks.count_collision_rows),
+            wrap_sprites: self.wrap_sprites.unwrap_or(base_quirks.wrap_sprites),
+            display_wait: self.display_wait.unwrap_or(base_quirks.display_wait),
+        }
+    }
+
+    /// Instructions per frame from the command line, the platform, the Octo cartridge or the default rate, in that order
+    pub fn get_instructions_per_frame(&self) -> u32 {
+        let ips_to_ipf = |ips: u64| ((ips + 30) / 60).max(1) as u32;
+        self.instructions_per_frame
+            .or(self.ips_throttling_rate.map(ips_to_ipf))
+            .or(self.get_platform().map(|platform| platform.get_instructions_per_frame()))
+            .or(self.cartridge_options.as_ref().map(|cartridge_options| cartridge_options.instructions_per_frame))
+            .unwrap_or(ips_to_ipf(Self::DEFAULT_IPS_THROTTLING_RATE))
+    }
+
+    /// An Octo cartridge asking for XO-CHIP always gets it, as its program may not fit otherwise
+    pub fn is_xo_chip(&self) -> bool {
+        self.xo_chip
+            || self.get_platform().is_some_and(|platform| platform.is_xo_chip())
+            || self.cartridge_options.as_ref().is_some_and(|cartridge_options| cartridge_options.is_xo_chip)
+    }
+
+    pub fn has_high_resolution(&self) -> bool {
+        self.get_platform().is_none_or(|platform| platform.has_high_resolution())
+    }
+
+    /// Palette from the command line, the Octo cartridge or the default palette, in that order
+    #[cfg_attr(not(feature = "sdl"), allow(dead_code, reason = "only the window draws in colour"))]
+    pub fn get_palette(&self) -> Palette {
+        self.palette
+            .or(self.cartridge_options.as_ref().map(|cartridge_options| cartridge_options.palette))
+            .unwrap_or_default()
+    }
+
+    /// Small and big fonts used unless font files are given
+    pub fn get_default_fonts(&self) -> (Font, Font) {
+        match self.get_platform() {
+            Some(platform) => platform.get_fonts(),
+            None => (Font::default_small(), Font::default_big()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::{Porcel8Command, Porcel8ProgramArgs, SyntaxArg};
+    use porcel8::cartridge::CartridgeOptions;
+    use porcel8::device::framebuffer::Palette;
+    use porcel8::util::QuirkConfig;
+
+    #[test]
+    fn test_default_quirks() {
+        let args = Porcel8ProgramArgs::parse_from(["porcel8", "rom.ch8"]);
+        assert_eq!(QuirkConfig::new_chip8(), args.get_quirk_config());
+    }
+
+    #[test]
+    fn test_original_chip8_behaviour() {
+        let args = Porcel8ProgramArgs::parse_from(["porcel8", "rom.ch8", "-n", "false"]);
+        assert_eq!(QuirkConfig::original_chip8(), args.get_quirk_config());
+        let args = Porcel8ProgramArgs::parse_from(["porcel8", "rom.ch8", "--new-chip8-behaviour", "true"]);
+        assert_eq!(QuirkConfig::new_chip8(), args.get_quirk_config());
+        assert!(Porcel8ProgramArgs::try_parse_from(["porcel8", "rom.ch8", "-n", "false", "-p", "vip"]).is_err());
+    }
+
+    #[test]
+    fn test_quirk_overrides() {
+        let args = Porcel8ProgramArgs::parse_from(["porcel8", "rom.ch8", "--vf-reset", "true", "--shift-in-place", "false", "--lores-half-scroll", "true"]);
+        let quirks = args.get_quirk_config();
+        assert!(quirks.vf_reset);
+        assert!(!quirks.shift_in_place);
+        assert!(quirks.jump_with_vx);
+        assert!(quirks.lores_half_scroll);
+        assert!(!quirks.count_collision_rows);
+
+        let args = Porcel8ProgramArgs::parse_from(["porcel8", "rom.ch8", "-p", "schip-1.1", "--lores-half-scroll", "false"]);
+        let quirks = args.get_quirk_config();
+        assert!(!quirks.lores_half_scroll);
+        assert!(quirks.count_collision_rows);
+    }
+
+    #[test]
+    fn test_platform_preset() {
+        let args = Porcel8ProgramArgs::parse_from(["porcel8", "rom.ch8", "--platform", "vip", "--memory-increment", "false"]);
+        let quirks = args.get_quirk_config();
+        assert!(quirks.vf_reset);
+        assert!(!quirks.memory_increment);
+        assert!(!args.has_high_resolution());
+        assert!(!args.is_xo_chip());
+        assert_eq!(11, args.get_instructions_per_frame());
+
+        let args = Porcel8ProgramArgs::parse_from(["porcel8", "rom.ch8", "-p", "xo-chip", "-r", "1000"]);
+        assert!(args.is_xo_chip());
+        assert_eq!(17, args.get_instructions_per_frame());
+
+        let args = Porcel8ProgramArgs::parse_from(["porcel8", "rom.ch8", "-p", "xo-chip", "--instructions-per-frame", "200"]);
+        assert_eq!(200, args.get_instructions_per_frame());
+    }
+
+    #[test]
+    fn test_headless_args() {
+        let args = Porcel8ProgramArgs::parse_from(["porcel8", "rom.ch8", "--headless", "--frames", "60", "--key-event", "10:+5", "--key-event", "20:-5"]);
+        assert!(args.headless);
+        assert_eq!(Some(60), args.frames);
+        assert_eq!(2, args.key_event.len());
+        assert!(Porcel8ProgramArgs::try_parse_from(["porcel8", "rom.ch8", "--frames", "60"]).is_err());
+        assert!(Porcel8ProgramArgs::try_parse_from(["porcel8", "rom.ch8", "--headless", "--frames", "1", "--cycles", "1"]).is_err());
+        assert!(Porcel8ProgramArgs::try_parse_from(["porcel8", "rom.ch8", "--headless"]).is_err());
+    }
+
+    #[test]
+    fn test_subcommands() {
+        let args = Porcel8ProgramArgs::parse_from(["porcel8", "disasm", "rom.ch8", "--syntax", "cowgod"]);
+        assert!(matches!(args.command, Some(Porcel8Command::Disasm { syntax: SyntaxArg::Cowgod, .. })));
+        assert!(args.filename.is_none());
+        assert!(Porcel8ProgramArgs::try_parse_from(["porcel8"]).is_err());
+
+        let args = Porcel8ProgramArgs::parse_from(["porcel8", "asm", "game.8o", "-o", "game.ch8"]);
+        assert!(matches!(args.command, Some(Porcel8Command::Asm { symbols: None, .. })));
+        assert!(Porcel8ProgramArgs::try_parse_from(["porcel8", "asm", "game.8o"]).is_err());
+    }
+
+    #[test]
+    fn test_cartridge_options() {
+        let options = CartridgeOptions {
+            instructions_per_frame: 100,
+            quirks: QuirkConfig { shift_in_place: false, wrap_sprites: false, ..QuirkConfig::new_chip8() },
+            is_xo_chip: true,
+            palette: "996600,ffcc00,ff6600,662200".parse().unwrap(),
+        };
+        let mut args = Porcel8ProgramArgs::parse_from(["porcel8", "game.gif", "--wrap-sprites", "true"]);
+        args.cartridge_options = Some(options.clone());
+        assert_eq!(100, args.get_instructions_per_frame());
+        assert!(!args.get_quirk_config().shift_in_place);
+        assert!(args.get_quirk_config().wrap_sprites);
+        assert!(args.is_xo_chip());
+        assert_eq!(options.palette, args.get_palette());
+
+        let mut args = Porcel8ProgramArgs::parse_from(["porcel8", "game.gif", "-r", "600", "--palette", "000000,ffffff,aaaaaa,555555"]);
+        args.cartridge_options = Some(options.clone());
+        assert_eq!(10, args.get_instructions_per_frame());
+        // an explicit palette wins even if it is the default one
+        assert_eq!(Palette::default(), args.get_palette());
+
+        // the platform replaces the cartridge quirks and rate, quirk flags still override the platform
+        let mut args = Porcel8ProgramArgs::parse_from(["porcel8", "game.gif", "-p", "vip", "--vf-reset", "false"]);
+        args.cartridge_options = Some(options.clone());
+        let quirks = args.get_quirk_config();
+        assert!(quirks.display_wait);
+        assert!(quirks.memory_increment);
+        assert!(!quirks.vf_reset);
+        assert_eq!(11, args.get_instructions_per_frame());
+    }
+
+    #[test]
+    fn test_default_instruction_rate() {
+        let args = Porcel8ProgramArgs::parse_from(["porcel8", "rom.ch8"]);
+        assert_eq!(13, args.get_instructions_per_frame());
+        assert!(args.has_high_resolution());
+    }
 }
@@ -0,0 +1,1056 @@
+use std::collections::HashMap;
+use std::f64::consts::{E, PI};
+
+use crate::device::instruction::Instruction;
+use crate::device::Device;
+use crate::rom;
+use crate::symbol_map::{SourceLocation, SymbolMap};
+use crate::util::{EmulatorError, EmulatorResult};
+
+/// Extension of Octo source files, which are assembled when loaded as a ROM
+pub const OCTO_SOURCE_EXTENSION: &str = "8o";
+
+/// A ROM assembled from source in the core language of the Octo assembler, with the source line of each instruction.
+///
+/// Supported are labels, `:next`, `:org`, `:const`, `:calc`, `:alias`, `:macro`, `:unpack`, `:call`,
+/// `:byte` and bare numbers as data, register and index operations, `if ... then`, `if ... begin ... else ... end`
+/// with `==`, `!=`, `<`, `>`, `<=`, `>=`, `key` and `-key`, `loop ... while ... again`
+/// and the SUPER-CHIP and XO-CHIP instructions. Other directives, such as `:stringmode`, are rejected.
+/// As in Octo, the program starts at the `main` label.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Program {
+    rom: Vec<u8>,
+    symbol_map: SymbolMap,
+}
+
+impl Program {
+    /// Assemble `source`, naming `file_name` as the source of each instruction in the symbol map
+    pub fn assemble(source: &str, file_name: &str) -> EmulatorResult<Program> {
+        Assembler::new(source, file_name).assemble().map_err(EmulatorError::InvalidAssembly)
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    pub fn symbol_map(&self) -> &SymbolMap {
+        &self.symbol_map
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Token<'a> {
+    text: &'a str,
+    line: u32,
+    /// How many macro expansions deep the token was produced, 0 for the source itself
+    macro_depth: usize,
+}
+
+/// How an address is written into an instruction
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum AddressWidth {
+    /// The NNN of a 2 byte instruction
+    Short,
+    /// The second half of F000 NNNN
+    Long,
+    /// The low nibble of the NN of `v0 := NN`, the high bits of a 12 bit address for `:unpack`
+    UnpackHigh,
+    /// The NN of `v0 := NN`, the high byte of an address for `:unpack long`
+    HighByte,
+    /// The NN of `v1 := NN`, the low byte of an address for `:unpack`
+    LowByte,
+}
+
+/// A use of a label before it is defined, filled in once all labels are known.
+/// The offset is that of the instruction holding the address.
+#[derive(Clone, Copy, Debug)]
+struct Reference<'a> {
+    offset: usize,
+    name: Token<'a>,
+    width: AddressWidth,
+}
+
+/// An open control flow block, closed by `end` or `again`
+#[derive(Clone, Debug)]
+enum Block {
+    /// Offset of the jump past the block, taken if the condition is false
+    If(usize),
+    /// Offset of the jump past the block, taken at the end of the if branch
+    Else(usize),
+    /// Start of the loop and offsets of the jumps out of it made by `while`
+    Loop(u16, Vec<usize>),
+}
+
+/// A `:macro`, called by name with one token for each of its parameters
+#[derive(Clone, Debug)]
+struct Macro<'a> {
+    parameters: Vec<&'a str>,
+    body: Vec<Token<'a>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Operand {
+    Register(usize),
+    Value(u8),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Condition {
+    Equal(usize, Operand),
+    NotEqual(usize, Operand),
+    KeyPressed(usize),
+    KeyNotPressed(usize),
+}
+
+impl Condition {
+    fn negate(self) -> Condition {
+        match self {
+            Condition::Equal(x, operand) => Condition::NotEqual(x, operand),
+            Condition::NotEqual(x, operand) => Condition::Equal(x, operand),
+            Condition::KeyPressed(x) => Condition::KeyNotPressed(x),
+            Condition::KeyNotPressed(x) => Condition::KeyPressed(x),
+        }
+    }
+
+    /// The instruction skipping the next one unless the condition holds
+    fn to_skip_instruction(self) -> Instruction {
+        match self {
+            Condition::Equal(x, Operand::Value(value)) => Instruction::ConditionalInEqSkipNext(x, value),
+            Condition::Equal(x, Operand::Register(y)) => Instruction::ConditionalInEqRegisterSkipNext(x, y),
+            Condition::NotEqual(x, Operand::Value(value)) => Instruction::ConditionalEqSkipNext(x, value),
+            Condition::NotEqual(x, Operand::Register(y)) => Instruction::ConditionalEqRegisterSkipNext(x, y),
+            Condition::KeyPressed(x) => Instruction::SkipIfKeyNotPressed(x),
+            Condition::KeyNotPressed(x) => Instruction::SkipIfKeyPressed(x),
+        }
+    }
+}
+
+/// Assembles in a single pass, filling in the addresses of labels used before their definition at the end
+struct Assembler<'a> {
+    tokens: Vec<Token<'a>>,
+    position: usize,
+    file_name: &'a str,
+    rom: Vec<u8>,
+    /// Offset in the ROM that the next instruction or data is written at, moved by `:org`
+    here: usize,
+    labels: HashMap<&'a str, u16>,
+    /// Constants from `:const` and `:calc`, which may have a fractional part as in Octo
+    constants: HashMap<&'a str, f64>,
+    aliases: HashMap<&'a str, usize>,
+    macros: HashMap<&'a str, Macro<'a>>,
+    references: Vec<Reference<'a>>,
+    /// Open blocks and the lines they were opened on
+    blocks: Vec<(Block, u32)>,
+    locations: Vec<SourceLocation>,
+}
+
+impl<'a> Assembler<'a> {
+    const MAIN_LABEL: &'static str = "main";
+    /// Deepest nesting of macro calls, to stop a macro that calls itself
+    const MAX_MACRO_DEPTH: usize = 64;
+
+    fn new(source: &'a str, file_name: &'a str) -> Assembler<'a> {
+        let tokens = source
+            .lines()
+            .enumerate()
+            .flat_map(|(index, line)| {
+                let code = line.split('#').next().unwrap_or_default();
+                code.split_whitespace().map(move |text| Token { text, line: index as u32 + 1, macro_depth: 0 })
+            })
+            .collect();
+        Assembler {
+            tokens,
+            position: 0,
+            file_name,
+            // a jump to main, removed if main is the first label
+            rom: Instruction::JumpTo(0).encode_instruction(),
+            here: 2,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            references: Vec::new(),
+            blocks: Vec::new(),
+            locations: Vec::new(),
+        }
+    }
+
+    fn assemble(mut self) -> Result<Program, String> {
+        while let Some(token) = self.next_token() {
+            self.assemble_statement(token)?;
+        }
+        if let Some((block, line)) = self.blocks.last() {
+            let message = match block {
+                Block::Loop(..) => "loop is never closed with again",
+                Block::If(_) | Block::Else(_) => "if is never closed with end",
+            };
+            return Err(format!("line {}: {}", line, message));
+        }
+        if !self.labels.contains_key(Self::MAIN_LABEL) {
+            return Err(format!("no {} label to start the program at", Self::MAIN_LABEL));
+        }
+        for reference in std::mem::take(&mut self.references) {
+            let address = match self.labels.get(reference.name.text) {
+                Some(address) => *address,
+                None => return Err(Self::error(reference.name, format!("undefined name {}", reference.name.text))),
+            };
+            self.patch_address(reference.offset, address, reference.width, reference.name)?;
+        }
+        if self.rom.len() > rom::XO_CHIP_ROM_SIZE {
+            return Err(format!("the program is larger than {} bytes", rom::XO_CHIP_ROM_SIZE));
+        }
+        Ok(Program {
+            rom: self.rom,
+            symbol_map: SymbolMap::new(self.locations),
+        })
+    }
+
+    fn assemble_statement(&mut self, token: Token<'a>) -> Result<(), String> {
+        let line = token.line;
+        match token.text {
+            ":" => {
+                let name = self.expect_token(token)?;
+                self.define_label(name)?;
+            }
+            ":next" => {
+                // names the second byte of the next instruction, for self-modifying code
+                let name = self.expect_token(token)?;
+                self.insert_label(name, self.address() + 1)?;
+            }
+            ":org" => {
+                let address_token = self.expect_token(token)?;
+                let address = self.value(address_token)?;
+                if !(Device::ROM_START as i32..=u16::MAX as i32).contains(&address) {
+                    return Err(Self::error(address_token, format!("{} is outside of the program", address_token.text)));
+                }
+                self.here = address as usize - Device::ROM_START;
+            }
+            ":const" => {
+                let name = self.expect_token(token)?;
+                let value_token = self.expect_token(name)?;
+                let value = self.value(value_token)?;
+                self.constants.insert(name.text, value as f64);
+            }
+            ":calc" => {
+                let name = self.expect_token(token)?;
+                let value = self.calc_block(name)?;
+                self.constants.insert(name.text, value);
+            }
+            ":macro" => self.define_macro(token)?,
+            ":alias" => {
+                let name = self.expect_token(token)?;
+                let register_token = self.expect_token(name)?;
+                let register = self.register(register_token)?;
+                self.aliases.insert(name.text, register);
+            }
+            ":byte" => {
+                let value = match self.peek_token() {
+                    Some(brace) if brace.text == "{" => {
+                        let value = self.calc_block(token)?;
+                        Self::to_byte(token, value.floor() as i32)?
+                    }
+                    _ => {
+                        let value_token = self.expect_token(token)?;
+                        self.byte(value_token)?
+                    }
+                };
+                self.emit_bytes(&[value]);
+            }
+            ":call" => {
+                let target = self.expect_token(token)?;
+                self.emit_with_address(line, target, AddressWidth::Short, Instruction::JumpAndLink)?;
+            }
+            ":unpack" => {
+                // v0 and v1 := the address of a label, below a nibble unless it is a long address
+                let nibble_token = self.expect_token(token)?;
+                let target = self.expect_token(nibble_token)?;
+                if nibble_token.text == "long" {
+                    self.emit_with_address(line, target, AddressWidth::HighByte, |address| Instruction::SetRegister(0, (address >> 8) as u8))?;
+                } else {
+                    let nibble = match self.value(nibble_token)? {
+                        value @ 0..=0xf => value as u8,
+                        _ => return Err(Self::error(nibble_token, format!("{} does not fit in 4 bits", nibble_token.text))),
+                    };
+                    self.emit_with_address(line, target, AddressWidth::UnpackHigh, |address| {
+                        Instruction::SetRegister(0, (nibble << 4) | (address >> 8) as u8)
+                    })?;
+                }
+                self.emit_with_address(line, target, AddressWidth::LowByte, |address| Instruction::SetRegister(1, address as u8))?;
+            }
+            ":breakpoint" => {
+                // pauses the Octo debugger, porcel8's debuggers set their own breakpoints
+                self.expect_token(token)?;
+            }
+            ":monitor" => {
+                // shows memory in the Octo debugger, with a length or a quoted format that may contain spaces
+                let address = self.expect_token(token)?;
+                let mut format = self.expect_token(address)?;
+                if format.text.starts_with('"') {
+                    while format.text.len() < 2 || !format.text.ends_with('"') {
+                        format = self.expect_token(format)?;
+                    }
+                }
+            }
+            "clear" => self.emit(Instruction::ClearScreen, line),
+            "return" | ";" => self.emit(Instruction::ReturnFromProcedure, line),
+            "exit" => self.emit(Instruction::Exit, line),
+            "lores" => self.emit(Instruction::DisableHighResolution, line),
+            "hires" => self.emit(Instruction::EnableHighResolution, line),
+            "scroll-left" => self.emit(Instruction::ScrollLeft, line),
+            "scroll-right" => self.emit(Instruction::ScrollRight, line),
+            "audio" => self.emit(Instruction::LoadAudioPattern, line),
+            "scroll-down" => {
+                let rows = self.expect_nibble(token)?;
+                self.emit(Instruction::ScrollDown(rows), line);
+            }
+            "plane" => {
+                let planes = self.expect_nibble(token)?;
+                if planes > 3 {
+                    return Err(Self::error(token, "plane takes 0 to 3".to_string()));
+                }
+                self.emit(Instruction::SelectPlanes(planes), line);
+            }
+            "jump" => {
+                let target = self.expect_token(token)?;
+                self.emit_with_address(line, target, AddressWidth::Short, Instruction::JumpTo)?;
+            }
+            "jump0" => {
+                let target = self.expect_token(token)?;
+                self.emit_with_address(line, target, AddressWidth::Short, |address| Instruction::JumpWithOffset((address >> 8) as usize, address))?;
+            }
+            "sprite" => {
+                let x = self.expect_register(token)?;
+                let y = self.expect_register(token)?;
+                let rows = self.expect_nibble(token)?;
+                self.emit(Instruction::Draw(x, y, rows), line);
+            }
+            "bcd" => {
+                let x = self.expect_register(token)?;
+                self.emit(Instruction::DoBCDConversion(x), line);
+            }
+            "saveflags" => {
+                let x = self.expect_register(token)?;
+                self.emit(Instruction::StoreRegistersToFlags(x), line);
+            }
+            "loadflags" => {
+                let x = self.expect_register(token)?;
+                self.emit(Instruction::LoadRegistersFromFlags(x), line);
+            }
+            "save" | "load" => {
+                let x = self.expect_register(token)?;
+                let range_end = match self.peek_token() {
+                    Some(dash) if dash.text == "-" => {
+                        self.position += 1;
+                        Some(self.expect_register(dash)?)
+                    }
+                    _ => None,
+                };
+                let instruction = match (token.text, range_end) {
+                    ("save", Some(y)) => Instruction::StoreRegisterRangeToMemory(x, y),
+                    ("save", None) => Instruction::StoreRegistersToMemory(x),
+                    (_, Some(y)) => Instruction::LoadRegisterRangeFromMemory(x, y),
+                    (_, None) => Instruction::LoadRegistersFromMemory(x),
+                };
+                self.emit(instruction, line);
+            }
+            "loop" => self.blocks.push((Block::Loop(self.address(), Vec::new()), line)),
+            "while" => {
+                let condition = self.condition(token)?;
+                let Some(loop_index) = self.blocks.iter().rposition(|(block, _)| matches!(block, Block::Loop(..))) else {
+                    return Err(Self::error(token, "while outside of a loop".to_string()));
+                };
+                self.emit(condition.negate().to_skip_instruction(), line);
+                let jump_offset = self.emit_jump_placeholder(line);
+                if let (Block::Loop(_, break_offsets), _) = &mut self.blocks[loop_index] {
+                    break_offsets.push(jump_offset);
+                }
+            }
+            "again" => {
+                let Some((Block::Loop(address, break_offsets), _)) = self.blocks.pop() else {
+                    return Err(Self::error(token, "again without a matching loop".to_string()));
+                };
+                self.emit(Instruction::JumpTo(address), line);
+                for offset in break_offsets {
+                    self.patch_address(offset, self.address(), AddressWidth::Short, token)?;
+                }
+            }
+            "if" => {
+                let condition = self.condition(token)?;
+                let keyword = self.expect_token(token)?;
+                match keyword.text {
+                    "then" => self.emit(condition.to_skip_instruction(), line),
+                    "begin" => {
+                        self.emit(condition.negate().to_skip_instruction(), line);
+                        let jump_offset = self.emit_jump_placeholder(line);
+                        self.blocks.push((Block::If(jump_offset), line));
+                    }
+                    _ => return Err(Self::error(keyword, format!("expected then or begin, found {}", keyword.text))),
+                }
+            }
+            "else" => {
+                let Some((Block::If(if_jump_offset), if_line)) = self.blocks.pop() else {
+                    return Err(Self::error(token, "else without a matching if begin".to_string()));
+                };
+                let jump_offset = self.emit_jump_placeholder(line);
+                self.patch_address(if_jump_offset, self.address(), AddressWidth::Short, token)?;
+                self.blocks.push((Block::Else(jump_offset), if_line));
+            }
+            "end" => {
+                let Some((Block::If(jump_offset) | Block::Else(jump_offset), _)) = self.blocks.pop() else {
+                    return Err(Self::error(token, "end without a matching if begin".to_string()));
+                };
+                self.patch_address(jump_offset, self.address(), AddressWidth::Short, token)?;
+            }
+            "i" => self.assemble_index_statement(token)?,
+            "delay" | "buzzer" | "pitch" => {
+                self.expect_operator(token, ":=")?;
+                let x = self.expect_register(token)?;
+                let instruction = match token.text {
+                    "delay" => Instruction::SetDelayTimer(x),
+                    "buzzer" => Instruction::SetSoundTimer(x),
+                    _ => Instruction::SetPitch(x),
+                };
+                self.emit(instruction, line);
+            }
+            text if self.is_register(text) => self.assemble_register_statement(token)?,
+            text if Self::parse_number(text).is_some() => {
+                let value = self.byte(token)?;
+                self.emit_bytes(&[value]);
+            }
+            text if self.macros.contains_key(text) => self.expand_macro(token)?,
+            text if text.starts_with(':') => return Err(Self::error(token, format!("unsupported directive {}", text))),
+            // any other name calls the label of that name
+            _ => self.emit_with_address(line, token, AddressWidth::Short, Instruction::JumpAndLink)?,
+        }
+        Ok(())
+    }
+
+    fn assemble_index_statement(&mut self, token: Token<'a>) -> Result<(), String> {
+        let operator = self.expect_token(token)?;
+        let operand = self.expect_token(operator)?;
+        match (operator.text, operand.text) {
+            (":=", "long") => {
+                let target = self.expect_token(operand)?;
+                self.emit_with_address(token.line, target, AddressWidth::Long, Instruction::LongSetIndex)?;
+            }
+            (":=", "hex") => {
+                let x = self.expect_register(operand)?;
+                self.emit(Instruction::SetIndexToFontCharacter(x), token.line);
+            }
+            (":=", "bighex") => {
+                let x = self.expect_register(operand)?;
+                self.emit(Instruction::SetIndexToBigFontCharacter(x), token.line);
+            }
+            (":=", _) => self.emit_with_address(token.line, operand, AddressWidth::Short, Instruction::SetIndex)?,
+            ("+=", _) => {
+                let x = self.register(operand)?;
+                self.emit(Instruction::AddToIndex(x), token.line);
+            }
+            _ => return Err(Self::error(operator, format!("unknown index operator {}", operator.text))),
+        }
+        Ok(())
+    }
+
+    fn assemble_register_statement(&mut self, token: Token<'a>) -> Result<(), String> {
+        let x = self.register(token)?;
+        let operator = self.expect_token(token)?;
+        let operand = self.expect_token(operator)?;
+        let operand_register = self.is_register(operand.text).then(|| self.register(operand)).transpose()?;
+        let instruction = match (operator.text, operand.text, operand_register) {
+            (":=", "random", _) => {
+                let mask_token = self.expect_token(operand)?;
+                Instruction::RandomAnd(x, self.byte(mask_token)?)
+            }
+            (":=", "delay", _) => Instruction::FetchDelayTimer(x),
+            (":=", "key", _) => Instruction::GetKey(x),
+            (":=", _, Some(y)) => Instruction::Set(x, y),
+            (":=", _, None) => Instruction::SetRegister(x, self.byte(operand)?),
+            ("+=", _, Some(y)) => Instruction::Add(x, y),
+            ("+=", _, None) => Instruction::AddValueToRegister(x, self.byte(operand)?),
+            ("-=", _, Some(y)) => Instruction::Sub(x, y),
+            ("-=", _, None) => Instruction::AddValueToRegister(x, self.byte(operand)?.wrapping_neg()),
+            ("=-", _, Some(y)) => Instruction::RSub(x, y),
+            ("|=", _, Some(y)) => Instruction::Or(x, y),
+            ("&=", _, Some(y)) => Instruction::And(x, y),
+            ("^=", _, Some(y)) => Instruction::Xor(x, y),
+            (">>=", _, Some(y)) => Instruction::RShift(x, y),
+            ("<<=", _, Some(y)) => Instruction::LShift(x, y),
+            ("=-" | "|=" | "&=" | "^=" | ">>=" | "<<=", _, None) => {
+                return Err(Self::error(operand, format!("expected a register, found {}", operand.text)));
+            }
+            _ => return Err(Self::error(operator, format!("unknown register operator {}", operator.text))),
+        };
+        self.emit(instruction, token.line);
+        Ok(())
+    }
+
+    /// `vx == n`, `vx != vy`, `vx < n`, `vx >= vy`, `vx key` or `vx -key`.
+    /// The ordering comparisons emit the instructions comparing through vf, as Octo does.
+    fn condition(&mut self, token: Token<'a>) -> Result<Condition, String> {
+        let x = self.expect_register(token)?;
+        let operator = self.expect_token(token)?;
+        let condition = match operator.text {
+            "key" => Condition::KeyPressed(x),
+            "-key" => Condition::KeyNotPressed(x),
+            "==" | "!=" => {
+                let operand_token = self.expect_token(operator)?;
+                let operand = self.operand(operand_token)?;
+                if operator.text == "==" {
+                    Condition::Equal(x, operand)
+                } else {
+                    Condition::NotEqual(x, operand)
+                }
+            }
+            "<" | ">" | "<=" | ">=" => {
+                let operand_token = self.expect_token(operator)?;
+                let operand = self.operand(operand_token)?;
+                if x == 0xf || matches!(operand, Operand::Register(0xf)) {
+                    return Err(Self::error(operator, format!("vf can not be compared with {}, the comparison overwrites it", operator.text)));
+                }
+                let set_flag_register = match operand {
+                    Operand::Register(y) => Instruction::Set(0xf, y),
+                    Operand::Value(value) => Instruction::SetRegister(0xf, value),
+                };
+                self.emit(set_flag_register, token.line);
+                // subtracting leaves 1 in vf unless it borrows: vf := operand - vx or vf := vx - operand
+                let (subtraction, holds_without_borrow) = match operator.text {
+                    "<=" | ">" => (Instruction::Sub(0xf, x), operator.text == "<="),
+                    _ => (Instruction::RSub(0xf, x), operator.text == ">="),
+                };
+                self.emit(subtraction, token.line);
+                if holds_without_borrow {
+                    Condition::Equal(0xf, Operand::Value(1))
+                } else {
+                    Condition::NotEqual(0xf, Operand::Value(1))
+                }
+            }
+            _ => return Err(Self::error(operator, format!("unknown condition {}", operator.text))),
+        };
+        Ok(condition)
+    }
+
+    fn define_label(&mut self, name: Token<'a>) -> Result<(), String> {
+        if name.text == Self::MAIN_LABEL && !self.labels.contains_key(name.text) {
+            if self.rom.len() == 2 && self.here == 2 {
+                // nothing follows the jump to main yet, so it is dropped.
+                // Labels and loops before main have nothing between them and main, so they move down with it
+                self.rom.clear();
+                self.here = 0;
+                for address in self.labels.values_mut() {
+                    *address -= 2;
+                }
+                for (block, _) in self.blocks.iter_mut() {
+                    if let Block::Loop(address, _) = block {
+                        *address -= 2;
+                    }
+                }
+            } else {
+                self.patch_address(0, self.address(), AddressWidth::Short, name)?;
+            }
+        }
+        self.insert_label(name, self.address())
+    }
+
+    fn insert_label(&mut self, name: Token<'a>, address: u16) -> Result<(), String> {
+        if self.labels.contains_key(name.text) {
+            return Err(Self::error(name, format!("label {} is already defined", name.text)));
+        }
+        self.labels.insert(name.text, address);
+        Ok(())
+    }
+
+    /// `:macro NAME PARAMETERS { BODY }`, where the body may hold braces of its own
+    fn define_macro(&mut self, token: Token<'a>) -> Result<(), String> {
+        let name = self.expect_token(token)?;
+        let mut parameters = Vec::new();
+        let mut parameter = self.expect_token(name)?;
+        while parameter.text != "{" {
+            parameters.push(parameter.text);
+            parameter = self.expect_token(parameter)?;
+        }
+        let mut body = Vec::new();
+        let mut depth = 1;
+        loop {
+            let body_token = self.next_token().ok_or_else(|| Self::error(token, format!("macro {} is never closed with }}", name.text)))?;
+            match body_token.text {
+                "{" => depth += 1,
+                "}" => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                break;
+            }
+            body.push(body_token);
+        }
+        self.macros.insert(name.text, Macro { parameters, body });
+        Ok(())
+    }
+
+    /// Replace a macro call and its arguments by the body of the macro.
+    /// The expanded tokens take the line of the call, so that errors and the symbol map point at it.
+    fn expand_macro(&mut self, token: Token<'a>) -> Result<(), String> {
+        if token.macro_depth >= Self::MAX_MACRO_DEPTH {
+            return Err(Self::error(token, format!("macros nested more than {} deep, {} may call itself", Self::MAX_MACRO_DEPTH, token.text)));
+        }
+        let definition = &self.macros[token.text];
+        let arguments_end = self.position + definition.parameters.len();
+        let Some(arguments) = self.tokens.get(self.position..arguments_end) else {
+            return Err(Self::error(token, format!("macro {} takes {} arguments", token.text, definition.parameters.len())));
+        };
+        let expansion: Vec<Token<'a>> = definition
+            .body
+            .iter()
+            .map(|body_token| {
+                let text = match definition.parameters.iter().position(|parameter| *parameter == body_token.text) {
+                    Some(index) => arguments[index].text,
+                    None => body_token.text,
+                };
+                Token { text, line: token.line, macro_depth: token.macro_depth + 1 }
+            })
+            .collect();
+        self.tokens.splice(self.position..arguments_end, expansion);
+        Ok(())
+    }
+
+    fn address(&self) -> u16 {
+        (Device::ROM_START + self.here) as u16
+    }
+
+    /// Write bytes at the current position, growing the ROM if they go past its end
+    fn emit_bytes(&mut self, bytes: &[u8]) {
+        let end = self.here + bytes.len();
+        if self.rom.len() < end {
+            self.rom.resize(end, 0);
+        }
+        self.rom[self.here..end].copy_from_slice(bytes);
+        self.here = end;
+    }
+
+    fn emit(&mut self, instruction: Instruction, line: u32) {
+        self.locations.push(SourceLocation {
+            address: self.address(),
+            file: self.file_name.to_string(),
+            line,
+        });
+        self.emit_bytes(&instruction.encode_instruction());
+    }
+
+    /// Emit a jump to be filled in later, returning its offset
+    fn emit_jump_placeholder(&mut self, line: u32) -> usize {
+        let offset = self.here;
+        self.emit(Instruction::JumpTo(0), line);
+        offset
+    }
+
+    /// Emit an instruction taking the address of `target`, which is a number, a constant or a label
+    fn emit_with_address(
+        &mut self,
+        line: u32,
+        target: Token<'a>,
+        width: AddressWidth,
+        instruction: impl FnOnce(u16) -> Instruction,
+    ) -> Result<(), String> {
+        let known_address = match Self::parse_number(target.text).or_else(|| self.constant(target.text)) {
+            Some(value) => Some(u16::try_from(value).map_err(|_| Self::error(target, format!("{} is not an address", target.text)))?),
+            None => self.labels.get(target.text).copied(),
+        };
+        let offset = self.here;
+        self.emit(instruction(0), line);
+        match known_address {
+            Some(address) => self.patch_address(offset, address, width, target)?,
+            None => self.references.push(Reference { offset, name: target, width }),
+        }
+        Ok(())
+    }
+
+    /// Write an address into the instruction at `offset`
+    fn patch_address(&mut self, offset: usize, address: u16, width: AddressWidth, token: Token) -> Result<(), String> {
+        match width {
+            AddressWidth::Short | AddressWidth::UnpackHigh if address > 0xfff => {
+                return Err(Self::error(token, format!("address {:#X} does not fit in 12 bits", address)));
+            }
+            AddressWidth::Short => {
+                self.rom[offset] = (self.rom[offset] & 0xf0) | (address >> 8) as u8;
+                self.rom[offset + 1] = address as u8;
+            }
+            AddressWidth::Long => self.rom[offset + 2..offset + 4].copy_from_slice(&address.to_be_bytes()),
+            AddressWidth::UnpackHigh => self.rom[offset + 1] = (self.rom[offset + 1] & 0xf0) | (address >> 8) as u8,
+            AddressWidth::HighByte => self.rom[offset + 1] = (address >> 8) as u8,
+            AddressWidth::LowByte => self.rom[offset + 1] = address as u8,
+        }
+        Ok(())
+    }
+
+    /// `{ EXPRESSION }` of `:calc`
+    fn calc_block(&mut self, previous: Token<'a>) -> Result<f64, String> {
+        self.expect_operator(previous, "{")?;
+        let value = self.calc_expression(previous)?;
+        self.expect_operator(previous, "}")?;
+        Ok(value)
+    }
+
+    /// Like Octo, operators have no precedence and are applied from right to left:
+    /// `1 + 2 * 3` is 7 and `2 * 3 + 1` is 8, parentheses group
+    fn calc_expression(&mut self, previous: Token<'a>) -> Result<f64, String> {
+        let left = self.calc_term(previous)?;
+        match self.peek_token() {
+            Some(token) if token.text != ")" && token.text != "}" => {}
+            _ => return Ok(left),
+        }
+        let operator = self.expect_token(previous)?;
+        let right = self.calc_expression(operator)?;
+        let (left_bits, right_bits) = (left as i64, right as i64);
+        let value = match operator.text {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" => left / right,
+            "%" => left % right,
+            "&" => (left_bits & right_bits) as f64,
+            "|" => (left_bits | right_bits) as f64,
+            "^" => (left_bits ^ right_bits) as f64,
+            "<<" => left_bits.checked_shl(right_bits as u32).unwrap_or(0) as f64,
+            ">>" => left_bits.checked_shr(right_bits as u32).unwrap_or(0) as f64,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "<" => (left < right) as u8 as f64,
+            ">" => (left > right) as u8 as f64,
+            "<=" => (left <= right) as u8 as f64,
+            ">=" => (left >= right) as u8 as f64,
+            "==" => (left == right) as u8 as f64,
+            "!=" => (left != right) as u8 as f64,
+            _ => return Err(Self::error(operator, format!("unknown operator {}", operator.text))),
+        };
+        Ok(value)
+    }
+
+    /// A value, a parenthesised expression or a unary operator applied to a term
+    fn calc_term(&mut self, previous: Token<'a>) -> Result<f64, String> {
+        let token = self.expect_token(previous)?;
+        let value = match token.text {
+            "(" => {
+                let value = self.calc_expression(token)?;
+                self.expect_operator(token, ")")?;
+                value
+            }
+            "-" => -self.calc_term(token)?,
+            "~" => !(self.calc_term(token)? as i64) as f64,
+            "!" => (self.calc_term(token)? == 0.0) as u8 as f64,
+            "sin" => self.calc_term(token)?.sin(),
+            "cos" => self.calc_term(token)?.cos(),
+            "tan" => self.calc_term(token)?.tan(),
+            "exp" => self.calc_term(token)?.exp(),
+            "log" => self.calc_term(token)?.ln(),
+            "abs" => self.calc_term(token)?.abs(),
+            "sqrt" => self.calc_term(token)?.sqrt(),
+            "sign" => self.calc_term(token)?.signum(),
+            "ceil" => self.calc_term(token)?.ceil(),
+            "floor" => self.calc_term(token)?.floor(),
+            "@" => {
+                // the byte assembled at an address so far
+                let address = self.calc_term(token)? as usize;
+                let byte = address.checked_sub(Device::ROM_START).and_then(|offset| self.rom.get(offset));
+                byte.copied().unwrap_or(0) as f64
+            }
+            "HERE" => self.address() as f64,
+            "PI" => PI,
+            "E" => E,
+            name => match Self::parse_number(name) {
+                Some(value) => value as f64,
+                None => match (self.constants.get(name), self.labels.get(name)) {
+                    (Some(value), _) => *value,
+                    (None, Some(address)) => *address as f64,
+                    (None, None) => return Err(Self::error(token, format!("undefined name {}", name))),
+                },
+            },
+        };
+        Ok(value)
+    }
+
+    fn next_token(&mut self) -> Option<Token<'a>> {
+        let token = self.tokens.get(self.position).copied();
+        self.position += 1;
+        token
+    }
+
+    fn peek_token(&self) -> Option<Token<'a>> {
+        self.tokens.get(self.position).copied()
+    }
+
+    /// The next token, which must follow `previous`
+    fn expect_token(&mut self, previous: Token<'a>) -> Result<Token<'a>, String> {
+        self.next_token().ok_or_else(|| Self::error(previous, format!("unexpected end of the file after {}", previous.text)))
+    }
+
+    fn expect_operator(&mut self, previous: Token<'a>, operator: &str) -> Result<(), String> {
+        let token = self.expect_token(previous)?;
+        if token.text != operator {
+            return Err(Self::error(token, format!("expected {}, found {}", operator, token.text)));
+        }
+        Ok(())
+    }
+
+    fn expect_register(&mut self, previous: Token<'a>) -> Result<usize, String> {
+        let token = self.expect_token(previous)?;
+        self.register(token)
+    }
+
+    fn expect_nibble(&mut self, previous: Token<'a>) -> Result<u8, String> {
+        let token = self.expect_token(previous)?;
+        match self.value(token)? {
+            value @ 0..=0xf => Ok(value as u8),
+            _ => Err(Self::error(token, format!("{} does not fit in 4 bits", token.text))),
+        }
+    }
+
+    fn is_register(&self, text: &str) -> bool {
+        self.aliases.contains_key(text) || Self::parse_register(text).is_some()
+    }
+
+    fn register(&self, token: Token) -> Result<usize, String> {
+        self.aliases
+            .get(token.text)
+            .copied()
+            .or_else(|| Self::parse_register(token.text))
+            .ok_or_else(|| Self::error(token, format!("expected a register, found {}", token.text)))
+    }
+
+    /// `v0` to `vf`, in either case
+    fn parse_register(text: &str) -> Option<usize> {
+        let digit = text.strip_prefix(['v', 'V'])?;
+        if digit.len() != 1 {
+            return None;
+        }
+        usize::from_str_radix(digit, 16).ok()
+    }
+
+    fn operand(&self, token: Token) -> Result<Operand, String> {
+        match self.is_register(token.text) {
+            true => Ok(Operand::Register(self.register(token)?)),
+            false => Ok(Operand::Value(self.byte(token)?)),
+        }
+    }
+
+    /// A constant, rounded down to a whole number as `:calc` results may have a fractional part
+    fn constant(&self, name: &str) -> Option<i32> {
+        self.constants.get(name).map(|value| value.floor() as i32)
+    }
+
+    /// A number or a constant
+    fn value(&self, token: Token) -> Result<i32, String> {
+        Self::parse_number(token.text)
+            .or_else(|| self.constant(token.text))
+            .ok_or_else(|| Self::error(token, format!("expected a number, found {}", token.text)))
+    }
+
+    fn byte(&self, token: Token) -> Result<u8, String> {
+        Self::to_byte(token, self.value(token)?)
+    }
+
+    /// A value from -128 to 255, with negative values stored in two's complement
+    fn to_byte(token: Token, value: i32) -> Result<u8, String> {
+        match value {
+            -128..=255 => Ok(value as u8),
+            _ => Err(Self::error(token, format!("{} does not fit in a byte", token.text))),
+        }
+    }
+
+    /// Decimal, `0x` hex or `0b` binary, optionally negative
+    fn parse_number(text: &str) -> Option<i32> {
+        let (is_negative, digits) = match text.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, text),
+        };
+        let value = if let Some(hex_digits) = digits.strip_prefix("0x") {
+            i32::from_str_radix(hex_digits, 16).ok()?
+        } else if let Some(binary_digits) = digits.strip_prefix("0b") {
+            i32::from_str_radix(binary_digits, 2).ok()?
+        } else {
+            digits.parse().ok()?
+        };
+        Some(if is_negative { -value } else { value })
+    }
+
+    fn error(token: Token, message: String) -> String {
+        format!("line {}: {}", token.line, message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Program;
+
+    #[test]
+    fn test_assemble_program() {
+        let source = "\
+: main
+  :alias counter v1
+  :const SPEED 2
+  clear
+  i := smile         # forward reference
+  loop
+    sprite v0 counter 3
+    counter += SPEED
+    if counter == 8 then v0 := -1
+    while counter != 16
+  again
+  exit
+: smile
+  0x3C 0x42 :byte 0b11
+";
+        let program = Program::assemble(source, "smile.8o").unwrap();
+        let expected = [
+            0x00, 0xe0, // clear
+            0xa2, 0x14, // i := smile
+            0xd0, 0x13, // sprite v0 v1 3
+            0x71, 0x02, // v1 += 2
+            0x41, 0x08, // if v1 == 8 then
+            0x60, 0xff, // v0 := -1
+            0x41, 0x10, // while v1 != 16
+            0x12, 0x12, // jump past again
+            0x12, 0x04, // again
+            0x00, 0xfd, // exit
+            0x3c, 0x42, 0x03,
+        ];
+        assert_eq!(&expected, program.rom());
+        let location = program.symbol_map().get_location(0x208).unwrap();
+        assert_eq!(("smile.8o", 9), (location.file.as_str(), location.line));
+    }
+
+    #[test]
+    fn test_jumps_to_main_if_not_first() {
+        let source = ": double v0 += v0 ;\n: main v0 := 1 double if v0 -key begin v0 := 2 else v0 := 3 end";
+        let program = Program::assemble(source, "main.8o").unwrap();
+        let expected = [
+            0x12, 0x06, // jump main
+            0x80, 0x04, // v0 += v0
+            0x00, 0xee, // return
+            0x60, 0x01, // v0 := 1
+            0x22, 0x02, // double
+            0xe0, 0xa1, // if v0 -key begin
+            0x12, 0x12, // to else
+            0x60, 0x02, // v0 := 2
+            0x12, 0x14, // to end
+            0x60, 0x03, // v0 := 3
+        ];
+        assert_eq!(&expected, program.rom());
+    }
+
+    #[test]
+    fn test_labels_before_first_main_move_with_it() {
+        let source = ": start :next first : main\n  v0 := 1\n  jump start\n  i := first";
+        let program = Program::assemble(source, "main.8o").unwrap();
+        let expected = [
+            0x60, 0x01, // v0 := 1
+            0x12, 0x00, // jump start
+            0xa2, 0x01, // i := first
+        ];
+        assert_eq!(&expected, program.rom());
+    }
+
+    #[test]
+    fn test_org_next_and_unpack() {
+        let source = "\
+: main
+  :unpack 0xA data
+  :unpack long far
+  :next target v2 := 7
+  i := target
+:org 0x300
+: data 0x11
+:org 0x1234
+: far 0x22
+";
+        let program = Program::assemble(source, "org.8o").unwrap();
+        let mut expected = vec![0; 0x1035];
+        expected[..12].copy_from_slice(&[
+            0x60, 0xa3, 0x61, 0x00, // :unpack 0xA data
+            0x60, 0x12, 0x61, 0x34, // :unpack long far
+            0x62, 0x07, // v2 := 7
+            0xa2, 0x09, // i := target
+        ]);
+        expected[0x100] = 0x11;
+        expected[0x1034] = 0x22;
+        assert_eq!(expected, program.rom());
+    }
+
+    #[test]
+    fn test_macros_and_calc() {
+        let source = "\
+:calc SPEED { 1 + 2 * 3 }
+:calc HALF { SPEED / 2 }
+:macro move register amount { register += amount }
+: main
+  move v1 SPEED
+  move v2 HALF
+  :byte { 2 * 3 + 1 }
+  :byte { HERE - 0x200 }
+  :byte { @ 0x201 }
+";
+        let program = Program::assemble(source, "macro.8o").unwrap();
+        let expected = [
+            0x71, 0x07, // v1 += 7
+            0x72, 0x03, // v2 += 3.5, rounded down
+            0x08, 0x05, 0x07,
+        ];
+        assert_eq!(&expected, program.rom());
+        let location = program.symbol_map().get_location(0x202).unwrap();
+        assert_eq!(6, location.line);
+    }
+
+    #[test]
+    fn test_macros_can_be_called_any_number_of_times() {
+        let source = format!(":macro nothing {{ }}\n:macro twice {{ nothing nothing }}\n: main\n{}clear", "twice\n".repeat(6000));
+        let program = Program::assemble(&source, "macro.8o").unwrap();
+        assert_eq!(&[0x00, 0xe0], program.rom());
+    }
+
+    #[test]
+    fn test_ordering_comparisons() {
+        let source = ": main\n  if v1 < 5 then v2 := 1\n  if v1 >= v3 begin v2 := 2 end\n  if v1 > 2 then v2 := 3";
+        let program = Program::assemble(source, "compare.8o").unwrap();
+        let expected = [
+            0x6f, 0x05, // vf := 5
+            0x8f, 0x17, // vf =- v1
+            0x3f, 0x01, // skip if v1 >= 5
+            0x62, 0x01, // v2 := 1
+            0x8f, 0x30, // vf := v3
+            0x8f, 0x17, // vf =- v1
+            0x3f, 0x01, // skip the jump if v1 >= v3
+            0x12, 0x12, // to end
+            0x62, 0x02, // v2 := 2
+            0x6f, 0x02, // vf := 2
+            0x8f, 0x15, // vf -= v1
+            0x3f, 0x01, // skip if v1 <= 2
+            0x62, 0x03, // v2 := 3
+        ];
+        assert_eq!(&expected, program.rom());
+    }
+
+    #[test]
+    fn test_debugger_directives_are_skipped() {
+        let source = ": main\n  :breakpoint start\n  :monitor v0 4\n  :monitor v0 \"%2i %2i\"\n  clear";
+        assert_eq!(&[0x00, 0xe0], Program::assemble(source, "debug.8o").unwrap().rom());
+    }
+
+    #[test]
+    fn test_errors_name_the_line() {
+        let error_message = |source: &str| Program::assemble(source, "error.8o").unwrap_err().to_string();
+        assert_eq!("Invalid assembly: line 2: undefined name missing", error_message(": main\njump missing"));
+        assert_eq!("Invalid assembly: line 1: expected a register, found vg", error_message(": main sprite v0 vg 1"));
+        assert_eq!("Invalid assembly: line 2: 300 does not fit in a byte", error_message(": main\nv0 := 300"));
+        assert_eq!("Invalid assembly: line 1: loop is never closed with again", error_message(": main loop"));
+        assert_eq!("Invalid assembly: no main label to start the program at", error_message("clear"));
+        assert_eq!("Invalid assembly: line 2: unsupported directive :stringmode", error_message(": main\n:stringmode"));
+        assert_eq!("Invalid assembly: line 1: 0x100 is outside of the program", error_message(": main :org 0x100"));
+        assert_eq!(
+            "Invalid assembly: line 1: vf can not be compared with <, the comparison overwrites it",
+            error_message(": main if vf < 3 then clear")
+        );
+        assert_eq!(
+            "Invalid assembly: line 2: macros nested more than 64 deep, forever may call itself",
+            error_message(":macro forever { forever }\n: main forever")
+        );
+    }
+}
@@ -0,0 +1,256 @@
+use serde_json::Value;
+use weezl::BitOrder;
+use weezl::decode::Decoder;
+
+use crate::device::framebuffer::Palette;
+use crate::rom;
+use crate::util::{EmulatorError, EmulatorResult, QuirkConfig};
+
+/// An Octo cartridge: a GIF image with the source of a program and its options hidden in the pixels.
+///
+/// The low 2 bits of each pixel of the first frame hold the payload, most significant bits first.
+/// The payload is a 4 byte big-endian length followed by that many bytes of JSON with `program` and `options` fields.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Cartridge {
+    program: String,
+    options: CartridgeOptions,
+}
+
+/// Options of a cartridge, with Octo's defaults for those missing
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CartridgeOptions {
+    pub instructions_per_frame: u32,
+    pub quirks: QuirkConfig,
+    pub is_xo_chip: bool,
+    pub palette: Palette,
+}
+
+impl Cartridge {
+    const GIF_SIGNATURE: &'static [u8] = b"GIF8";
+    const EXTENSION_INTRODUCER: u8 = 0x21;
+    const IMAGE_SEPARATOR: u8 = 0x2c;
+    const TRAILER: u8 = 0x3b;
+    /// Flag of the logical screen and image descriptors for a colour table following them
+    const COLOR_TABLE_FLAG: u8 = 0x80;
+    const INTERLACE_FLAG: u8 = 0x40;
+
+    /// Whether the file is a GIF image, which may be a cartridge
+    pub fn is_cartridge(file: &[u8]) -> bool {
+        file.starts_with(Self::GIF_SIGNATURE)
+    }
+
+    pub fn decode(gif: &[u8]) -> EmulatorResult<Cartridge> {
+        Self::decode_payload(gif).map_err(EmulatorError::InvalidCartridge)
+    }
+
+    /// Octo source of the program
+    pub fn program(&self) -> &str {
+        &self.program
+    }
+
+    pub fn options(&self) -> &CartridgeOptions {
+        &self.options
+    }
+
+    fn decode_payload(gif: &[u8]) -> Result<Cartridge, String> {
+        let pixels = Self::decode_first_frame(gif)?;
+        let payload: Vec<u8> = pixels
+            .chunks_exact(4)
+            .map(|chunk| chunk.iter().fold(0, |byte, pixel| (byte << 2) | (pixel & 0b11)))
+            .collect();
+        let length_bytes = payload.get(0..4).ok_or("the image is too small to hold a program")?;
+        let length = u32::from_be_bytes(length_bytes.try_into().unwrap()) as usize;
+        let json = payload.get(4..4 + length).ok_or("the program is longer than the image")?;
+        let json: Value = serde_json::from_slice(json).map_err(|err| format!("the payload is not JSON: {}", err))?;
+        let program = json["program"].as_str().ok_or("the payload has no program")?.to_string();
+        let options = CartridgeOptions::from_json(&json["options"])?;
+        Ok(Cartridge { program, options })
+    }
+
+    /// Colour indices of the pixels of the first image in a GIF
+    fn decode_first_frame(gif: &[u8]) -> Result<Vec<u8>, String> {
+        const LOGICAL_SCREEN_END: usize = 13;
+        if !Self::is_cartridge(gif) || gif.len() < LOGICAL_SCREEN_END {
+            return Err("not a GIF image".to_string());
+        }
+        let mut position = LOGICAL_SCREEN_END + Self::color_table_length(gif[10]);
+        loop {
+            match gif.get(position) {
+                Some(&Self::EXTENSION_INTRODUCER) => {
+                    // the introducer and label, then data sub-blocks
+                    position = Self::skip_sub_blocks(gif, position + 2)?;
+                }
+                Some(&Self::IMAGE_SEPARATOR) => break,
+                Some(&Self::TRAILER) | None => return Err("the GIF has no image".to_string()),
+                Some(block) => return Err(format!("unknown GIF block {:#04X}", block)),
+            }
+        }
+
+        let descriptor = gif.get(position + 1..position + 10).ok_or("the GIF image descriptor is cut off")?;
+        let width = u16::from_le_bytes([descriptor[4], descriptor[5]]) as usize;
+        let height = u16::from_le_bytes([descriptor[6], descriptor[7]]) as usize;
+        let flags = descriptor[8];
+        if flags & Self::INTERLACE_FLAG != 0 {
+            return Err("interlaced GIF images are not supported".to_string());
+        }
+        position += 10 + Self::color_table_length(flags);
+        let min_code_size = *gif.get(position).ok_or("the GIF image data is cut off")?;
+        if !(1..=8).contains(&min_code_size) {
+            return Err(format!("invalid GIF code size {}", min_code_size));
+        }
+        let mut data = Vec::new();
+        Self::read_sub_blocks(gif, position + 1, &mut data)?;
+
+        let mut pixels = Decoder::new(BitOrder::Lsb, min_code_size)
+            .decode(&data)
+            .map_err(|err| format!("invalid GIF image data: {}", err))?;
+        pixels.truncate(width * height);
+        Ok(pixels)
+    }
+
+    /// Bytes taken by the colour table described by the packed fields of a descriptor
+    fn color_table_length(flags: u8) -> usize {
+        if flags & Self::COLOR_TABLE_FLAG == 0 {
+            return 0;
+        }
+        3 << ((flags & 0b111) + 1)
+    }
+
+    /// Position after the sub-blocks starting at `position`
+    fn skip_sub_blocks(gif: &[u8], position: usize) -> Result<usize, String> {
+        Self::read_sub_blocks(gif, position, &mut Vec::new())
+    }
+
+    /// Append the data of the sub-blocks starting at `position`, returning the position after them
+    fn read_sub_blocks(gif: &[u8], mut position: usize, data: &mut Vec<u8>) -> Result<usize, String> {
+        loop {
+            let length = *gif.get(position).ok_or("a GIF block is cut off")? as usize;
+            position += 1;
+            if length == 0 {
+                return Ok(position);
+            }
+            data.extend_from_slice(gif.get(position..position + length).ok_or("a GIF block is cut off")?);
+            position += length;
+        }
+    }
+}
+
+impl CartridgeOptions {
+    const DEFAULT_TICKRATE: u64 = 20;
+    /// Octo's default colours for the background, plane 1, plane 2 and both planes
+    const DEFAULT_COLORS: [&'static str; 4] = ["#996600", "#FFCC00", "#FF6600", "#662200"];
+
+    /// Options from the JSON Octo saves, such as `{"tickrate": 20, "shiftQuirks": false, "fillColor": "#FFCC00"}`
+    fn from_json(options: &Value) -> Result<CartridgeOptions, String> {
+        let quirk = |name: &str| options[name].as_bool().unwrap_or(false);
+        let quirks = QuirkConfig {
+            vf_reset: quirk("logicQuirks"),
+            memory_increment: !quirk("loadStoreQuirks"),
+            shift_in_place: quirk("shiftQuirks"),
+            jump_with_vx: quirk("jumpQuirks"),
+            index_overflow_flag: false,
+            lores_half_scroll: false,
+            count_collision_rows: false,
+            wrap_sprites: !quirk("clipQuirks"),
+            display_wait: quirk("vBlankQuirks"),
+        };
+        let tickrate = options["tickrate"].as_u64().unwrap_or(Self::DEFAULT_TICKRATE);
+        // ROMs too big for the 4 KiB of CHIP-8 memory need XO-CHIP
+        let is_xo_chip = options["maxSize"].as_u64().is_some_and(|max_size| max_size > rom::ROM_SIZE as u64);
+        let colors: Vec<&str> = ["backgroundColor", "fillColor", "fillColor2", "blendColor"]
+            .iter()
+            .zip(Self::DEFAULT_COLORS)
+            .map(|(name, default)| options[*name].as_str().unwrap_or(default))
+            .collect();
+        let palette = colors.join(",").parse()?;
+        Ok(CartridgeOptions {
+            instructions_per_frame: tickrate.clamp(1, u32::MAX as u64) as u32,
+            quirks,
+            is_xo_chip,
+            palette,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use weezl::BitOrder;
+    use weezl::encode::Encoder;
+
+    use super::Cartridge;
+    use crate::device::framebuffer::Palette;
+
+    /// A GIF with a 4 colour global table and one image holding the payload in its pixels
+    fn encode_cartridge(payload: &[u8]) -> Vec<u8> {
+        let mut data = (payload.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(payload);
+        let pixels: Vec<u8> = data.iter().flat_map(|byte| [byte >> 6, (byte >> 4) & 3, (byte >> 2) & 3, byte & 3]).collect();
+        let width = 16u16;
+        let height = pixels.len().div_ceil(width as usize) as u16;
+        let mut padded_pixels = pixels.clone();
+        padded_pixels.resize(width as usize * height as usize, 0);
+        let image_data = Encoder::new(BitOrder::Lsb, 2).encode(&padded_pixels).unwrap();
+
+        let mut gif = b"GIF89a".to_vec();
+        gif.extend_from_slice(&width.to_le_bytes());
+        gif.extend_from_slice(&height.to_le_bytes());
+        gif.extend_from_slice(&[0x81, 0, 0]);
+        gif.extend_from_slice(&[0; 12]);
+        // a graphic control extension, skipped when decoding
+        gif.extend_from_slice(&[0x21, 0xf9, 4, 0, 0, 0, 0, 0]);
+        gif.push(0x2c);
+        gif.extend_from_slice(&[0, 0, 0, 0]);
+        gif.extend_from_slice(&width.to_le_bytes());
+        gif.extend_from_slice(&height.to_le_bytes());
+        gif.extend_from_slice(&[0, 2]);
+        for block in image_data.chunks(255) {
+            gif.push(block.len() as u8);
+            gif.extend_from_slice(block);
+        }
+        gif.extend_from_slice(&[0, 0x3b]);
+        gif
+    }
+
+    #[test]
+    fn test_decode_cartridge() {
+        let payload = json!({
+            "program": ": main\n  clear\n",
+            "options": {"tickrate": 100, "shiftQuirks": true, "clipQuirks": true, "maxSize": 65024, "backgroundColor": "#000000"},
+        });
+        let gif = encode_cartridge(payload.to_string().as_bytes());
+        assert!(Cartridge::is_cartridge(&gif));
+        let cartridge = Cartridge::decode(&gif).unwrap();
+        assert_eq!(": main\n  clear\n", cartridge.program());
+
+        let options = cartridge.options();
+        assert_eq!(100, options.instructions_per_frame);
+        assert!(options.quirks.shift_in_place);
+        assert!(!options.quirks.wrap_sprites);
+        assert!(options.quirks.memory_increment);
+        assert!(!options.quirks.vf_reset);
+        assert!(options.is_xo_chip);
+        let expected_palette: Palette = "000000,FFCC00,FF6600,662200".parse().unwrap();
+        assert_eq!(expected_palette, options.palette);
+    }
+
+    #[test]
+    fn test_decode_gif_from_another_encoder() {
+        // the gameplay recording of the README: a real 512x255 animated GIF, but not a cartridge
+        let gif = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/pong.gif")).unwrap();
+        assert!(Cartridge::is_cartridge(&gif));
+        assert_eq!(512 * 255, Cartridge::decode_first_frame(&gif).unwrap().len());
+        assert_eq!(
+            "Invalid Octo cartridge: the program is longer than the image",
+            Cartridge::decode(&gif).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_invalid_cartridges() {
+        assert!(!Cartridge::is_cartridge(&[0x00, 0xe0]));
+        assert!(Cartridge::decode(b"GIF89a").is_err());
+        assert!(Cartridge::decode(&encode_cartridge(b"not json")).is_err());
+        assert!(Cartridge::decode(&encode_cartridge(b"{\"options\": {}}")).is_err());
+    }
+}
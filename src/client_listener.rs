@@ -0,0 +1,106 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::util::EmulatorResult;
+
+/// Accepts debugging clients over TCP for the GDB and debug adapter servers.
+/// One client is served at a time, between frames on the thread running the device:
+/// accepting and reading never block for longer than `READ_TIMEOUT`, so the device keeps running.
+pub struct ClientListener {
+    listener: TcpListener,
+    connection: Option<ClientConnection>,
+    /// Kind of client, for logging
+    client_name: &'static str,
+}
+
+/// The connected client and the data received from it that is not handled yet
+pub struct ClientConnection {
+    pub stream: TcpStream,
+    pub received: Vec<u8>,
+    /// Whether writing to the client failed, after which nothing more is sent
+    is_lost: bool,
+    client_name: &'static str,
+}
+
+impl ClientListener {
+    /// How long to wait for more data from the client between frames
+    const READ_TIMEOUT: Duration = Duration::from_millis(1);
+
+    pub fn bind(address: impl ToSocketAddrs, client_name: &'static str) -> EmulatorResult<ClientListener> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        log::info!("Waiting for a {} client on {}", client_name, listener.local_addr()?);
+        Ok(ClientListener { listener, connection: None, client_name })
+    }
+
+    pub fn local_addr(&self) -> EmulatorResult<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    pub fn connection_mut(&mut self) -> Option<&mut ClientConnection> {
+        self.connection.as_mut()
+    }
+
+    /// Accept a waiting client, unless one is connected already
+    pub fn accept(&mut self) -> EmulatorResult<()> {
+        if self.connection.is_some() {
+            return Ok(());
+        }
+        match self.listener.accept() {
+            Ok((stream, address)) => {
+                log::info!("{} client connected from {}", self.client_name, address);
+                stream.set_read_timeout(Some(Self::READ_TIMEOUT))?;
+                stream.set_nodelay(true)?;
+                self.connection = Some(ClientConnection { stream, received: Vec::new(), is_lost: false, client_name: self.client_name });
+                Ok(())
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Read until the client goes quiet, appending to the received data.
+    /// Returns whether the connection was closed or failed. It is kept until `disconnect`,
+    /// so that the data received before closing can still be handled.
+    pub fn receive(&mut self) -> bool {
+        let Some(connection) = self.connection.as_mut() else {
+            return false;
+        };
+        let mut buffer = [0u8; 4096];
+        loop {
+            match connection.stream.read(&mut buffer) {
+                Ok(0) => return true,
+                Ok(length) => connection.received.extend_from_slice(&buffer[..length]),
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => return false,
+                Err(err) => {
+                    log::warn!("Lost the {} client: {}", self.client_name, err);
+                    return true;
+                }
+            }
+        }
+    }
+
+    pub fn disconnect(&mut self) {
+        log::info!("{} client disconnected, resuming", self.client_name);
+        self.connection = None;
+    }
+}
+
+impl ClientConnection {
+    /// Write to the client. A failed write does not stop the device: it is logged and the connection is marked
+    /// as lost, for the server to disconnect once it is done with the current request.
+    pub fn send(&mut self, data: &[u8]) {
+        if self.is_lost {
+            return;
+        }
+        if let Err(err) = self.stream.write_all(data) {
+            log::warn!("Lost the {} client: {}", self.client_name, err);
+            self.is_lost = true;
+        }
+    }
+
+    pub fn is_lost(&self) -> bool {
+        self.is_lost
+    }
+}
@@ -0,0 +1,688 @@
+use std::collections::{BTreeSet, HashMap};
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use serde_json::{json, Value};
+
+use crate::client_listener::{ClientConnection, ClientListener};
+use crate::debugger::{DebugCommand, Debugger, StopReason};
+use crate::device::instruction::Instruction;
+use crate::device::Device;
+use crate::rom;
+use crate::symbol_map::SymbolMap;
+use crate::util::EmulatorResult;
+
+/// Serves the Debug Adapter Protocol over TCP, for debugging from editors.
+///
+/// Clients attach to the running emulator, or launch a ROM on it, which resets the device keeping its configuration.
+/// The device waits for the client to finish configuring before running. Breakpoints are set by address,
+/// or by source line when a symbol map is given with `set_symbol_map` or the `symbols` launch and attach argument.
+/// There is a single thread, whose call stack is pc followed by the return addresses on the device stack.
+/// Stepping in executes one instruction, stepping over runs calls to completion.
+pub struct DapServer {
+    client_listener: ClientListener,
+    debugger: Debugger,
+    symbol_map: Option<SymbolMap>,
+    /// Breakpoint addresses set by line, per source file
+    source_breakpoints: HashMap<String, Vec<u16>>,
+    /// Breakpoint addresses set by instruction
+    instruction_breakpoints: Vec<u16>,
+    /// Breakpoints set in the debugger, the union of the above
+    active_breakpoints: BTreeSet<u16>,
+    /// Where to stop while stepping over a call or out of a subroutine
+    step_target: Option<StepTarget>,
+    stop_on_entry: bool,
+    has_reported_exit: bool,
+    sequence: u64,
+}
+
+/// Where a step that runs whole frames ends
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum StepTarget {
+    /// Return from the subroutine running at a stack depth
+    Out(usize),
+    /// Return to an address at a stack depth, after a call
+    Over(u16, usize),
+}
+
+impl StepTarget {
+    fn is_reached(&self, device: &Device) -> bool {
+        match *self {
+            StepTarget::Out(depth) => device.stack.len() < depth,
+            StepTarget::Over(address, depth) => device.stack.len() <= depth && device.registers.pc == address,
+        }
+    }
+}
+
+impl DapServer {
+    const THREAD_ID: u64 = 1;
+    const REGISTERS_REFERENCE: u64 = 1;
+    const TIMERS_REFERENCE: u64 = 2;
+    const HEADER_END: &'static [u8] = b"\r\n\r\n";
+
+    /// Listen on `address`. The device stays paused until a client has configured it.
+    pub fn bind(address: impl ToSocketAddrs) -> EmulatorResult<DapServer> {
+        Ok(DapServer {
+            client_listener: ClientListener::bind(address, "Debug adapter")?,
+            debugger: Debugger::new(true),
+            symbol_map: None,
+            source_breakpoints: HashMap::new(),
+            instruction_breakpoints: Vec::new(),
+            active_breakpoints: BTreeSet::new(),
+            step_target: None,
+            stop_on_entry: false,
+            has_reported_exit: false,
+            sequence: 0,
+        })
+    }
+
+    pub fn local_addr(&self) -> EmulatorResult<SocketAddr> {
+        self.client_listener.local_addr()
+    }
+
+    /// Map source lines to addresses, for setting breakpoints by line and showing the source of stack frames
+    pub fn set_symbol_map(&mut self, symbol_map: SymbolMap) {
+        self.symbol_map = Some(symbol_map);
+    }
+
+    /// Handle the messages received from the client, then run a frame unless paused and report if the device stopped.
+    /// Returns whether the device ran.
+    pub fn run_frame(&mut self, device: &mut Device) -> EmulatorResult<bool> {
+        self.client_listener.accept()?;
+        self.handle_messages(device)?;
+        if self.debugger.is_paused() {
+            return Ok(false);
+        }
+        let step_target = self.step_target;
+        let stop = self.debugger.run_frame_until(device, |device| step_target.is_some_and(|target| target.is_reached(device)))?;
+        if let Some(stop) = stop {
+            self.step_target = None;
+            self.send_stopped(if stop == StopReason::Breakpoint { "breakpoint" } else { "step" });
+        }
+        if device.has_exited() && !self.has_reported_exit {
+            self.has_reported_exit = true;
+            self.send_event("exited", json!({ "exitCode": 0 }));
+            self.send_event("terminated", json!({}));
+        }
+        if self.is_connection_lost() {
+            self.disconnect(device)?;
+        }
+        Ok(true)
+    }
+
+    /// Read until the client goes quiet, answering every complete request
+    fn handle_messages(&mut self, device: &mut Device) -> EmulatorResult<()> {
+        let is_closed = self.client_listener.receive();
+        let Some(connection) = self.client_listener.connection_mut() else {
+            return Ok(());
+        };
+        let mut messages = Vec::new();
+        while let Some(message) = Self::take_message(connection) {
+            messages.push(message);
+        }
+        for message in messages {
+            self.handle_request(&message, device)?;
+        }
+        if is_closed || self.is_connection_lost() {
+            self.disconnect(device)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the next `Content-Length` framed message from the received data
+    fn take_message(connection: &mut ClientConnection) -> Option<Value> {
+        loop {
+            let header_end = connection.received.windows(Self::HEADER_END.len()).position(|window| window == Self::HEADER_END)?;
+            let header = String::from_utf8_lossy(&connection.received[..header_end]).into_owned();
+            let content_length = header
+                .lines()
+                .find_map(|line| line.strip_prefix("Content-Length:"))
+                .and_then(|length| length.trim().parse::<usize>().ok());
+            let body_start = header_end + Self::HEADER_END.len();
+            let Some(content_length) = content_length else {
+                log::warn!("Dropping debug adapter message without a length: {}", header);
+                connection.received.drain(..body_start);
+                continue;
+            };
+            if connection.received.len() < body_start + content_length {
+                return None;
+            }
+            let message: Vec<u8> = connection.received.drain(..body_start + content_length).skip(body_start).collect();
+            match serde_json::from_slice(&message) {
+                Ok(message) => return Some(message),
+                Err(err) => log::warn!("Dropping invalid debug adapter message: {}", err),
+            }
+        }
+    }
+
+    fn handle_request(&mut self, request: &Value, device: &mut Device) -> EmulatorResult<()> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let arguments = &request["arguments"];
+        let mut events = Vec::new();
+        let response = match command {
+            "initialize" => {
+                events.push(("initialized", json!({})));
+                Ok(json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsReadMemoryRequest": true,
+                    "supportsInstructionBreakpoints": true,
+                }))
+            }
+            "launch" => match arguments["program"].as_str() {
+                Some(program) => self.launch(program, device).and_then(|()| self.attach(arguments)),
+                None => Err("Launching needs the program to run".to_string()),
+            },
+            "attach" => self.attach(arguments),
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    events.push(("stopped", Self::get_stopped_body("entry")));
+                } else {
+                    self.debugger.execute(DebugCommand::Continue, device)?;
+                }
+                Ok(json!({}))
+            }
+            "setBreakpoints" => Ok(self.set_source_breakpoints(arguments, device)?),
+            "setInstructionBreakpoints" => Ok(self.set_instruction_breakpoints(arguments, device)?),
+            "setExceptionBreakpoints" => Ok(json!({ "breakpoints": [] })),
+            "threads" => Ok(json!({ "threads": [{ "id": Self::THREAD_ID, "name": "CHIP-8" }] })),
+            "stackTrace" => Ok(self.get_stack_trace(device)),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": Self::REGISTERS_REFERENCE, "expensive": false },
+                { "name": "Timers", "variablesReference": Self::TIMERS_REFERENCE, "expensive": false },
+            ] })),
+            "variables" => Ok(Self::get_variables(arguments["variablesReference"].as_u64().unwrap_or_default(), device)),
+            "readMemory" => Self::read_memory(arguments, device),
+            "continue" => {
+                self.debugger.execute(DebugCommand::Continue, device)?;
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" if matches!(device.get_next_instruction(), Instruction::JumpAndLink(_)) => {
+                let return_address = device.registers.pc.wrapping_add(2);
+                self.run_to(StepTarget::Over(return_address, device.stack.len()), device)?;
+                Ok(json!({}))
+            }
+            "stepOut" if !device.stack.is_empty() => {
+                self.run_to(StepTarget::Out(device.stack.len()), device)?;
+                Ok(json!({}))
+            }
+            // there is nothing to step over or out of, so these execute one instruction
+            "next" | "stepIn" | "stepOut" => {
+                self.step_target = None;
+                self.debugger.execute(DebugCommand::Pause, device)?;
+                self.debugger.execute(DebugCommand::Step(1), device)?;
+                events.push(("stopped", Self::get_stopped_body("step")));
+                Ok(json!({}))
+            }
+            "pause" => {
+                self.debugger.execute(DebugCommand::Pause, device)?;
+                self.step_target = None;
+                events.push(("stopped", Self::get_stopped_body("pause")));
+                Ok(json!({}))
+            }
+            "disconnect" => {
+                self.send_response(request, Ok(json!({})));
+                return self.disconnect(device);
+            }
+            _ => Err(format!("Unsupported request {}", command)),
+        };
+        self.send_response(request, response);
+        for (event, body) in events {
+            self.send_event(event, body);
+        }
+        Ok(())
+    }
+
+    /// Load a ROM in place of the running program, on a reset device that keeps its configuration.
+    /// The device is paused until the client is done configuring, as when starting.
+    fn launch(&mut self, program: &str, device: &mut Device) -> Result<(), String> {
+        let max_rom_size = device.memory.len() - Device::ROM_START;
+        let (rom, _) = rom::load_rom(program.to_string(), max_rom_size).map_err(|err| err.to_string())?;
+        if rom.len() > max_rom_size {
+            return Err(format!("{} does not fit in the memory of the device", program));
+        }
+        self.debugger.execute(DebugCommand::Pause, device).map_err(|err| err.to_string())?;
+        device.reset();
+        device.load_rom(&rom);
+        self.step_target = None;
+        self.has_reported_exit = false;
+        Ok(())
+    }
+
+    /// Take the launch and attach options, the device then waits for `configurationDone`
+    fn attach(&mut self, arguments: &Value) -> Result<Value, String> {
+        self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+        let symbol_map = arguments["symbols"].as_str().map(SymbolMap::load).transpose().map_err(|err| err.to_string())?;
+        self.symbol_map = symbol_map.or(self.symbol_map.take());
+        Ok(json!({}))
+    }
+
+    /// Run whole frames until the step target or a breakpoint is reached, or the client pauses
+    fn run_to(&mut self, step_target: StepTarget, device: &mut Device) -> EmulatorResult<()> {
+        self.step_target = Some(step_target);
+        self.debugger.execute(DebugCommand::Continue, device)?;
+        Ok(())
+    }
+
+    /// Let the device run freely once the client is gone, without the breakpoints it set
+    fn disconnect(&mut self, device: &mut Device) -> EmulatorResult<()> {
+        self.client_listener.disconnect();
+        self.step_target = None;
+        self.source_breakpoints.clear();
+        self.instruction_breakpoints.clear();
+        self.update_breakpoints(device)?;
+        self.debugger.execute(DebugCommand::Continue, device)?;
+        Ok(())
+    }
+
+    fn set_source_breakpoints(&mut self, arguments: &Value, device: &mut Device) -> EmulatorResult<Value> {
+        let source = arguments["source"]["path"].as_str().or(arguments["source"]["name"].as_str()).unwrap_or_default().to_string();
+        let lines: Vec<u64> = arguments["breakpoints"].as_array().into_iter().flatten().filter_map(|breakpoint| breakpoint["line"].as_u64()).collect();
+        let mut addresses = Vec::new();
+        let breakpoints: Vec<Value> = lines
+            .iter()
+            .map(|line| {
+                let address = self.symbol_map.as_ref().and_then(|symbol_map| symbol_map.get_address(&source, *line as u32));
+                match address {
+                    Some(address) => {
+                        addresses.push(address);
+                        json!({ "verified": true, "line": line, "instructionReference": Self::format_address(address) })
+                    }
+                    None if self.symbol_map.is_none() => json!({ "verified": false, "line": line, "message": "No symbol map to find the address of the line" }),
+                    None => json!({ "verified": false, "line": line, "message": "No instruction at this line" }),
+                }
+            })
+            .collect();
+        self.source_breakpoints.insert(source, addresses);
+        self.update_breakpoints(device)?;
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn set_instruction_breakpoints(&mut self, arguments: &Value, device: &mut Device) -> EmulatorResult<Value> {
+        let addresses: Vec<Option<u16>> = arguments["breakpoints"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|breakpoint| {
+                let address = Self::parse_address(breakpoint["instructionReference"].as_str()?)?;
+                u16::try_from(address as i64 + breakpoint["offset"].as_i64().unwrap_or(0)).ok()
+            })
+            .collect();
+        self.instruction_breakpoints = addresses.iter().flatten().copied().collect();
+        self.update_breakpoints(device)?;
+        let breakpoints: Vec<Value> = addresses.iter().map(|address| json!({ "verified": address.is_some() })).collect();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    /// Set the debugger breakpoints to those set by line and by instruction
+    fn update_breakpoints(&mut self, device: &mut Device) -> EmulatorResult<()> {
+        let breakpoints: BTreeSet<u16> = self.source_breakpoints.values().flatten().chain(&self.instruction_breakpoints).copied().collect();
+        for address in self.active_breakpoints.difference(&breakpoints) {
+            self.debugger.execute(DebugCommand::ClearBreakpoint(*address), device)?;
+        }
+        for address in breakpoints.difference(&self.active_breakpoints) {
+            self.debugger.execute(DebugCommand::SetBreakpoint(*address), device)?;
+        }
+        self.active_breakpoints = breakpoints;
+        Ok(())
+    }
+
+    /// pc, then the return addresses from the innermost call outwards
+    fn get_stack_trace(&self, device: &Device) -> Value {
+        let addresses = std::iter::once(device.registers.pc).chain(device.stack.iter().rev().copied());
+        let frames: Vec<Value> = addresses
+            .enumerate()
+            .map(|(index, address)| {
+                let location = self.symbol_map.as_ref().and_then(|symbol_map| symbol_map.get_location(address));
+                let mut frame = json!({
+                    "id": index,
+                    "name": Self::format_address(address),
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": Self::format_address(address),
+                });
+                if let Some(location) = location {
+                    frame["line"] = json!(location.line);
+                    frame["column"] = json!(1);
+                    frame["source"] = json!({ "name": location.file, "path": location.file });
+                }
+                frame
+            })
+            .collect();
+        json!({ "stackFrames": frames, "totalFrames": frames.len() })
+    }
+
+    fn get_variables(reference: u64, device: &Device) -> Value {
+        let variable = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+        let variables: Vec<Value> = match reference {
+            Self::REGISTERS_REFERENCE => {
+                let mut variables: Vec<Value> = device.registers.v.iter().enumerate().map(|(index, value)| variable(format!("V{:X}", index), format!("0x{:02X}", value))).collect();
+                let mut index_register = variable("I".to_string(), Self::format_address(device.registers.i));
+                index_register["memoryReference"] = json!(Self::format_address(device.registers.i));
+                variables.push(index_register);
+                variables.push(variable("PC".to_string(), Self::format_address(device.registers.pc)));
+                variables.push(variable("SP".to_string(), device.stack.len().to_string()));
+                variables
+            }
+            Self::TIMERS_REFERENCE => vec![
+                variable("DT".to_string(), format!("0x{:02X}", device.timer.poll_value())),
+                variable("ST".to_string(), format!("0x{:02X}", device.timer.get_sound_state().timer)),
+            ],
+            _ => Vec::new(),
+        };
+        json!({ "variables": variables })
+    }
+
+    fn read_memory(arguments: &Value, device: &Device) -> Result<Value, String> {
+        let reference = arguments["memoryReference"].as_str().unwrap_or_default();
+        let address = Self::parse_address(reference).ok_or_else(|| format!("Invalid memory reference {}", reference))?;
+        let start = (address as i64).saturating_add(arguments["offset"].as_i64().unwrap_or(0));
+        let start = usize::try_from(start).ok().filter(|start| *start < device.memory.len()).ok_or_else(|| format!("Address {} is outside of memory", start))?;
+        let count = arguments["count"].as_u64().unwrap_or(0) as usize;
+        let end = start.saturating_add(count).min(device.memory.len());
+        Ok(json!({
+            "address": Self::format_address(start as u16),
+            "data": Self::encode_base64(&device.memory[start..end]),
+            "unreadableBytes": count - (end - start),
+        }))
+    }
+
+    fn get_stopped_body(reason: &str) -> Value {
+        json!({ "reason": reason, "threadId": Self::THREAD_ID, "allThreadsStopped": true })
+    }
+
+    fn send_stopped(&mut self, reason: &str) {
+        self.send_event("stopped", Self::get_stopped_body(reason));
+    }
+
+    fn send_response(&mut self, request: &Value, response: Result<Value, String>) {
+        let mut message = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": response.is_ok(),
+        });
+        match response {
+            Ok(body) => message["body"] = body,
+            Err(error) => message["message"] = json!(error),
+        }
+        self.send_message(message);
+    }
+
+    fn send_event(&mut self, event: &str, body: Value) {
+        self.send_message(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    /// A failed write marks the connection as lost, to be disconnected once the current messages are handled
+    fn send_message(&mut self, mut message: Value) {
+        let Some(connection) = self.client_listener.connection_mut() else {
+            return;
+        };
+        self.sequence += 1;
+        message["seq"] = json!(self.sequence);
+        let body = message.to_string();
+        connection.send(format!("Content-Length: {}\r\n\r\n{}", body.len(), body).as_bytes());
+    }
+
+    fn is_connection_lost(&mut self) -> bool {
+        self.client_listener.connection_mut().is_some_and(|connection| connection.is_lost())
+    }
+
+    fn format_address(address: u16) -> String {
+        format!("0x{:04X}", address)
+    }
+
+    fn parse_address(reference: &str) -> Option<u16> {
+        u16::from_str_radix(reference.trim_start_matches("0x"), 16).ok()
+    }
+
+    fn encode_base64(data: &[u8]) -> String {
+        const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+        for chunk in data.chunks(3) {
+            let bits = chunk.iter().enumerate().fold(0u32, |bits, (index, byte)| bits | (*byte as u32) << (16 - 8 * index));
+            for index in 0..4 {
+                if index <= chunk.len() {
+                    encoded.push(ALPHABET[((bits >> (18 - 6 * index)) & 0x3f) as usize] as char);
+                } else {
+                    encoded.push('=');
+                }
+            }
+        }
+        encoded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    use serde_json::{json, Value};
+
+    use super::DapServer;
+    use crate::device::Device;
+    use crate::device::test_util::device_with_program;
+
+    /// Sends requests and collects messages from a server running on the test thread
+    struct TestClient {
+        stream: TcpStream,
+        received: Vec<u8>,
+        sequence: u64,
+    }
+
+    impl TestClient {
+        fn connect(server: &DapServer) -> TestClient {
+            let stream = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+            stream.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+            TestClient { stream, received: Vec::new(), sequence: 0 }
+        }
+
+        fn send(&mut self, command: &str, arguments: Value) {
+            self.sequence += 1;
+            let body = json!({ "seq": self.sequence, "type": "request", "command": command, "arguments": arguments }).to_string();
+            self.stream.write_all(format!("Content-Length: {}\r\n\r\n{}", body.len(), body).as_bytes()).unwrap();
+        }
+
+        /// Run frames until a message matching `is_expected` arrives, skipping other messages
+        fn expect(&mut self, server: &mut DapServer, device: &mut Device, is_expected: impl Fn(&Value) -> bool) -> Value {
+            for _ in 0..100 {
+                while let Some(message) = self.take_message() {
+                    if is_expected(&message) {
+                        return message;
+                    }
+                }
+                server.run_frame(device).unwrap();
+                let mut buffer = [0u8; 4096];
+                if let Ok(length) = self.stream.read(&mut buffer) {
+                    self.received.extend_from_slice(&buffer[..length]);
+                }
+            }
+            panic!("Expected message not received");
+        }
+
+        fn request(&mut self, server: &mut DapServer, device: &mut Device, command: &str, arguments: Value) -> Value {
+            self.send(command, arguments);
+            let response = self.expect(server, device, |message| message["type"] == "response" && message["command"] == command);
+            assert_eq!(json!(true), response["success"], "{}", response);
+            response["body"].clone()
+        }
+
+        fn take_message(&mut self) -> Option<Value> {
+            let text = String::from_utf8_lossy(&self.received).into_owned();
+            let header_end = text.find("\r\n\r\n")?;
+            let length: usize = text[..header_end].trim_start_matches("Content-Length:").trim().parse().unwrap();
+            let body_start = header_end + 4;
+            if text.len() < body_start + length {
+                return None;
+            }
+            self.received.drain(..body_start + length);
+            Some(serde_json::from_str(&text[body_start..body_start + length]).unwrap())
+        }
+    }
+
+    fn get_device_with_subroutine() -> Device {
+        // call 0x206 forever, which adds 1 to v0 and returns
+        device_with_program(&[0x22, 0x06, 0x12, 0x00, 0x00, 0x00, 0x70, 0x01, 0x00, 0xee], 10)
+    }
+
+    #[test]
+    fn test_breakpoints_and_inspection() {
+        let mut device = get_device_with_subroutine();
+        let mut server = DapServer::bind("127.0.0.1:0").unwrap();
+        server.set_symbol_map("0200 1 game.8o\n0206 4 game.8o\n0208 5 game.8o".parse().unwrap());
+        let mut client = TestClient::connect(&server);
+
+        let capabilities = client.request(&mut server, &mut device, "initialize", json!({ "adapterID": "porcel8" }));
+        assert_eq!(json!(true), capabilities["supportsReadMemoryRequest"]);
+        client.request(&mut server, &mut device, "attach", json!({}));
+        let breakpoints = client.request(&mut server, &mut device, "setBreakpoints", json!({ "source": { "path": "/project/game.8o" }, "breakpoints": [{ "line": 5 }, { "line": 2 }] }));
+        assert_eq!(json!([true, false]), json!([breakpoints["breakpoints"][0]["verified"], breakpoints["breakpoints"][1]["verified"]]));
+        client.request(&mut server, &mut device, "configurationDone", json!({}));
+        let stopped = client.expect(&mut server, &mut device, |message| message["event"] == "stopped");
+        assert_eq!("breakpoint", stopped["body"]["reason"]);
+        assert_eq!(0x208, device.registers.pc);
+
+        let stack_trace = client.request(&mut server, &mut device, "stackTrace", json!({ "threadId": 1 }));
+        assert_eq!(json!(5), stack_trace["stackFrames"][0]["line"]);
+        assert_eq!(json!("0x0202"), stack_trace["stackFrames"][1]["instructionPointerReference"]);
+        let variables = client.request(&mut server, &mut device, "variables", json!({ "variablesReference": 1 }));
+        assert_eq!(json!({ "name": "V0", "value": "0x01", "variablesReference": 0 }), variables["variables"][0]);
+        assert_eq!(json!("1"), variables["variables"][18]["value"]);
+        let memory = client.request(&mut server, &mut device, "readMemory", json!({ "memoryReference": "0x0206", "count": 4 }));
+        assert_eq!(json!("cAEA7g=="), memory["data"]);
+
+        client.request(&mut server, &mut device, "stepOut", json!({ "threadId": 1 }));
+        client.expect(&mut server, &mut device, |message| message["event"] == "stopped");
+        assert_eq!((0x202, 0), (device.registers.pc, device.stack.len()));
+        client.request(&mut server, &mut device, "next", json!({ "threadId": 1 }));
+        assert_eq!(0x200, device.registers.pc);
+    }
+
+    #[test]
+    fn test_instruction_breakpoints_and_continue() {
+        let mut device = get_device_with_subroutine();
+        let mut server = DapServer::bind("127.0.0.1:0").unwrap();
+        let mut client = TestClient::connect(&server);
+        client.request(&mut server, &mut device, "initialize", json!({}));
+        client.request(&mut server, &mut device, "attach", json!({ "stopOnEntry": true }));
+        let breakpoints = client.request(&mut server, &mut device, "setBreakpoints", json!({ "source": { "path": "game.8o" }, "breakpoints": [{ "line": 1 }] }));
+        assert_eq!(json!(false), breakpoints["breakpoints"][0]["verified"]);
+        client.request(&mut server, &mut device, "setInstructionBreakpoints", json!({ "breakpoints": [{ "instructionReference": "0x0206" }] }));
+        client.request(&mut server, &mut device, "configurationDone", json!({}));
+        let stopped = client.expect(&mut server, &mut device, |message| message["event"] == "stopped");
+        assert_eq!("entry", stopped["body"]["reason"]);
+        assert_eq!(0x200, device.registers.pc);
+
+        client.request(&mut server, &mut device, "continue", json!({ "threadId": 1 }));
+        client.expect(&mut server, &mut device, |message| message["event"] == "stopped");
+        client.request(&mut server, &mut device, "continue", json!({ "threadId": 1 }));
+        client.expect(&mut server, &mut device, |message| message["event"] == "stopped");
+        assert_eq!((0x206, 1), (device.registers.pc, device.registers.v[0]));
+    }
+
+    #[test]
+    fn test_step_over_and_out_run_whole_frames() {
+        let mut device = get_device_with_subroutine();
+        // wait for the delay timer in the subroutine, which only counts down between frames
+        device.memory[0x206..0x210].copy_from_slice(&[0x60, 0x03, 0xf0, 0x15, 0xf0, 0x07, 0x30, 0x00, 0x12, 0x0a]);
+        device.memory[0x210..0x212].copy_from_slice(&[0x00, 0xee]);
+        let mut server = DapServer::bind("127.0.0.1:0").unwrap();
+        let mut client = TestClient::connect(&server);
+        client.request(&mut server, &mut device, "initialize", json!({}));
+        client.request(&mut server, &mut device, "attach", json!({ "stopOnEntry": true }));
+        client.request(&mut server, &mut device, "configurationDone", json!({}));
+        client.expect(&mut server, &mut device, |message| message["event"] == "stopped");
+
+        client.request(&mut server, &mut device, "next", json!({ "threadId": 1 }));
+        let stopped = client.expect(&mut server, &mut device, |message| message["event"] == "stopped");
+        assert_eq!("step", stopped["body"]["reason"]);
+        assert_eq!((0x202, 0), (device.registers.pc, device.stack.len()));
+        assert_eq!(0, device.timer.poll_value());
+
+        client.request(&mut server, &mut device, "next", json!({ "threadId": 1 }));
+        client.request(&mut server, &mut device, "stepIn", json!({ "threadId": 1 }));
+        client.request(&mut server, &mut device, "stepIn", json!({ "threadId": 1 }));
+        assert_eq!((0x208, 1), (device.registers.pc, device.stack.len()));
+        client.request(&mut server, &mut device, "stepOut", json!({ "threadId": 1 }));
+        client.expect(&mut server, &mut device, |message| message["event"] == "stopped");
+        assert_eq!((0x202, 0), (device.registers.pc, device.stack.len()));
+    }
+
+    #[test]
+    fn test_launch_and_memory_outside_of_memory() {
+        let mut device = get_device_with_subroutine();
+        device.registers.v[0] = 5;
+        device.stack.push(0x202);
+        let rom_directory = std::env::temp_dir().join(format!("porcel8-dap-test-{}", std::process::id()));
+        std::fs::create_dir_all(&rom_directory).unwrap();
+        let rom_file = rom_directory.join("game.ch8");
+        // set v1 to 7, then loop
+        std::fs::write(&rom_file, [0x61, 0x07, 0x12, 0x02]).unwrap();
+        let mut server = DapServer::bind("127.0.0.1:0").unwrap();
+        let mut client = TestClient::connect(&server);
+        client.send("launch", json!({ "program": rom_directory.join("missing.ch8") }));
+        let response = client.expect(&mut server, &mut device, |message| message["command"] == "launch");
+        assert_eq!(json!(false), response["success"]);
+        client.request(&mut server, &mut device, "launch", json!({ "program": rom_file, "stopOnEntry": true }));
+        std::fs::remove_dir_all(&rom_directory).unwrap();
+        client.request(&mut server, &mut device, "configurationDone", json!({}));
+        let stopped = client.expect(&mut server, &mut device, |message| message["event"] == "stopped");
+        assert_eq!("entry", stopped["body"]["reason"]);
+        assert_eq!((0x200, 0, 0), (device.registers.pc, device.registers.v[0], device.stack.len()));
+        assert_eq!([0x61, 0x07, 0x12, 0x02, 0x00, 0x00, 0x00, 0x00], device.memory[0x200..0x208]);
+        client.request(&mut server, &mut device, "next", json!({ "threadId": 1 }));
+        assert_eq!(7, device.registers.v[1]);
+
+        client.send("readMemory", json!({ "memoryReference": "0xFFFF", "offset": 2, "count": 4 }));
+        let response = client.expect(&mut server, &mut device, |message| message["command"] == "readMemory");
+        assert_eq!(json!(false), response["success"]);
+        let memory = client.request(&mut server, &mut device, "readMemory", json!({ "memoryReference": "0x0FFE", "count": 4 }));
+        assert_eq!(json!(2), memory["unreadableBytes"]);
+    }
+
+    #[test]
+    fn test_disconnect_clears_breakpoints() {
+        let mut device = get_device_with_subroutine();
+        let mut server = DapServer::bind("127.0.0.1:0").unwrap();
+        let mut client = TestClient::connect(&server);
+        client.request(&mut server, &mut device, "attach", json!({}));
+        client.request(&mut server, &mut device, "setInstructionBreakpoints", json!({ "breakpoints": [{ "instructionReference": "0x0206" }] }));
+        client.request(&mut server, &mut device, "configurationDone", json!({}));
+        client.expect(&mut server, &mut device, |message| message["event"] == "stopped");
+        drop(client);
+        for _ in 0..3 {
+            server.run_frame(&mut device).unwrap();
+        }
+        assert!(device.registers.v[0] > 2);
+    }
+
+    #[test]
+    fn test_failed_writes_disconnect_the_client() {
+        let mut device = get_device_with_subroutine();
+        let mut server = DapServer::bind("127.0.0.1:0").unwrap();
+        let mut client = TestClient::connect(&server);
+        client.request(&mut server, &mut device, "attach", json!({}));
+        client.request(&mut server, &mut device, "setInstructionBreakpoints", json!({ "breakpoints": [{ "instructionReference": "0x0206" }] }));
+        client.request(&mut server, &mut device, "configurationDone", json!({}));
+        client.expect(&mut server, &mut device, |message| message["event"] == "stopped");
+        // closing with a response left unread resets the connection, so answering the request sent before fails
+        client.send("threads", json!({}));
+        server.run_frame(&mut device).unwrap();
+        client.send("threads", json!({}));
+        std::thread::sleep(Duration::from_millis(10));
+        drop(client);
+        std::thread::sleep(Duration::from_millis(10));
+        for _ in 0..3 {
+            server.run_frame(&mut device).unwrap();
+        }
+        assert!(device.registers.v[0] > 2);
+    }
+
+    #[test]
+    fn test_encode_base64() {
+        assert_eq!("", DapServer::encode_base64(b""));
+        assert_eq!("Zg==", DapServer::encode_base64(b"f"));
+        assert_eq!("Zm8=", DapServer::encode_base64(b"fo"));
+        assert_eq!("Zm9vYmFy", DapServer::encode_base64(b"foobar"));
+    }
+}
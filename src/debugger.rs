@@ -0,0 +1,284 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::str::FromStr;
+
+use crate::device::Device;
+use crate::util::EmulatorResult;
+
+/// A command of the interactive debugger, parsed from a line such as `break 204` or `memory 300 20`.
+/// Addresses and lengths are hex, with an optional `0x` prefix.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DebugCommand {
+    /// Stop running frames
+    Pause,
+    /// Run frames until a breakpoint is hit
+    Continue,
+    /// Execute a number of instructions while paused
+    Step(u32),
+    SetBreakpoint(u16),
+    ClearBreakpoint(u16),
+    ListBreakpoints,
+    Registers,
+    Stack,
+    Timers,
+    /// Dump memory from an address, for a length
+    Memory(u16, u16),
+    Help,
+}
+
+impl DebugCommand {
+    const DEFAULT_MEMORY_LENGTH: u16 = 0x10;
+    pub const HELP: &'static str = "\
+pause (p)                  stop running
+continue (c)               run until a breakpoint
+step (s) [COUNT]           execute COUNT instructions, 1 by default
+break (b) ADDR             set a breakpoint at ADDR
+delete (d) ADDR            clear the breakpoint at ADDR
+breakpoints                list breakpoints
+registers (r)              print the registers
+stack                      print the stack
+timers                     print the delay and sound timers
+memory (m) ADDR [LENGTH]   dump LENGTH bytes of memory from ADDR, 10 by default
+Numbers are hex, except the step count";
+
+    fn parse_hex(value: &str) -> Result<u16, String> {
+        let digits = value.strip_prefix("0x").unwrap_or(value);
+        u16::from_str_radix(digits, 16).map_err(|_| format!("Invalid hex number {}", value))
+    }
+}
+
+impl FromStr for DebugCommand {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut words = line.split_whitespace();
+        let command = words.next().ok_or_else(|| "Empty command".to_string())?;
+        let argument = words.next();
+        let address = || argument.ok_or_else(|| format!("{} needs an address", command)).and_then(Self::parse_hex);
+        let debug_command = match command {
+            "pause" | "p" => DebugCommand::Pause,
+            "continue" | "c" => DebugCommand::Continue,
+            "step" | "s" => {
+                let count = argument.map(|count| count.parse().map_err(|_| format!("Invalid step count {}", count))).transpose()?;
+                DebugCommand::Step(count.unwrap_or(1))
+            }
+            "break" | "b" => DebugCommand::SetBreakpoint(address()?),
+            "delete" | "d" => DebugCommand::ClearBreakpoint(address()?),
+            "breakpoints" => DebugCommand::ListBreakpoints,
+            "registers" | "r" => DebugCommand::Registers,
+            "stack" => DebugCommand::Stack,
+            "timers" => DebugCommand::Timers,
+            "memory" | "m" => {
+                let length = words.next().map(Self::parse_hex).transpose()?;
+                DebugCommand::Memory(address()?, length.unwrap_or(Self::DEFAULT_MEMORY_LENGTH))
+            }
+            "help" | "h" => DebugCommand::Help,
+            _ => return Err(format!("Unknown command {}, try help", command)),
+        };
+        Ok(debug_command)
+    }
+}
+
+/// Why the debugger stopped a frame early
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StopReason {
+    Breakpoint,
+    /// The condition given to `Debugger::run_frame_until` was met
+    Target,
+}
+
+/// Runs the device frame by frame while allowing it to be paused, stepped and stopped at breakpoints.
+/// Breakpoints end the frame early, like the display wait quirk; timers are not counted down while stepping.
+#[derive(Clone, Debug, Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    is_paused: bool,
+    /// Set when resuming, so that the breakpoint at the current instruction does not stop it again
+    is_resuming: bool,
+}
+
+impl Debugger {
+    pub fn new(is_paused: bool) -> Debugger {
+        Debugger {
+            is_paused,
+            ..Debugger::default()
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.is_paused
+    }
+
+    /// Remove every breakpoint, for when the client that set them goes away
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// Run a frame unless paused. Returns a message if a breakpoint was hit, pausing the debugger.
+    pub fn run_frame(&mut self, device: &mut Device) -> EmulatorResult<Option<String>> {
+        let stop = self.run_frame_until(device, |_| false)?;
+        Ok(stop.map(|_| format!("Breakpoint at {}", Self::describe_next_instruction(device))))
+    }
+
+    /// Run a frame unless paused, also stopping before an instruction where `is_target` is true.
+    /// Returns why the frame stopped early, pausing the debugger.
+    pub fn run_frame_until(&mut self, device: &mut Device, mut is_target: impl FnMut(&Device) -> bool) -> EmulatorResult<Option<StopReason>> {
+        if self.is_paused {
+            return Ok(None);
+        }
+        let instructions_per_frame = device.device_config.get_instructions_per_frame();
+        let mut is_resuming = std::mem::take(&mut self.is_resuming);
+        let mut stop = None;
+        device.run_frame_until_breakpoint(instructions_per_frame, |device| {
+            if !is_resuming && self.breakpoints.contains(&device.registers.pc) {
+                stop = Some(StopReason::Breakpoint);
+            } else if is_target(device) {
+                stop = Some(StopReason::Target);
+            }
+            is_resuming = false;
+            stop.is_some()
+        })?;
+        if stop.is_some() {
+            self.is_paused = true;
+        }
+        Ok(stop)
+    }
+
+    /// Execute a command, returning the text to show
+    pub fn execute(&mut self, command: DebugCommand, device: &mut Device) -> EmulatorResult<String> {
+        let output = match command {
+            DebugCommand::Pause => {
+                self.is_paused = true;
+                format!("Paused at {}", Self::describe_next_instruction(device))
+            }
+            DebugCommand::Continue => {
+                self.is_paused = false;
+                self.is_resuming = true;
+                "Continuing".to_string()
+            }
+            DebugCommand::Step(_) if !self.is_paused => "Pause before stepping".to_string(),
+            DebugCommand::Step(count) => {
+                for _ in 0..count {
+                    if device.has_exited() {
+                        break;
+                    }
+                    device.cycle()?;
+                }
+                device.present()?;
+                Self::describe_next_instruction(device)
+            }
+            DebugCommand::SetBreakpoint(address) => {
+                self.breakpoints.insert(address);
+                format!("Breakpoint set at {:04X}", address)
+            }
+            DebugCommand::ClearBreakpoint(address) => {
+                if self.breakpoints.remove(&address) {
+                    format!("Breakpoint cleared at {:04X}", address)
+                } else {
+                    format!("No breakpoint at {:04X}", address)
+                }
+            }
+            DebugCommand::ListBreakpoints if self.breakpoints.is_empty() => "No breakpoints".to_string(),
+            DebugCommand::ListBreakpoints => Self::join_addresses(self.breakpoints.iter()),
+            DebugCommand::Registers => device.registers.to_string(),
+            DebugCommand::Stack if device.stack.is_empty() => "Stack is empty".to_string(),
+            DebugCommand::Stack => Self::join_addresses(device.stack.iter()),
+            DebugCommand::Timers => format!("DT={:02X} ST={:02X}", device.timer.poll_value(), device.timer.get_sound_state().timer),
+            DebugCommand::Memory(address, length) => Self::dump_memory(&device.memory, address as usize, length as usize),
+            DebugCommand::Help => DebugCommand::HELP.to_string(),
+        };
+        Ok(output)
+    }
+
+    /// pc and the instruction there
+    fn describe_next_instruction(device: &Device) -> String {
+        format!("{:04X}: {:?}", device.registers.pc, device.get_next_instruction())
+    }
+
+    fn join_addresses<'a>(addresses: impl Iterator<Item = &'a u16>) -> String {
+        addresses.map(|address| format!("{:04X}", address)).collect::<Vec<_>>().join(" ")
+    }
+
+    /// Hex dump with 16 bytes per line, clamped to the end of memory
+    fn dump_memory(memory: &[u8], address: usize, length: usize) -> String {
+        let start = address.min(memory.len());
+        let end = (address + length).min(memory.len());
+        let mut dump = String::new();
+        for (line_index, line) in memory[start..end].chunks(0x10).enumerate() {
+            if line_index > 0 {
+                dump.push('\n');
+            }
+            write!(dump, "{:04X}:", start + line_index * 0x10).unwrap();
+            for byte in line {
+                write!(dump, " {:02X}", byte).unwrap();
+            }
+        }
+        dump
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DebugCommand, Debugger, StopReason};
+    use crate::device::test_util::counting_device;
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(DebugCommand::Step(1), "s".parse().unwrap());
+        assert_eq!(DebugCommand::Step(20), "step 20".parse().unwrap());
+        assert_eq!(DebugCommand::SetBreakpoint(0x204), "break 0x204".parse().unwrap());
+        assert_eq!(DebugCommand::Memory(0x300, 0x10), "m 300".parse().unwrap());
+        assert_eq!(DebugCommand::Memory(0x300, 0x20), "memory 300 20".parse().unwrap());
+        assert!("break".parse::<DebugCommand>().is_err());
+        assert!("break xyz".parse::<DebugCommand>().is_err());
+        assert!("jump 200".parse::<DebugCommand>().is_err());
+        assert!("".parse::<DebugCommand>().is_err());
+    }
+
+    #[test]
+    fn test_breakpoint_pauses_then_continues_past_it() {
+        let mut device = counting_device();
+        let mut debugger = Debugger::new(false);
+        debugger.execute(DebugCommand::SetBreakpoint(0x202), &mut device).unwrap();
+
+        let message = debugger.run_frame(&mut device).unwrap();
+        assert_eq!(Some("Breakpoint at 0202: AddValueToRegister(1, 1)".to_string()), message);
+        assert!(debugger.is_paused());
+        assert_eq!((1, 0), (device.registers.v[0], device.registers.v[1]));
+        assert_eq!(None, debugger.run_frame(&mut device).unwrap());
+        assert_eq!(0x202, device.registers.pc);
+
+        debugger.execute(DebugCommand::Continue, &mut device).unwrap();
+        assert!(debugger.run_frame(&mut device).unwrap().is_some());
+        assert_eq!((2, 1), (device.registers.v[0], device.registers.v[1]));
+    }
+
+    #[test]
+    fn test_run_frame_until_target() {
+        let mut device = counting_device();
+        let mut debugger = Debugger::new(false);
+        let stop = debugger.run_frame_until(&mut device, |device| device.registers.v[1] == 2).unwrap();
+        assert_eq!(Some(StopReason::Target), stop);
+        assert!(debugger.is_paused());
+        assert_eq!((2, 2, 0x204), (device.registers.v[0], device.registers.v[1], device.registers.pc));
+    }
+
+    #[test]
+    fn test_step_executes_instructions() {
+        let mut device = counting_device();
+        let mut debugger = Debugger::new(true);
+        assert_eq!("0200: AddValueToRegister(0, 1)", debugger.execute(DebugCommand::Step(3), &mut device).unwrap());
+        assert_eq!("0202: AddValueToRegister(1, 1)", debugger.execute(DebugCommand::Step(4), &mut device).unwrap());
+        assert_eq!((3, 2), (device.registers.v[0], device.registers.v[1]));
+    }
+
+    #[test]
+    fn test_dump_memory() {
+        let mut device = counting_device();
+        let mut debugger = Debugger::new(true);
+        let dump = debugger.execute(DebugCommand::Memory(0x1f8, 0x0c), &mut device).unwrap();
+        assert_eq!("01F8: 00 00 00 00 00 00 00 00 70 01 71 01", dump);
+        let dump = debugger.execute(DebugCommand::Memory(0xff8, 0x20), &mut device).unwrap();
+        assert_eq!("0FF8: 00 00 00 00 00 00 00 00", dump);
+    }
+}
@@ -1,104 +1,257 @@
 use crate::{device::instruction::Instruction, util::EmulatorError};
-use crate::device::keyboard::Keyboard;
+use crate::device::font::{Font, FontLayout};
+use crate::device::framebuffer::FrameBuffer;
+use crate::device::frontend::{AudioSink, DisplaySink, InputSource};
+use crate::device::save_state::SaveState;
+use crate::device::sound::SoundState;
 use crate::device::timer::DeviceTimerManager;
 use crate::util::{DeviceConfig, EmulatorResult};
-use rand::random;
-use std::sync::{Arc, Mutex};
-use std::thread::sleep;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 use std::time::Duration;
 
 use super::registers::RegisterFile;
 
 pub struct Device {
     pub registers: RegisterFile,
-    pub memory: Box<[u8; Self::DEVICE_MEMORY_SIZE]>,
+    pub memory: Box<[u8]>,
     pub timer: DeviceTimerManager,
     pub stack: Vec<u16>,
-    pub frame_buffer: Arc<Mutex<Box<[bool; 64 * 32]>>>,
-    pub device_keyboard: Keyboard,
-    pub device_config: DeviceConfig
+    pub frame_buffer: FrameBuffer,
+    pub device_config: DeviceConfig,
+    pub font_layout: FontLayout,
+    /// Set once the program executes the exit instruction
+    exited: bool,
+    /// Source of CXNN random numbers, seedable so that runs can be reproduced
+    rng: ChaCha8Rng,
+    /// Frontend receiving the display after every frame
+    display: Box<dyn DisplaySink + Send>,
+    /// Frontend receiving the sound state after every frame
+    audio: Box<dyn AudioSink + Send>,
+    /// Frontend providing the keypad
+    input: Box<dyn InputSource + Send>,
 }
 
 impl Device {
     pub const DEVICE_MEMORY_SIZE: usize = 1 << 12;
-    pub const FRAME_BUFFER_WIDTH: usize = 64;
-    pub const FRAME_BUFFER_HEIGHT: usize = 32;
-    pub const FRAME_BUFFER_SIZE: usize = Self::FRAME_BUFFER_WIDTH * Self::FRAME_BUFFER_HEIGHT;
+    pub const XO_CHIP_MEMORY_SIZE: usize = 1 << 16;
+    /// Deepest nesting of subroutine calls
+    pub const STACK_SIZE: usize = 16;
     pub fn new(
-        timer: DeviceTimerManager,
-        fb: Arc<Mutex<Box<[bool; Device::FRAME_BUFFER_SIZE]>>>,
-        device_keyboard: Keyboard,
+        display: Box<dyn DisplaySink + Send>,
+        audio: Box<dyn AudioSink + Send>,
+        input: Box<dyn InputSource + Send>,
         device_config: DeviceConfig
     ) -> Device {
-        let memory = vec![0u8; Self::DEVICE_MEMORY_SIZE]
-            .into_boxed_slice()
-            .try_into()
-            .unwrap();
+        let memory = vec![0u8; device_config.get_memory_size()].into_boxed_slice();
         log::trace!("Successfully initiated device memory");
         Device {
             registers: RegisterFile::default(),
             memory,
-            frame_buffer: fb,
-            stack: Vec::with_capacity(16),
-            timer,
-            device_keyboard,
-            device_config
+            frame_buffer: FrameBuffer::new(),
+            stack: Vec::with_capacity(Self::STACK_SIZE),
+            timer: DeviceTimerManager::new(),
+            device_config,
+            font_layout: FontLayout {
+                small_font_start: Self::FONT_MEM_LOCATION_START as u16,
+                small_glyph_height: 0,
+                big_font_start: Self::FONT_MEM_LOCATION_START as u16,
+                big_glyph_height: 0,
+            },
+            exited: false,
+            rng: ChaCha8Rng::seed_from_u64(rand::random()),
+            display,
+            audio,
+            input,
         }
     }
 }
 
 impl Device {
     pub const ROM_START: usize = 0x200;
-    const FONT_HEIGHT: u16 = 5;
-    const FONT_DEFAULT_MEM_LOCATION_START: usize = 0x50;
-    const FONT_DEFAULT_MEM_LOCATION_END: usize = 0x9F;
+    const FONT_MEM_LOCATION_START: usize = 0x50;
+    /// Duration of a 60 Hz frame
+    pub const FRAME_TIME: Duration = Duration::from_nanos(1_000_000_000 / 60);
 
-    pub fn cycle(&mut self) -> EmulatorResult<()> {
-        let time_start = std::time::Instant::now();
-        self.device_keyboard.update_keyboard_registers()?;
+    /// Run one 60 Hz frame: update the input, execute up to the configured number of instructions,
+    /// count the timers down once, then present the display and sound to the frontend
+    pub fn run_frame(&mut self) -> EmulatorResult<()> {
+        self.run_partial_frame(self.device_config.get_instructions_per_frame())?;
+        Ok(())
+    }
 
-        let pc = self.registers.pc as usize;
-        let instr_slice = self.memory.get(pc..pc + 2).expect(format!("Failed to get memory at {}",pc).as_str());
-        self.registers.pc += 2;
+    /// Run a frame that executes at most `max_instructions`, returning the number of instructions executed.
+    /// The frame ends early if the program exits or draws with the display wait quirk.
+    pub fn run_partial_frame(&mut self, max_instructions: u32) -> EmulatorResult<u32> {
+        let (executed_instructions, _) = self.run_frame_until_breakpoint(max_instructions, |_| false)?;
+        Ok(executed_instructions)
+    }
 
-        let instruction = Instruction::decode_instruction(instr_slice);
+    /// Run a frame like `run_partial_frame`, also ending it before executing an instruction
+    /// where `is_breakpoint` is true. It is called with the device before each instruction.
+    /// Returns the number of instructions executed and whether the frame stopped at a breakpoint.
+    pub fn run_frame_until_breakpoint(&mut self, max_instructions: u32, mut is_breakpoint: impl FnMut(&Device) -> bool) -> EmulatorResult<(u32, bool)> {
+        self.input.update()?;
+        let mut executed_instructions = 0;
+        let mut is_at_breakpoint = false;
+        while executed_instructions < max_instructions && !self.exited {
+            if is_breakpoint(self) {
+                is_at_breakpoint = true;
+                break;
+            }
+            let instruction = self.cycle()?;
+            executed_instructions += 1;
+            // The COSMAC VIP waits for the vertical blank after drawing, ending the frame
+            if matches!(instruction, Instruction::Draw(..)) && self.device_config.get_quirks().display_wait {
+                break;
+            }
+        }
+        self.timer.tick();
+        self.present()?;
+        Ok((executed_instructions, is_at_breakpoint))
+    }
+
+    /// Send the display and sound state to the frontends, as done at the end of every frame
+    pub fn present(&mut self) -> EmulatorResult<()> {
+        self.display.present(&self.frame_buffer)?;
+        self.audio.update(self.timer.get_sound_state())
+    }
+
+    /// Execute the instruction at pc, returning it
+    pub fn cycle(&mut self) -> EmulatorResult<Instruction> {
+        let instruction = self.get_next_instruction();
+        self.set_pc(self.registers.pc.wrapping_add(instruction.get_length()));
         self.execute_instruction(instruction)?;
+        Ok(instruction)
+    }
 
-        if let Some(throttling_duration) = self.device_config.get_throttling_config() {
-            let instruction_time = time_start.elapsed();
-            let time_left_to_sleep_for_instruction = throttling_duration.checked_sub(instruction_time).unwrap_or(Duration::ZERO);
-            log::trace!("Instruction took {:?}, left with {:?}",instruction_time,time_left_to_sleep_for_instruction);
-            sleep(time_left_to_sleep_for_instruction);
+    /// Decode the instruction at pc without executing it.
+    /// Instructions at the end of memory wrap around to the start.
+    pub fn get_next_instruction(&self) -> Instruction {
+        let pc = self.registers.pc as usize;
+        // only XO-CHIP has the 4 byte long index load
+        let instruction_length = if self.device_config.is_xo_chip() { Instruction::MAX_LENGTH } else { 2 };
+        let instruction_bytes: Vec<u8> = (pc..pc + instruction_length).map(|address| self.memory_at(address)).collect();
+        Instruction::decode_instruction(&instruction_bytes)
+    }
+
+    /// Byte of memory at an address, wrapping around past the end of memory
+    fn memory_at(&self, address: usize) -> u8 {
+        self.memory[address % self.memory.len()]
+    }
+
+    fn memory_at_mut(&mut self, address: usize) -> &mut u8 {
+        let memory_size = self.memory.len();
+        &mut self.memory[address % memory_size]
+    }
+
+    /// Seed the random number generator, making runs with the same inputs reproducible
+    pub fn set_random_seed(&mut self, seed: u64) {
+        self.rng = ChaCha8Rng::seed_from_u64(seed);
+    }
+
+    /// Snapshot the complete machine, including the keys held down and the random number generator
+    pub fn save_state(&self) -> SaveState {
+        SaveState {
+            registers: self.registers.clone(),
+            stack: self.stack.clone(),
+            memory: self.memory.to_vec(),
+            frame_buffer: self.frame_buffer.clone(),
+            font_layout: self.font_layout,
+            timer: self.timer.clone(),
+            keys_down: (0..=0xfu8).filter(|key| self.input.is_key_down(*key)).fold(0, |keys_down, key| keys_down | (1 << key)),
+            device_config: self.device_config,
+            exited: self.exited,
+            rng: self.rng.clone(),
         }
+    }
 
+    /// Restore a snapshot taken by `save_state`, replacing the configuration as well.
+    /// The frontends are kept and receive the restored display and sound at the end of the next frame.
+    pub fn load_state(&mut self, save_state: &SaveState) {
+        self.registers = save_state.registers.clone();
+        self.stack = save_state.stack.clone();
+        self.memory = save_state.memory.clone().into_boxed_slice();
+        self.frame_buffer = save_state.frame_buffer.clone();
+        self.font_layout = save_state.font_layout;
+        self.timer = save_state.timer.clone();
+        self.input.restore_keys_down(save_state.keys_down);
+        self.device_config = save_state.device_config;
+        self.exited = save_state.exited;
+        self.rng = save_state.rng.clone();
+    }
+
+    /// Skip the instruction at pc, which takes 4 bytes for the XO-CHIP long index load
+    fn skip_next_instruction(&mut self) {
+        let pc = self.registers.pc as usize;
+        let is_long_instruction = self.device_config.is_xo_chip()
+            && [self.memory_at(pc), self.memory_at(pc + 1)] == Instruction::LONG_SET_INDEX_PREFIX.to_be_bytes();
+        self.set_pc(self.registers.pc.wrapping_add(if is_long_instruction { 4 } else { 2 }));
+    }
+    /// Move pc, wrapping around the end of memory like the memory accesses do
+    fn set_pc(&mut self, pc: u16) {
+        self.registers.pc = (pc as usize % self.memory.len()) as u16;
+    }
+    /// Skip an instruction the device does not support, or halt if configured to
+    fn execute_invalid_instruction(&self) -> EmulatorResult<()> {
+        log::info!("Executing passthrough");
+        if self.device_config.should_halt_on_invalid() {
+            return Err(EmulatorError::IOError("Caught Invalid Instruction".to_string()));
+        }
         Ok(())
     }
-    /// convert the 2 indices into one
-    fn get_framebuffer_index(x: usize, y: usize) -> usize {
-        y * Self::FRAME_BUFFER_WIDTH + x
+    /// Whether the program has requested to exit. No more instructions are executed once set.
+    pub fn has_exited(&self) -> bool {
+        self.exited
     }
     pub fn execute_instruction(&mut self, instruction: Instruction) -> EmulatorResult<()> {
         log::trace!("Executing {:?}, {:?}", &instruction, &self.registers);
         match instruction {
             Instruction::InvalidInstruction => {
-                log::info!("Executing passthrough");
-                if self.device_config.should_halt_on_invalid() {
-                    return Err(EmulatorError::IOError("Caught Invalid Instruction".to_string()));
-                }
+                self.execute_invalid_instruction()?;
             },
             Instruction::ClearScreen => {
-                let mut frame_buffer = self
-                    .frame_buffer
-                    .lock()
-                    .expect("Failed to grab framebuffer for drawing");
-                for pixel in frame_buffer.iter_mut() {
-                    *pixel = false;
-                }
+                self.frame_buffer.clear();
                 log::trace!("ClearScreen")
             }
+            Instruction::ScrollDown(n) => {
+                let amount = self.get_scroll_amount(n as usize);
+                self.frame_buffer.scroll_down(amount);
+            }
+            Instruction::ScrollRight => {
+                let amount = self.get_scroll_amount(4);
+                self.frame_buffer.scroll_right(amount);
+            }
+            Instruction::ScrollLeft => {
+                let amount = self.get_scroll_amount(4);
+                self.frame_buffer.scroll_left(amount);
+            }
+            Instruction::Exit => {
+                log::info!("Program requested exit");
+                self.exited = true;
+            }
+            Instruction::SelectPlanes(_)
+            | Instruction::StoreRegisterRangeToMemory(..)
+            | Instruction::LoadRegisterRangeFromMemory(..)
+            | Instruction::LoadAudioPattern
+            | Instruction::SetPitch(_) if !self.device_config.is_xo_chip() => {
+                self.execute_invalid_instruction()?;
+            }
+            Instruction::SelectPlanes(planes) => {
+                self.frame_buffer.select_planes(planes);
+            }
+            Instruction::DisableHighResolution | Instruction::EnableHighResolution if !self.device_config.has_high_resolution() => {
+                self.execute_invalid_instruction()?;
+            }
+            Instruction::DisableHighResolution => {
+                self.frame_buffer.set_high_resolution(false);
+            }
+            Instruction::EnableHighResolution => {
+                self.frame_buffer.set_high_resolution(true);
+            }
             Instruction::JumpTo(new_pc) => {
                 // hint that we're jumping back to self
-                self.registers.pc = new_pc;
+                self.set_pc(new_pc);
             }
             Instruction::SetRegister(reg_location, value) => {
                 self.registers.v[reg_location] = value;
@@ -112,56 +265,71 @@ impl Device {
             Instruction::Draw(regx, regy, n) => {
                 let x = self.registers.v[regx] as usize;
                 let y = self.registers.v[regy] as usize;
-                let toggle_state = self.draw_sprite_at_location(x, y, n);
-                self.set_flag_register(toggle_state);
+                let (collided_rows, clipped_rows) = self.draw_sprite_at_location(x, y, n);
+                let is_high_resolution = self.frame_buffer.is_high_resolution();
+                // SUPER-CHIP 1.1 reports the number of rows that collided or went below the screen
+                if self.device_config.get_quirks().count_collision_rows && is_high_resolution {
+                    self.registers.v[0xf] = (collided_rows + clipped_rows) as u8;
+                } else {
+                    self.set_flag_register(collided_rows > 0);
+                }
             }
             Instruction::JumpAndLink(jump_location) => {
-                self.stack.push(self.registers.pc);
-                self.registers.pc = jump_location;
-            }
-            Instruction::ReturnFromProcedure => {
-                let old_pc = self.stack.pop().expect("Expected value on stack pop");
-                self.registers.pc = old_pc;
+                // calling past the deepest stack level is invalid and the call is skipped
+                if self.stack.len() >= Self::STACK_SIZE {
+                    log::warn!("Stack overflow calling {:#05X}", jump_location);
+                    self.execute_invalid_instruction()?;
+                } else {
+                    self.stack.push(self.registers.pc);
+                    self.set_pc(jump_location);
+                }
             }
+            Instruction::ReturnFromProcedure => match self.stack.pop() {
+                Some(old_pc) => self.set_pc(old_pc),
+                None => {
+                    log::warn!("Stack underflow returning from {:#05X}", self.registers.pc);
+                    self.execute_invalid_instruction()?;
+                }
+            },
 
             Instruction::ConditionalEqSkipNext(regx, num) => {
                 if self.registers.v[regx] == num {
-                    self.registers.pc += 2;
+                    self.skip_next_instruction();
                 }
             }
             Instruction::ConditionalInEqSkipNext(regx, num) => {
                 if self.registers.v[regx] != num {
-                    self.registers.pc += 2;
+                    self.skip_next_instruction();
                 }
             }
             Instruction::ConditionalEqRegisterSkipNext(regx, regy) => {
                 if self.registers.v[regx] == self.registers.v[regy] {
-                    self.registers.pc += 2;
+                    self.skip_next_instruction();
                 }
             }
             Instruction::ConditionalInEqRegisterSkipNext(regx, regy) => {
                 if self.registers.v[regx] != self.registers.v[regy] {
-                    self.registers.pc += 2;
+                    self.skip_next_instruction();
                 }
             }
             Instruction::JumpWithOffset(x, num) => {
-                let regnum = if self.device_config.is_new_chip8() { x } else { 0 };
+                let regnum = if self.device_config.get_quirks().jump_with_vx { x } else { 0 };
                 let new_pc = self.registers.v[regnum] as u16 + num;
-                self.registers.pc = new_pc;
+                self.set_pc(new_pc);
             }
             Instruction::RandomAnd(dest, n) => {
-                self.registers.v[dest] = random::<u8>() & n;
+                self.registers.v[dest] = self.rng.random::<u8>() & n;
             }
             Instruction::SkipIfKeyPressed(x) => {
                 let key_press_expected_for = self.registers.v[x];
-                if self.device_keyboard.query_key_down(key_press_expected_for) {
-                    self.registers.pc += 2;
+                if self.input.is_key_down(key_press_expected_for) {
+                    self.skip_next_instruction();
                 }
             }
             Instruction::SkipIfKeyNotPressed(x) => {
                 let key_press_expected_for = self.registers.v[x];
-                if !self.device_keyboard.query_key_down(key_press_expected_for) {
-                    self.registers.pc += 2;
+                if !self.input.is_key_down(key_press_expected_for) {
+                    self.skip_next_instruction();
                 }
             }
             Instruction::Set(x, y) => {
@@ -169,12 +337,21 @@ impl Device {
             }
             Instruction::Or(x, y) => {
                 self.registers.v[x] |= self.registers.v[y];
+                if self.device_config.get_quirks().vf_reset {
+                    self.set_flag_register(false);
+                }
             }
             Instruction::And(x, y) => {
                 self.registers.v[x] &= self.registers.v[y];
+                if self.device_config.get_quirks().vf_reset {
+                    self.set_flag_register(false);
+                }
             }
             Instruction::Xor(x, y) => {
                 self.registers.v[x] ^= self.registers.v[y];
+                if self.device_config.get_quirks().vf_reset {
+                    self.set_flag_register(false);
+                }
             }
             Instruction::Add(x, y) => {
                 let left = self.registers.v[x];
@@ -198,7 +375,7 @@ impl Device {
                 self.set_flag_register(!is_overflow);
             }
             Instruction::RShift(x, y) => {
-                if !self.device_config.is_new_chip8() {
+                if !self.device_config.get_quirks().shift_in_place {
                     self.registers.v[x] = self.registers.v[y];
                 }
                 let val = self.registers.v[x];
@@ -207,7 +384,7 @@ impl Device {
                 self.set_flag_register(bit_carry);
             }
             Instruction::LShift(x, y) => {
-                if !self.device_config.is_new_chip8() {
+                if !self.device_config.get_quirks().shift_in_place {
                     self.registers.v[x] = self.registers.v[y];
                 }
                 let left = self.registers.v[x];
@@ -217,46 +394,49 @@ impl Device {
             }
 
             Instruction::FetchDelayTimer(x) => {
-                let timer_left = self.timer.poll_value()?;
+                let timer_left = self.timer.poll_value();
                 self.registers.v[x] = timer_left
             }
             Instruction::SetDelayTimer(x) => {
                 let delay_timer_val = self.registers.v[x];
-                self.timer.try_set_timer(delay_timer_val)?;
+                self.timer.set_timer(delay_timer_val);
             }
             Instruction::SetSoundTimer(x) => {
                 let delay_timer_val = self.registers.v[x];
-                self.timer.try_set_sound(delay_timer_val)?;
+                self.timer.set_sound(delay_timer_val);
             }
             Instruction::AddToIndex(x) => {
                 let reg_value = self.registers.v[x];
                 let index_original = self.registers.i;
-                // newer instruction set requires wrapping on 12 bit overflow, and setting vf
-                let addn_res = if self.device_config.is_new_chip8() {
-                    let overflowing = (reg_value as u16 + index_original) >= 0x1000;
+                // some interpreters wrap on overflow past memory, and set vf
+                let addn_res = if self.device_config.get_quirks().index_overflow_flag {
+                    let addition_result = reg_value as usize + index_original as usize;
+                    let overflowing = addition_result >= self.memory.len();
                     self.set_flag_register(overflowing);
-                    (reg_value as u16 + index_original) % 0x1000
+                    (addition_result % self.memory.len()) as u16
                 } else {
-                    reg_value as u16 + index_original
+                    index_original.wrapping_add(reg_value as u16)
                 };
                 self.registers.i = addn_res;
             }
             Instruction::GetKey(x) => {
-                let mut possible_presses = (0..=0xfu8).filter(|x|{self.device_keyboard.query_key_down(*x)});
+                let mut possible_presses = (0..=0xfu8).filter(|x|{self.input.is_key_down(*x)});
                 let pressed = possible_presses.next();
                 if let Some(pressed_key) = pressed {
 
                     self.registers.v[x] = pressed_key;
 
                 } else{
-                    self.registers.pc -= 2;
+                    self.set_pc(self.registers.pc.wrapping_sub(2));
                 }
             }
             Instruction::SetIndexToFontCharacter(x) => {
                 let requested_char = self.registers.v[x];
-                let font_address = Self::FONT_DEFAULT_MEM_LOCATION_START as u16
-                    + Self::FONT_HEIGHT * requested_char as u16;
-                self.registers.i = font_address;
+                self.registers.i = self.font_layout.get_small_glyph_address(requested_char);
+            }
+            Instruction::SetIndexToBigFontCharacter(x) => {
+                let requested_char = self.registers.v[x];
+                self.registers.i = self.font_layout.get_big_glyph_address(requested_char);
             }
             Instruction::DoBCDConversion(x) => {
                 let mut binary_value_to_decode_temp = self.registers.v[x];
@@ -272,24 +452,62 @@ impl Device {
 
                 let val = [hundreds_digit, tens_digit, unit_digit];
                 let index = self.registers.i as usize;
-                self.memory[index..(index + 3)].copy_from_slice(&val);
+                for (offset, digit) in val.into_iter().enumerate() {
+                    *self.memory_at_mut(index + offset) = digit;
+                }
             }
             Instruction::StoreRegistersToMemory(last_reg_to_store) => {
-                let reg_slice = &self.registers.v[0..=last_reg_to_store];
                 let index = self.registers.i as usize;
-                self.memory[index..=(index + last_reg_to_store)].copy_from_slice(reg_slice);
+                for reg in 0..=last_reg_to_store {
+                    *self.memory_at_mut(index + reg) = self.registers.v[reg];
+                }
                 // Old Chip8 used to use i as a incrementing index
-                if !self.device_config.is_new_chip8() {
-                    self.registers.i += last_reg_to_store as u16 + 1;
+                if self.device_config.get_quirks().memory_increment {
+                    self.registers.i = self.registers.i.wrapping_add(last_reg_to_store as u16 + 1);
                 }
             }
             Instruction::LoadRegistersFromMemory(last_reg_to_load) => {
                 let index = self.registers.i as usize;
-                let mem_slice = &self.memory[index..=(index + last_reg_to_load)];
-                self.registers.v[0..=last_reg_to_load].copy_from_slice(mem_slice);
+                for reg in 0..=last_reg_to_load {
+                    self.registers.v[reg] = self.memory_at(index + reg);
+                }
                 // Old Chip8 used to use i as a incrementing index
-                if !self.device_config.is_new_chip8() {
-                    self.registers.i += last_reg_to_load as u16 + 1;
+                if self.device_config.get_quirks().memory_increment {
+                    self.registers.i = self.registers.i.wrapping_add(last_reg_to_load as u16 + 1);
+                }
+            }
+            Instruction::StoreRegistersToFlags(last_reg_to_store) => {
+                let last_reg_to_store = self.get_last_rpl_flag(last_reg_to_store)?;
+                self.registers.rpl[0..=last_reg_to_store].copy_from_slice(&self.registers.v[0..=last_reg_to_store]);
+            }
+            Instruction::LoadRegistersFromFlags(last_reg_to_load) => {
+                let last_reg_to_load = self.get_last_rpl_flag(last_reg_to_load)?;
+                self.registers.v[0..=last_reg_to_load].copy_from_slice(&self.registers.rpl[0..=last_reg_to_load]);
+            }
+            Instruction::LongSetIndex(value) => {
+                self.registers.i = value;
+            }
+            Instruction::LoadAudioPattern => {
+                let index = self.registers.i as usize;
+                let mut pattern = [0u8; SoundState::PATTERN_SIZE];
+                for (offset, byte) in pattern.iter_mut().enumerate() {
+                    *byte = self.memory_at(index + offset);
+                }
+                self.timer.set_sound_pattern(pattern);
+            }
+            Instruction::SetPitch(x) => {
+                self.timer.set_sound_pitch(self.registers.v[x]);
+            }
+            Instruction::StoreRegisterRangeToMemory(x, y) => {
+                let index = self.registers.i as usize;
+                for (offset, reg) in Self::get_register_range(x, y).enumerate() {
+                    *self.memory_at_mut(index + offset) = self.registers.v[reg];
+                }
+            }
+            Instruction::LoadRegisterRangeFromMemory(x, y) => {
+                let index = self.registers.i as usize;
+                for (offset, reg) in Self::get_register_range(x, y).enumerate() {
+                    self.registers.v[reg] = self.memory_at(index + offset);
                 }
             }
         };
@@ -297,70 +515,118 @@ impl Device {
     }
     ///
     /// Draw a sprite at location at (x,y) for n pixels long and 8 pixels wide.
-    /// Returns whether any pixel was toggled
-    fn draw_sprite_at_location(&mut self, x: usize, y: usize, n: u8) -> bool {
-        let mut frame_buffer = self
-            .frame_buffer
-            .lock()
-            .expect("Failed to grab framebuffer for drawing");
-
-        let mut is_pixel_toggled_off = false;
-        for i in 0..n as usize {
-            let index = Self::get_framebuffer_index(x, y + i);
-            let slice_from_memory = self.memory[self.registers.i as usize + i];
-            // if we are drawing below the screen
-            if (y + i) >= Self::FRAME_BUFFER_HEIGHT {
-                log::trace!("Overdraw detected, skipping");
+    /// If n is 0, a 16x16 sprite (2 bytes per row) is drawn instead, as in SUPER-CHIP.
+    /// Each selected plane is drawn with its own sprite, stored one after another starting at index (XO-CHIP).
+    /// Returns the number of rows where a pixel was toggled off, and the number of rows clipped at the bottom
+    fn draw_sprite_at_location(&mut self, x: usize, y: usize, n: u8) -> (usize, usize) {
+        let frame_buffer = &mut self.frame_buffer;
+        // sprites past the end of memory wrap around to the start, like the audio pattern
+        let memory = &self.memory;
+        let memory_at = |address: usize| memory[address % memory.len()];
+        let (sprite_width, sprite_height) = if n == 0 { (16, 16) } else { (8, n as usize) };
+        let bytes_per_row = sprite_width / 8;
+        let selected_planes = frame_buffer.selected_planes();
+        let wrap_sprites = self.device_config.get_quirks().wrap_sprites;
+        // the starting position always wraps around the screen
+        let x = x % frame_buffer.width();
+        let y = y % frame_buffer.height();
+
+        // one bit per row
+        let mut collided_rows_mask = 0u16;
+        let mut clipped_rows = 0;
+        let mut sprite_location = self.registers.i as usize;
+        for plane in (0..FrameBuffer::PLANE_COUNT).map(|plane_index| 1u8 << plane_index) {
+            if (selected_planes & plane) == 0 {
                 continue;
             }
-            for bit_index in (0..8).rev() {
-                // if going out of the screen, stop
-                if Self::get_framebuffer_index(0, y + i + 1) <= (index + (7 - bit_index)) {
-                    break;
+            clipped_rows = 0;
+            for i in 0..sprite_height {
+                let pixel_y = if wrap_sprites { (y + i) % frame_buffer.height() } else { y + i };
+                // if we are drawing below the screen
+                if pixel_y >= frame_buffer.height() {
+                    log::trace!("Overdraw detected, skipping");
+                    clipped_rows += 1;
+                    continue;
                 }
-                let bit_is_true = (slice_from_memory & (1 << bit_index)) == (1 << bit_index);
+                // sprite row, left aligned in 16 bits
+                let row_location = sprite_location + i * bytes_per_row;
+                let slice_from_memory = if bytes_per_row == 2 {
+                    u16::from_be_bytes([memory_at(row_location), memory_at(row_location + 1)])
+                } else {
+                    (memory_at(row_location) as u16) << 8
+                };
+                for bit_offset in 0..sprite_width {
+                    let pixel_x = if wrap_sprites { (x + bit_offset) % frame_buffer.width() } else { x + bit_offset };
+                    // if going out of the screen, stop
+                    if pixel_x >= frame_buffer.width() {
+                        break;
+                    }
+                    let bit_is_true = (slice_from_memory & (0x8000 >> bit_offset)) != 0;
 
-                // if the pixel is going to be toggled false, set this flag bit to true
-                if frame_buffer[index + (7 - bit_index)] && (bit_is_true) {
-                    is_pixel_toggled_off = true;
+                    // if the pixel is going to be toggled false, mark this row as collided
+                    if frame_buffer.toggle_pixel(pixel_x, pixel_y, plane, bit_is_true) {
+                        collided_rows_mask |= 1 << i;
+                    }
                 }
-                frame_buffer[index + (7 - bit_index)] =
-                    frame_buffer[index + (7 - bit_index)] ^ (bit_is_true);
             }
+            sprite_location += sprite_height * bytes_per_row;
+        }
+        (collided_rows_mask.count_ones() as usize, clipped_rows)
+    }
+    /// Registers from x to y, in descending order if y is below x
+    fn get_register_range(x: usize, y: usize) -> impl Iterator<Item = usize> {
+        (0..=x.abs_diff(y)).map(move |offset| if x <= y { x + offset } else { x - offset })
+    }
+    /// SUPER-CHIP only has 8 RPL flags, XO-CHIP has 16.
+    /// Using more than SUPER-CHIP has is an invalid instruction, which only accesses the first 8 unless halting.
+    fn get_last_rpl_flag(&self, last_reg: usize) -> EmulatorResult<usize> {
+        if self.device_config.is_xo_chip() || last_reg < RegisterFile::SUPER_CHIP_RPL_FLAG_COUNT {
+            return Ok(last_reg);
+        }
+        self.execute_invalid_instruction()?;
+        Ok(RegisterFile::SUPER_CHIP_RPL_FLAG_COUNT - 1)
+    }
+    /// SUPER-CHIP 1.1 scrolls by display pixels, which is half of a pixel in the low resolution mode
+    fn get_scroll_amount(&self, n: usize) -> usize {
+        if self.device_config.get_quirks().lores_half_scroll && !self.frame_buffer.is_high_resolution() {
+            n / 2
+        } else {
+            n
         }
-        is_pixel_toggled_off
     }
     fn set_flag_register(&mut self, x: bool) {
         self.registers.v[0xf] = if x { 1 } else { 0 }
     }
 
-    pub fn set_default_font(&mut self) {
-        const DEFAULT_FONT: [u8; Device::FONT_HEIGHT as usize * 16] = [
-            0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
-            0x20, 0x60, 0x20, 0x20, 0x70, // 1
-            0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
-            0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
-            0x90, 0x90, 0xF0, 0x10, 0x10, // 4
-            0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
-            0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
-            0xF0, 0x10, 0x20, 0x40, 0x40, // 7
-            0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
-            0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
-            0xF0, 0x90, 0xF0, 0x90, 0x90, // A
-            0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
-            0xF0, 0x80, 0x80, 0x80, 0xF0, // C
-            0xE0, 0x90, 0x90, 0x90, 0xE0, // D
-            0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
-            0xF0, 0x80, 0xF0, 0x80, 0x80, // F
-        ];
-        log::info!("Loaded default font from memory");
-        self.memory[Self::FONT_DEFAULT_MEM_LOCATION_START..=Self::FONT_DEFAULT_MEM_LOCATION_END]
-            .copy_from_slice(&DEFAULT_FONT);
+    /// Install the small and big fonts into the interpreter area of memory, the big font right after the small one
+    pub fn set_font(&mut self, small_font: &Font, big_font: &Font) {
+        let small_font_start = Self::FONT_MEM_LOCATION_START;
+        let big_font_start = small_font_start + small_font.data().len();
+        let big_font_end = big_font_start + big_font.data().len();
+        assert!(big_font_end <= Self::ROM_START, "Fonts do not fit in interpreter memory");
+
+        self.memory[small_font_start..big_font_start].copy_from_slice(small_font.data());
+        self.memory[big_font_start..big_font_end].copy_from_slice(big_font.data());
+        self.font_layout = FontLayout {
+            small_font_start: small_font_start as u16,
+            small_glyph_height: small_font.glyph_height() as u16,
+            big_font_start: big_font_start as u16,
+            big_glyph_height: big_font.glyph_height() as u16,
+        };
+    }
+    /// Return to the state before the ROM was loaded, with the fonts, configuration and RPL flags kept
+    pub fn reset(&mut self) {
+        self.registers = RegisterFile { rpl: self.registers.rpl, ..RegisterFile::default() };
+        self.stack.clear();
+        self.memory[Self::ROM_START..].fill(0);
+        self.frame_buffer = FrameBuffer::new();
+        self.timer = DeviceTimerManager::new();
+        self.exited = false;
     }
     /// load a rom from bytes
     pub fn load_rom(&mut self, rom: &[u8]) {
         log::info!("Loaded ROM from memory");
-        self.memory[Self::ROM_START..].copy_from_slice(rom);
+        self.memory[Self::ROM_START..(Self::ROM_START + rom.len())].copy_from_slice(rom);
     }
     /// Shift right and get carried out bit
     fn shr_1(left: u8) -> (u8, bool) {
@@ -375,8 +641,542 @@ impl Device {
     }
 }
 
-impl Drop for Device {
-    fn drop(&mut self) {
-        self.timer.send_stop_signal()
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::Sender;
+    use std::sync::{Arc, Mutex};
+
+    use crate::device::font::Font;
+    use crate::device::framebuffer::FrameBuffer;
+    use crate::device::instruction::Instruction;
+    use crate::device::keyboard::{Keyboard, KeyboardEvent};
+    use crate::device::frontend::{InputSource, NullFrontend, ScriptedInput};
+    use crate::device::sound::SoundState;
+    use crate::util::{DeviceConfig, EmulatorResult, QuirkConfig};
+
+    use super::Device;
+
+    fn get_test_device(device_config: DeviceConfig) -> Device {
+        let (device, _keyboard_event_sender) = get_test_device_with_keyboard(device_config);
+        device
+    }
+
+    /// Device with a connected keyboard, so that it can cycle
+    fn get_test_device_with_keyboard(device_config: DeviceConfig) -> (Device, Sender<KeyboardEvent>) {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let keyboard = Keyboard::new(receiver);
+        let display = Arc::new(Mutex::new(FrameBuffer::new()));
+        let audio = Arc::new(Mutex::new(SoundState::default()));
+        let device = Device::new(Box::new(display), Box::new(audio), Box::new(keyboard), device_config);
+        (device, sender)
+    }
+
+    fn get_lit_pixel_count(device: &Device) -> usize {
+        let frame_buffer = &device.frame_buffer;
+        frame_buffer.pixels().iter().filter(|pixel| **pixel != 0).count()
+    }
+
+    #[test]
+    fn test_draw_sets_flag_on_collision() {
+        let mut device = get_test_device(DeviceConfig::new(QuirkConfig::new_chip8(), 10));
+        device.registers.i = 0x300;
+        device.memory[0x300] = 0b1010_0000;
+
+        device.execute_instruction(Instruction::Draw(0, 1, 1)).unwrap();
+        assert_eq!(2, get_lit_pixel_count(&device));
+        assert_eq!(0, device.registers.v[0xf]);
+
+        device.execute_instruction(Instruction::Draw(0, 1, 1)).unwrap();
+        assert_eq!(0, get_lit_pixel_count(&device));
+        assert_eq!(1, device.registers.v[0xf]);
+    }
+
+    #[test]
+    fn test_draw_wraps_sprite_past_end_of_memory() {
+        let mut device = get_test_device(DeviceConfig::new(QuirkConfig::new_chip8(), 10));
+        device.registers.i = 0xfff;
+        device.memory[0xfff] = 0xff;
+        device.memory[0] = 0xff;
+
+        device.execute_instruction(Instruction::Draw(0, 1, 0)).unwrap();
+        assert_eq!(16, get_lit_pixel_count(&device));
+    }
+
+    #[test]
+    fn test_draw_clips_at_right_edge() {
+        let mut device = get_test_device(DeviceConfig::new(QuirkConfig::new_chip8(), 10));
+        device.registers.i = 0x300;
+        device.memory[0x300] = 0xff;
+        device.registers.v[0] = 60;
+
+        device.execute_instruction(Instruction::Draw(0, 1, 1)).unwrap();
+        assert_eq!(4, get_lit_pixel_count(&device));
+    }
+
+    #[test]
+    fn test_draw_16x16_sprite_in_high_resolution() {
+        let mut device = get_test_device(DeviceConfig::new(QuirkConfig::new_chip8(), 10).with_high_resolution(true));
+        device.execute_instruction(Instruction::EnableHighResolution).unwrap();
+        device.registers.i = 0x300;
+        device.memory[0x300..0x320].fill(0xff);
+        device.registers.v[0] = 100;
+        device.registers.v[1] = 40;
+
+        device.execute_instruction(Instruction::Draw(0, 1, 0)).unwrap();
+        assert_eq!(16 * 16, get_lit_pixel_count(&device));
+        assert_eq!(0, device.registers.v[0xf]);
+        {
+            let frame_buffer = &device.frame_buffer;
+            assert_eq!(1, frame_buffer.pixels()[40 * 128 + 100]);
+            assert_eq!(1, frame_buffer.pixels()[55 * 128 + 115]);
+            assert_eq!(0, frame_buffer.pixels()[56 * 128 + 116]);
+        }
+
+        device.execute_instruction(Instruction::Draw(0, 1, 0)).unwrap();
+        assert_eq!(0, get_lit_pixel_count(&device));
+        assert_eq!(1, device.registers.v[0xf]);
+    }
+
+    #[test]
+    fn test_draw_counts_collided_and_clipped_rows_in_high_resolution() {
+        let mut device = get_test_device(DeviceConfig::new(QuirkConfig { count_collision_rows: true, ..QuirkConfig::new_chip8() }, 10).with_high_resolution(true));
+        device.execute_instruction(Instruction::EnableHighResolution).unwrap();
+        device.registers.i = 0x300;
+        device.memory[0x300..0x320].fill(0xff);
+        device.registers.v[1] = 60;
+
+        // 4 rows are drawn and 12 rows are clipped
+        device.execute_instruction(Instruction::Draw(0, 1, 0)).unwrap();
+        assert_eq!(12, device.registers.v[0xf]);
+
+        device.execute_instruction(Instruction::Draw(0, 1, 0)).unwrap();
+        assert_eq!(16, device.registers.v[0xf]);
+    }
+
+    #[test]
+    fn test_draw_collision_is_a_flag_in_low_resolution() {
+        let mut device = get_test_device(DeviceConfig::new(QuirkConfig { count_collision_rows: true, ..QuirkConfig::new_chip8() }, 10));
+        device.registers.i = 0x300;
+        device.memory[0x300..0x304].fill(0xff);
+        device.registers.v[1] = 30;
+
+        device.execute_instruction(Instruction::Draw(0, 1, 4)).unwrap();
+        assert_eq!(0, device.registers.v[0xf]);
+        device.execute_instruction(Instruction::Draw(0, 1, 4)).unwrap();
+        assert_eq!(1, device.registers.v[0xf]);
+    }
+
+    #[test]
+    fn test_font_character_addresses_follow_installed_font() {
+        let mut device = get_test_device(DeviceConfig::new(QuirkConfig::new_chip8(), 10));
+        let small_font = Font::new_small(vec![0xaa; 6 * 16]).unwrap();
+        let big_font = Font::new_big(vec![0xbb; 100]).unwrap();
+        device.set_font(&small_font, &big_font);
+        device.registers.v[2] = 0xf;
+
+        device.execute_instruction(Instruction::SetIndexToFontCharacter(2)).unwrap();
+        assert_eq!(0x50 + 6 * 0xf, device.registers.i);
+
+        device.registers.v[2] = 9;
+        device.execute_instruction(Instruction::SetIndexToBigFontCharacter(2)).unwrap();
+        assert_eq!(0x50 + 96 + 90, device.registers.i);
+        assert_eq!(0xbb, device.memory[device.registers.i as usize]);
+    }
+
+    #[test]
+    fn test_rpl_flags_round_trip() {
+        let mut device = get_test_device(DeviceConfig::new(QuirkConfig::new_chip8(), 10));
+        device.registers.v[0..4].copy_from_slice(&[1, 2, 3, 4]);
+
+        device.execute_instruction(Instruction::StoreRegistersToFlags(2)).unwrap();
+        assert_eq!([1, 2, 3, 0], device.registers.rpl[0..4]);
+
+        device.registers.v[0..4].fill(0);
+        device.execute_instruction(Instruction::LoadRegistersFromFlags(1)).unwrap();
+        assert_eq!([1, 2, 0, 0], device.registers.v[0..4]);
+    }
+
+    #[test]
+    fn test_exit_stops_execution() {
+        let (mut device, _keyboard_event_sender) = get_test_device_with_keyboard(DeviceConfig::new(QuirkConfig::new_chip8(), 10));
+        assert!(!device.has_exited());
+
+        device.execute_instruction(Instruction::Exit).unwrap();
+        assert!(device.has_exited());
+
+        let pc = device.registers.pc;
+        device.run_frame().unwrap();
+        assert_eq!(pc, device.registers.pc);
+    }
+
+    #[test]
+    fn test_rpl_flags_limited_to_8_without_xo_chip() {
+        let mut device = get_test_device(DeviceConfig::new(QuirkConfig::new_chip8(), 10));
+        device.registers.v.fill(9);
+        device.execute_instruction(Instruction::StoreRegistersToFlags(0xf)).unwrap();
+        assert_eq!([9; 8], device.registers.rpl[0..8]);
+        assert_eq!([0; 8], device.registers.rpl[8..16]);
+
+        let mut device = get_test_device(DeviceConfig::new(QuirkConfig::new_chip8(), 10).with_halt_on_invalid(true));
+        device.execute_instruction(Instruction::StoreRegistersToFlags(0x7)).unwrap();
+        assert!(device.execute_instruction(Instruction::StoreRegistersToFlags(0x8)).is_err());
+        assert!(device.execute_instruction(Instruction::LoadRegistersFromFlags(0xf)).is_err());
+
+        let mut device = get_test_device(DeviceConfig::new(QuirkConfig::new_chip8(), 10).with_xo_chip(true));
+        device.registers.v.fill(9);
+        device.execute_instruction(Instruction::StoreRegistersToFlags(0xf)).unwrap();
+        assert_eq!([9; 16], device.registers.rpl);
+    }
+
+    #[test]
+    fn test_xo_chip_memory_size() {
+        let device = get_test_device(DeviceConfig::new(QuirkConfig::new_chip8(), 10));
+        assert_eq!(4096, device.memory.len());
+        let device = get_test_device(DeviceConfig::new(QuirkConfig::new_chip8(), 10).with_xo_chip(true));
+        assert_eq!(65536, device.memory.len());
+    }
+
+    #[test]
+    fn test_long_set_index_cycle() {
+        let (mut device, _keyboard_event_sender) = get_test_device_with_keyboard(DeviceConfig::new(QuirkConfig::new_chip8(), 10).with_xo_chip(true));
+        device.load_rom(&[0xf0, 0x00, 0xbe, 0xef, 0x60, 0x01]);
+
+        device.cycle().unwrap();
+        assert_eq!(0xbeef, device.registers.i);
+        assert_eq!(0x204, device.registers.pc);
+        device.cycle().unwrap();
+        assert_eq!(1, device.registers.v[0]);
+    }
+
+    #[test]
+    fn test_skip_over_long_set_index() {
+        let (mut device, _keyboard_event_sender) = get_test_device_with_keyboard(DeviceConfig::new(QuirkConfig::new_chip8(), 10).with_xo_chip(true));
+        // skip if v0 == 0, then F000 NNNN, then set v1
+        device.load_rom(&[0x30, 0x00, 0xf0, 0x00, 0x12, 0x34, 0x61, 0x01]);
+
+        device.cycle().unwrap();
+        assert_eq!(0x206, device.registers.pc);
+        device.cycle().unwrap();
+        assert_eq!(1, device.registers.v[1]);
+        assert_eq!(0, device.registers.i);
+    }
+
+    #[test]
+    fn test_register_range_store_and_load() {
+        let mut device = get_test_device(DeviceConfig::new(QuirkConfig::new_chip8(), 10).with_xo_chip(true));
+        device.registers.i = 0x400;
+        device.registers.v[2..5].copy_from_slice(&[1, 2, 3]);
+
+        device.execute_instruction(Instruction::StoreRegisterRangeToMemory(2, 4)).unwrap();
+        assert_eq!([1, 2, 3], device.memory[0x400..0x403]);
+        assert_eq!(0x400, device.registers.i);
+
+        // reversed range loads in reverse order
+        device.execute_instruction(Instruction::LoadRegisterRangeFromMemory(7, 5)).unwrap();
+        assert_eq!([3, 2, 1], device.registers.v[5..8]);
+    }
+
+    #[test]
+    fn test_memory_access_wraps_past_end_of_memory() {
+        let mut device = get_test_device(DeviceConfig::new(QuirkConfig::original_chip8(), 10));
+        device.registers.i = 0xffe;
+        device.registers.v[0..4].copy_from_slice(&[1, 2, 3, 4]);
+        device.execute_instruction(Instruction::StoreRegistersToMemory(3)).unwrap();
+        assert_eq!([1, 2], device.memory[0xffe..]);
+        assert_eq!([3, 4], device.memory[0..2]);
+        assert_eq!(0x1002, device.registers.i);
+
+        device.registers.i = 0xffe;
+        device.registers.v[0..4].fill(0);
+        device.execute_instruction(Instruction::LoadRegistersFromMemory(3)).unwrap();
+        assert_eq!([1, 2, 3, 4], device.registers.v[0..4]);
+
+        device.registers.i = 0xfff;
+        device.registers.v[0] = 123;
+        device.execute_instruction(Instruction::DoBCDConversion(0)).unwrap();
+        assert_eq!(1, device.memory[0xfff]);
+        assert_eq!([2, 3], device.memory[0..2]);
+    }
+
+    #[test]
+    fn test_stack_overflow_and_underflow_are_invalid() {
+        let mut device = get_test_device(DeviceConfig::new(QuirkConfig::new_chip8(), 10));
+        for _ in 0..Device::STACK_SIZE + 1 {
+            device.execute_instruction(Instruction::JumpAndLink(0x300)).unwrap();
+        }
+        assert_eq!(Device::STACK_SIZE, device.stack.len());
+        device.stack.clear();
+        device.execute_instruction(Instruction::ReturnFromProcedure).unwrap();
+
+        let mut device = get_test_device(DeviceConfig::new(QuirkConfig::new_chip8(), 10).with_halt_on_invalid(true));
+        assert!(device.execute_instruction(Instruction::ReturnFromProcedure).is_err());
+        device.stack = vec![0x200; Device::STACK_SIZE];
+        assert!(device.execute_instruction(Instruction::JumpAndLink(0x300)).is_err());
+    }
+
+    #[test]
+    fn test_jump_with_offset_wraps_past_end_of_memory() {
+        let mut device = get_test_device(DeviceConfig::new(QuirkConfig::original_chip8(), 10));
+        device.registers.v[0] = 0x10;
+        device.execute_instruction(Instruction::JumpWithOffset(0, 0xffe)).unwrap();
+        assert_eq!(0x00e, device.registers.pc);
+    }
+
+    #[test]
+    fn test_pc_wraps_past_end_of_address_space() {
+        let (mut device, _keyboard_event_sender) = get_test_device_with_keyboard(DeviceConfig::new(QuirkConfig::new_chip8(), 10).with_xo_chip(true));
+        device.registers.pc = 0xfffe;
+        device.memory[0xfffe..].copy_from_slice(&[0x60, 0x01]);
+        device.cycle().unwrap();
+        assert_eq!(1, device.registers.v[0]);
+        assert_eq!(0, device.registers.pc);
+    }
+
+    #[test]
+    fn test_long_set_index_only_decoded_on_xo_chip() {
+        let (mut device, _keyboard_event_sender) = get_test_device_with_keyboard(DeviceConfig::new(QuirkConfig::new_chip8(), 10));
+        device.load_rom(&[0xf0, 0x00, 0xbe, 0xef]);
+        assert_eq!(2, device.get_next_instruction().get_length());
+        device.cycle().unwrap();
+        assert_eq!(0x202, device.registers.pc);
+        assert_eq!(0, device.registers.i);
+    }
+
+    #[test]
+    fn test_legacy_scroll_is_halved_in_low_resolution() {
+        let mut device = get_test_device(DeviceConfig::new(QuirkConfig { lores_half_scroll: true, ..QuirkConfig::new_chip8() }, 10));
+        device.registers.i = 0x300;
+        device.memory[0x300] = 0x80;
+
+        device.execute_instruction(Instruction::Draw(0, 1, 1)).unwrap();
+        device.execute_instruction(Instruction::ScrollDown(4)).unwrap();
+        device.execute_instruction(Instruction::ScrollRight).unwrap();
+        let frame_buffer = &device.frame_buffer;
+        assert_eq!(1, frame_buffer.pixels()[2 * 64 + 2]);
+    }
+
+    #[test]
+    fn test_draw_both_planes_uses_consecutive_sprites() {
+        let mut device = get_test_device(DeviceConfig::new(QuirkConfig::new_chip8(), 10).with_xo_chip(true));
+        device.registers.i = 0x300;
+        device.memory[0x300] = 0b1100_0000;
+        device.memory[0x301] = 0b1010_0000;
+
+        device.execute_instruction(Instruction::SelectPlanes(3)).unwrap();
+        device.execute_instruction(Instruction::Draw(0, 1, 1)).unwrap();
+        {
+            let frame_buffer = &device.frame_buffer;
+            assert_eq!([3, 1, 2, 0], frame_buffer.pixels()[0..4]);
+        }
+        assert_eq!(0, device.registers.v[0xf]);
+
+        // only the second plane collides
+        device.execute_instruction(Instruction::SelectPlanes(2)).unwrap();
+        device.execute_instruction(Instruction::Draw(0, 1, 1)).unwrap();
+        assert_eq!(1, device.registers.v[0xf]);
+        device.execute_instruction(Instruction::ClearScreen).unwrap();
+        let frame_buffer = &device.frame_buffer;
+        assert_eq!([1, 1, 0, 0], frame_buffer.pixels()[0..4]);
+    }
+
+    #[test]
+    fn test_audio_pattern_and_pitch() {
+        let mut device = get_test_device(DeviceConfig::new(QuirkConfig::new_chip8(), 10).with_xo_chip(true));
+        device.registers.i = 0x300;
+        for offset in 0..16 {
+            device.memory[0x300 + offset] = offset as u8;
+        }
+        device.registers.v[3] = 112;
+
+        device.execute_instruction(Instruction::LoadAudioPattern).unwrap();
+        device.execute_instruction(Instruction::SetPitch(3)).unwrap();
+        let sound_state = device.timer.get_sound_state();
+        assert_eq!(Some([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]), sound_state.pattern);
+        assert_eq!(112, sound_state.pitch);
+        assert_eq!(0x300, device.registers.i);
+    }
+
+    #[test]
+    fn test_xo_chip_instructions_are_invalid_without_xo_chip() {
+        let mut device = get_test_device(DeviceConfig::new(QuirkConfig::new_chip8(), 10).with_halt_on_invalid(true));
+        assert!(device.execute_instruction(Instruction::SelectPlanes(3)).is_err());
+        assert!(device.execute_instruction(Instruction::StoreRegisterRangeToMemory(0, 1)).is_err());
+        assert!(device.execute_instruction(Instruction::LoadRegisterRangeFromMemory(0, 1)).is_err());
+        assert!(device.execute_instruction(Instruction::LoadAudioPattern).is_err());
+        assert!(device.execute_instruction(Instruction::SetPitch(0)).is_err());
+        assert_eq!(None, device.timer.get_sound_state().pattern);
+    }
+
+    #[test]
+    fn test_original_chip8_quirks() {
+        let mut device = get_test_device(DeviceConfig::new(QuirkConfig::original_chip8(), 10));
+        device.registers.v[0xf] = 1;
+        device.execute_instruction(Instruction::Or(0, 1)).unwrap();
+        assert_eq!(0, device.registers.v[0xf]);
+
+        device.registers.v[1] = 0b10;
+        device.execute_instruction(Instruction::RShift(0, 1)).unwrap();
+        assert_eq!(1, device.registers.v[0]);
+
+        device.registers.i = 0x300;
+        device.execute_instruction(Instruction::StoreRegistersToMemory(2)).unwrap();
+        assert_eq!(0x303, device.registers.i);
+
+        device.registers.v[0] = 4;
+        device.registers.v[3] = 8;
+        device.execute_instruction(Instruction::JumpWithOffset(3, 0x310)).unwrap();
+        assert_eq!(0x314, device.registers.pc);
+    }
+
+    #[test]
+    fn test_high_resolution_unsupported() {
+        let mut device = get_test_device(DeviceConfig::new(QuirkConfig::original_chip8(), 10));
+        device.execute_instruction(Instruction::EnableHighResolution).unwrap();
+        assert!(!device.frame_buffer.is_high_resolution());
+
+        let mut device = get_test_device(DeviceConfig::new(QuirkConfig::original_chip8(), 10).with_halt_on_invalid(true));
+        assert!(device.execute_instruction(Instruction::EnableHighResolution).is_err());
+    }
+
+    #[test]
+    fn test_draw_start_position_wraps() {
+        let mut device = get_test_device(DeviceConfig::new(QuirkConfig::new_chip8(), 10));
+        device.registers.i = 0x300;
+        device.memory[0x300] = 0b1000_0000;
+        device.registers.v[0] = 64 + 3;
+        device.registers.v[1] = 32 + 2;
+
+        device.execute_instruction(Instruction::Draw(0, 1, 1)).unwrap();
+        let frame_buffer = &device.frame_buffer;
+        assert_eq!(1, frame_buffer.pixels()[2 * 64 + 3]);
+    }
+
+    #[test]
+    fn test_draw_wraps_around_edges() {
+        let quirks = QuirkConfig { wrap_sprites: true, ..QuirkConfig::new_chip8() };
+        let mut device = get_test_device(DeviceConfig::new(quirks, 10));
+        device.registers.i = 0x300;
+        device.memory[0x300] = 0xff;
+        device.memory[0x301] = 0xff;
+        device.registers.v[0] = 60;
+        device.registers.v[1] = 31;
+
+        device.execute_instruction(Instruction::Draw(0, 1, 2)).unwrap();
+        assert_eq!(16, get_lit_pixel_count(&device));
+        {
+            let frame_buffer = &device.frame_buffer;
+            assert_eq!(1, frame_buffer.pixels()[31 * 64 + 63]);
+            assert_eq!(1, frame_buffer.pixels()[3]);
+            assert_eq!(0, frame_buffer.pixels()[4]);
+        }
+
+        device.execute_instruction(Instruction::Draw(0, 1, 2)).unwrap();
+        assert_eq!(0, get_lit_pixel_count(&device));
+        assert_eq!(1, device.registers.v[0xf]);
+    }
+
+    #[test]
+    fn test_run_frame_executes_instructions_then_ticks_timers() {
+        let (mut device, _keyboard_event_sender) = get_test_device_with_keyboard(DeviceConfig::new(QuirkConfig::new_chip8(), 10));
+        // jump to self
+        device.memory[0x200..0x202].copy_from_slice(&[0x12, 0x00]);
+        device.timer.set_timer(5);
+        device.registers.v[0] = 3;
+        device.execute_instruction(Instruction::SetSoundTimer(0)).unwrap();
+
+        device.run_frame().unwrap();
+        assert_eq!(4, device.timer.poll_value());
+        assert_eq!(2, device.timer.get_sound_state().timer);
+    }
+
+    #[test]
+    fn test_display_wait_ends_frame() {
+        let quirks = QuirkConfig { display_wait: true, ..QuirkConfig::new_chip8() };
+        let (mut device, _keyboard_event_sender) = get_test_device_with_keyboard(DeviceConfig::new(quirks, 10));
+        // two draws in a row
+        device.memory[0x200..0x204].copy_from_slice(&[0xd0, 0x11, 0xd0, 0x11]);
+        device.run_frame().unwrap();
+        assert_eq!(0x202, device.registers.pc);
+        device.run_frame().unwrap();
+        assert_eq!(0x204, device.registers.pc);
+    }
+
+    #[test]
+    fn test_seeded_random_is_reproducible() {
+        let mut device = get_test_device(DeviceConfig::new(QuirkConfig::new_chip8(), 10));
+        let mut other_device = get_test_device(DeviceConfig::new(QuirkConfig::new_chip8(), 10));
+        device.set_random_seed(42);
+        other_device.set_random_seed(42);
+        for _ in 0..8 {
+            device.execute_instruction(Instruction::RandomAnd(0, 0xff)).unwrap();
+            other_device.execute_instruction(Instruction::RandomAnd(0, 0xff)).unwrap();
+            assert_eq!(device.registers.v[0], other_device.registers.v[0]);
+        }
+    }
+
+    /// Keypad with a fixed set of keys held down
+    struct HeldKeys(u16);
+
+    impl InputSource for HeldKeys {
+        fn update(&mut self) -> EmulatorResult<()> {
+            Ok(())
+        }
+
+        fn is_key_down(&self, key: u8) -> bool {
+            (self.0 & (1 << key)) != 0
+        }
+    }
+
+    #[test]
+    fn test_run_frame_uses_frontends() {
+        let display = Arc::new(Mutex::new(FrameBuffer::new()));
+        let audio = Arc::new(Mutex::new(SoundState::default()));
+        let device_config = DeviceConfig::new(QuirkConfig::new_chip8(), 10);
+        let mut device = Device::new(Box::new(display.clone()), Box::new(audio.clone()), Box::new(HeldKeys(1 << 0xb)), device_config);
+        // wait for a key, set the sound timer to it and draw its glyph
+        device.set_font(&Font::default_small(), &Font::default_big());
+        device.memory[0x200..0x208].copy_from_slice(&[0xf0, 0x0a, 0xf0, 0x18, 0xf0, 0x29, 0xd1, 0x15]);
+
+        device.run_frame().unwrap();
+        assert_eq!(0xb, device.registers.v[0]);
+        assert_eq!(0xb - 1, audio.lock().unwrap().timer);
+        assert!(get_lit_pixel_count(&device) > 0);
+        assert_eq!(device.frame_buffer, *display.lock().unwrap());
+    }
+
+    #[test]
+    fn test_partial_frame_counts_instructions() {
+        let (mut device, _keyboard_event_sender) = get_test_device_with_keyboard(DeviceConfig::new(QuirkConfig::new_chip8(), 10));
+        // add 1 to v0 forever
+        device.memory[0x200..0x204].copy_from_slice(&[0x70, 0x01, 0x12, 0x00]);
+        assert_eq!(3, device.run_partial_frame(3).unwrap());
+        assert_eq!(2, device.registers.v[0]);
+
+        device.memory[0x202..0x204].copy_from_slice(&[0x00, 0xfd]);
+        assert_eq!(1, device.run_partial_frame(10).unwrap());
+        assert!(device.has_exited());
+        assert_eq!(0, device.run_partial_frame(10).unwrap());
+    }
+
+    #[test]
+    fn test_load_state_replays_identically() {
+        let device_config = DeviceConfig::new(QuirkConfig::new_chip8(), 10);
+        let input = ScriptedInput::new(vec!["0:+3".parse().unwrap()]);
+        let mut device = Device::new(Box::new(NullFrontend), Box::new(NullFrontend), Box::new(input), device_config);
+        device.set_random_seed(5);
+        // store a random number in the next free register, set the delay timer and loop
+        device.memory[0x200..0x20a].copy_from_slice(&[0xc0, 0xff, 0x70, 0x01, 0xf0, 0x15, 0xa3, 0x00, 0x12, 0x00]);
+        device.run_frame().unwrap();
+        let save_state = device.save_state();
+        assert_eq!(1 << 3, save_state.keys_down);
+
+        device.run_frame().unwrap();
+        let expected_registers = device.registers.clone();
+        device.memory[0x300] = 0xaa;
+        device.load_state(&save_state);
+        assert_eq!(save_state, device.save_state());
+        device.run_frame().unwrap();
+        assert_eq!(expected_registers, device.registers);
+        assert_eq!(0, device.memory[0x300]);
     }
 }
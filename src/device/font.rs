@@ -0,0 +1,218 @@
+use crate::util::{EmulatorError, EmulatorResult};
+
+/// A hexadecimal font, stored as consecutive glyphs of `glyph_height` bytes each
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Font {
+    glyph_height: usize,
+    data: Vec<u8>,
+}
+
+impl Font {
+    pub const GLYPH_COUNT: usize = 16;
+    /// Tallest small glyph that can still be drawn with DXYN
+    pub const MAX_SMALL_GLYPH_HEIGHT: usize = 15;
+    pub const BIG_GLYPH_HEIGHT: usize = 10;
+
+    const DEFAULT_SMALL_FONT: [u8; 5 * Font::GLYPH_COUNT] = [
+        0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+        0x20, 0x60, 0x20, 0x20, 0x70, // 1
+        0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+        0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+        0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+        0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+        0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+        0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+        0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+        0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+        0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+        0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+        0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+        0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+        0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+        0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+    ];
+
+    const DEFAULT_BIG_FONT: [u8; Font::BIG_GLYPH_HEIGHT * Font::GLYPH_COUNT] = [
+        0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+        0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+        0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+        0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+        0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+        0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+        0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+        0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+        0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+        0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+        0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+        0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+        0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+        0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+        0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+        0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+    ];
+
+    /// Font of the CHIP-8 interpreter in the COSMAC VIP ROM
+    const VIP_SMALL_FONT: [u8; 5 * Font::GLYPH_COUNT] = [
+        0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+        0x60, 0x20, 0x20, 0x20, 0x70, // 1
+        0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+        0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+        0xA0, 0xA0, 0xF0, 0x20, 0x20, // 4
+        0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+        0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+        0xF0, 0x10, 0x10, 0x10, 0x10, // 7
+        0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+        0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+        0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+        0xF0, 0x50, 0x70, 0x50, 0xF0, // B
+        0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+        0xF0, 0x50, 0x50, 0x50, 0xF0, // D
+        0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+        0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+    ];
+
+    /// Big font of SUPER-CHIP 1.1, which only has the digits
+    const SCHIP_BIG_FONT: [u8; Font::BIG_GLYPH_HEIGHT * 10] = [
+        0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+        0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+        0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+        0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+        0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+        0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+        0x3E, 0x7C, 0xC0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+        0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+        0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+        0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+    ];
+
+    pub fn default_small() -> Font {
+        Font {
+            glyph_height: 5,
+            data: Self::DEFAULT_SMALL_FONT.to_vec(),
+        }
+    }
+
+    pub fn default_big() -> Font {
+        Font {
+            glyph_height: Self::BIG_GLYPH_HEIGHT,
+            data: Self::DEFAULT_BIG_FONT.to_vec(),
+        }
+    }
+
+    pub fn vip_small() -> Font {
+        Font {
+            glyph_height: 5,
+            data: Self::VIP_SMALL_FONT.to_vec(),
+        }
+    }
+
+    pub fn schip_big() -> Font {
+        Font {
+            glyph_height: Self::BIG_GLYPH_HEIGHT,
+            data: Self::SCHIP_BIG_FONT.to_vec(),
+        }
+    }
+
+    /// Create a small font from raw bytes. All 16 glyphs must be present; the glyph height is derived from the size.
+    pub fn new_small(data: Vec<u8>) -> EmulatorResult<Font> {
+        let glyph_height = data.len() / Self::GLYPH_COUNT;
+        if !data.len().is_multiple_of(Self::GLYPH_COUNT) || !(1..=Self::MAX_SMALL_GLYPH_HEIGHT).contains(&glyph_height) {
+            return Err(EmulatorError::InvalidConfiguration(format!(
+                "Small font must contain 16 glyphs of at most {} bytes each, found {} bytes",
+                Self::MAX_SMALL_GLYPH_HEIGHT,
+                data.len()
+            )));
+        }
+        Ok(Font { glyph_height, data })
+    }
+
+    /// Create a big font from raw bytes. Glyphs are 10 bytes high; SUPER-CHIP fonts may only contain the 10 digits.
+    pub fn new_big(data: Vec<u8>) -> EmulatorResult<Font> {
+        let glyph_count = data.len() / Self::BIG_GLYPH_HEIGHT;
+        if !data.len().is_multiple_of(Self::BIG_GLYPH_HEIGHT) || !(1..=Self::GLYPH_COUNT).contains(&glyph_count) {
+            return Err(EmulatorError::InvalidConfiguration(format!(
+                "Big font must contain up to 16 glyphs of {} bytes each, found {} bytes",
+                Self::BIG_GLYPH_HEIGHT,
+                data.len()
+            )));
+        }
+        Ok(Font { glyph_height: Self::BIG_GLYPH_HEIGHT, data })
+    }
+
+    pub fn glyph_height(&self) -> usize {
+        self.glyph_height
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// Where the installed fonts are located in device memory
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FontLayout {
+    pub small_font_start: u16,
+    pub small_glyph_height: u16,
+    pub big_font_start: u16,
+    pub big_glyph_height: u16,
+}
+
+impl FontLayout {
+    /// Address of the small glyph for the lowest nibble of `character`
+    pub fn get_small_glyph_address(&self, character: u8) -> u16 {
+        self.small_font_start + self.small_glyph_height * (character & 0xf) as u16
+    }
+
+    /// Address of the big glyph for the lowest nibble of `character`
+    pub fn get_big_glyph_address(&self, character: u8) -> u16 {
+        self.big_font_start + self.big_glyph_height * (character & 0xf) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Font, FontLayout};
+
+    #[test]
+    fn test_default_fonts() {
+        assert_eq!(5, Font::default_small().glyph_height());
+        assert_eq!(80, Font::default_small().data().len());
+        assert_eq!(10, Font::default_big().glyph_height());
+        assert_eq!(160, Font::default_big().data().len());
+        assert_eq!(80, Font::vip_small().data().len());
+        assert_eq!(100, Font::schip_big().data().len());
+    }
+
+    #[test]
+    fn test_small_font_height_from_size() {
+        let font = Font::new_small(vec![0; 6 * 16]).expect("Valid font rejected");
+        assert_eq!(6, font.glyph_height());
+    }
+
+    #[test]
+    fn test_small_font_invalid_size() {
+        assert!(Font::new_small(vec![0; 81]).is_err());
+        assert!(Font::new_small(vec![]).is_err());
+        assert!(Font::new_small(vec![0; 16 * 16]).is_err());
+    }
+
+    #[test]
+    fn test_big_font_digits_only() {
+        let font = Font::new_big(vec![0; 100]).expect("Valid font rejected");
+        assert_eq!(10, font.glyph_height());
+        assert!(Font::new_big(vec![0; 105]).is_err());
+        assert!(Font::new_big(vec![0; 170]).is_err());
+    }
+
+    #[test]
+    fn test_glyph_address() {
+        let font_layout = FontLayout {
+            small_font_start: 0x50,
+            small_glyph_height: 6,
+            big_font_start: 0xb0,
+            big_glyph_height: 10,
+        };
+        assert_eq!(0x50 + 6 * 0xa, font_layout.get_small_glyph_address(0xa));
+        assert_eq!(0xb0 + 10 * 3, font_layout.get_big_glyph_address(0x13));
+    }
+}
@@ -0,0 +1,118 @@
+use std::time::{Duration, Instant};
+
+use crate::device::Device;
+
+/// Source of time for the frame limiter, so that tests do not have to wait in real time
+pub trait Clock {
+    fn now(&self) -> Instant;
+    fn sleep(&mut self, duration: Duration);
+}
+
+/// The system clock, sleeping the current thread
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// Paces emulated frames to 60 Hz by sleeping at frame boundaries.
+/// Each deadline is exactly one frame after the previous one, so sleep inaccuracies do not accumulate.
+pub struct FrameLimiter<C: Clock = SystemClock> {
+    clock: C,
+    next_frame_start: Instant,
+}
+
+impl FrameLimiter {
+    pub fn new() -> FrameLimiter {
+        FrameLimiter::with_clock(SystemClock)
+    }
+}
+
+impl<C: Clock> FrameLimiter<C> {
+    pub fn with_clock(clock: C) -> FrameLimiter<C> {
+        let next_frame_start = clock.now();
+        FrameLimiter { clock, next_frame_start }
+    }
+
+    /// Sleep until the next frame should start.
+    /// If the emulator fell behind, the schedule restarts from now instead of rushing to catch up.
+    pub fn wait_for_next_frame(&mut self) {
+        self.next_frame_start += Device::FRAME_TIME;
+        let now = self.clock.now();
+        if self.next_frame_start > now {
+            self.clock.sleep(self.next_frame_start - now);
+        } else {
+            self.next_frame_start = now;
+        }
+    }
+}
+
+impl Default for FrameLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Clock, FrameLimiter};
+    use crate::device::Device;
+
+    /// A clock that only moves when slept on or advanced by the test
+    struct TestClock {
+        start: Instant,
+        elapsed: Duration,
+        sleeps: Vec<Duration>,
+    }
+
+    impl Clock for TestClock {
+        fn now(&self) -> Instant {
+            self.start + self.elapsed
+        }
+
+        fn sleep(&mut self, duration: Duration) {
+            self.elapsed += duration;
+            self.sleeps.push(duration);
+        }
+    }
+
+    fn get_test_frame_limiter() -> FrameLimiter<TestClock> {
+        FrameLimiter::with_clock(TestClock { start: Instant::now(), elapsed: Duration::ZERO, sleeps: Vec::new() })
+    }
+
+    #[test]
+    fn test_waits_one_frame_per_call() {
+        let mut frame_limiter = get_test_frame_limiter();
+        frame_limiter.wait_for_next_frame();
+        frame_limiter.wait_for_next_frame();
+        assert_eq!(vec![Device::FRAME_TIME; 2], frame_limiter.clock.sleeps);
+    }
+
+    #[test]
+    fn test_sleeps_less_after_slow_frames() {
+        let mut frame_limiter = get_test_frame_limiter();
+        frame_limiter.clock.elapsed += Duration::from_millis(10);
+        frame_limiter.wait_for_next_frame();
+        assert_eq!(vec![Device::FRAME_TIME - Duration::from_millis(10)], frame_limiter.clock.sleeps);
+    }
+
+    #[test]
+    fn test_restarts_schedule_when_behind() {
+        let mut frame_limiter = get_test_frame_limiter();
+        frame_limiter.clock.elapsed += Device::FRAME_TIME * 5;
+        frame_limiter.wait_for_next_frame();
+        assert!(frame_limiter.clock.sleeps.is_empty());
+        // the next frame gets its full time instead of running late frames back to back
+        frame_limiter.wait_for_next_frame();
+        assert_eq!(vec![Device::FRAME_TIME], frame_limiter.clock.sleeps);
+    }
+}
@@ -0,0 +1,333 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// Display memory of the device.
+/// Holds enough pixels for the SUPER-CHIP 128x64 mode, but only the region of the active resolution is used.
+/// Each pixel holds one bit per XO-CHIP bitplane; drawing, clearing and scrolling only affect the selected planes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FrameBuffer {
+    high_resolution: bool,
+    selected_planes: u8,
+    pixels: Box<[u8; FrameBuffer::MAX_SIZE]>,
+}
+
+impl FrameBuffer {
+    pub const LOW_RES_WIDTH: usize = 64;
+    pub const LOW_RES_HEIGHT: usize = 32;
+    pub const HIGH_RES_WIDTH: usize = 128;
+    pub const HIGH_RES_HEIGHT: usize = 64;
+    pub const MAX_SIZE: usize = Self::HIGH_RES_WIDTH * Self::HIGH_RES_HEIGHT;
+    /// Number of XO-CHIP bitplanes
+    pub const PLANE_COUNT: usize = 2;
+    /// Mask of every bitplane
+    pub const ALL_PLANES: u8 = (1 << Self::PLANE_COUNT) - 1;
+
+    pub fn new() -> FrameBuffer {
+        FrameBuffer {
+            high_resolution: false,
+            selected_planes: 1,
+            pixels: vec![0; Self::MAX_SIZE].into_boxed_slice().try_into().unwrap(),
+        }
+    }
+
+    /// Rebuild a frame buffer from the values returned by its getters and `raw_pixels`
+    pub(crate) fn from_raw_parts(high_resolution: bool, selected_planes: u8, pixels: &[u8]) -> FrameBuffer {
+        let mut frame_buffer = FrameBuffer::new();
+        frame_buffer.high_resolution = high_resolution;
+        frame_buffer.select_planes(selected_planes);
+        frame_buffer.pixels.copy_from_slice(pixels);
+        frame_buffer
+    }
+
+    pub fn is_high_resolution(&self) -> bool {
+        self.high_resolution
+    }
+
+    /// Switch between the 64x32 and 128x64 modes. Switching modes clears every plane.
+    pub fn set_high_resolution(&mut self, high_resolution: bool) {
+        if self.high_resolution != high_resolution {
+            self.high_resolution = high_resolution;
+            self.pixels.fill(0);
+        }
+    }
+
+    /// Mask of the bitplanes affected by drawing, clearing and scrolling
+    pub fn selected_planes(&self) -> u8 {
+        self.selected_planes
+    }
+
+    pub fn select_planes(&mut self, planes: u8) {
+        self.selected_planes = planes & Self::ALL_PLANES;
+    }
+
+    pub fn width(&self) -> usize {
+        if self.high_resolution { Self::HIGH_RES_WIDTH } else { Self::LOW_RES_WIDTH }
+    }
+
+    pub fn height(&self) -> usize {
+        if self.high_resolution { Self::HIGH_RES_HEIGHT } else { Self::LOW_RES_HEIGHT }
+    }
+
+    /// Pixels of the active resolution, row by row. Each value has one bit per plane.
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels[..self.width() * self.height()]
+    }
+
+    /// Every pixel, including those outside the active resolution
+    pub(crate) fn raw_pixels(&self) -> &[u8] {
+        &self.pixels[..]
+    }
+
+    /// Clear the selected planes
+    pub fn clear(&mut self) {
+        let planes = self.selected_planes;
+        for pixel in self.pixels.iter_mut() {
+            *pixel &= !planes;
+        }
+    }
+
+    /// Move the selected planes `n` rows down, filling the top with blank rows
+    pub fn scroll_down(&mut self, n: usize) {
+        self.scroll(0, n as isize);
+    }
+
+    /// Move the selected planes `n` columns right, filling the left with blank columns
+    pub fn scroll_right(&mut self, n: usize) {
+        self.scroll(n as isize, 0);
+    }
+
+    /// Move the selected planes `n` columns left, filling the right with blank columns
+    pub fn scroll_left(&mut self, n: usize) {
+        self.scroll(-(n as isize), 0);
+    }
+
+    /// XOR the pixel at (x,y) of a single plane with `value`.
+    /// Returns true if the pixel was turned off.
+    pub fn toggle_pixel(&mut self, x: usize, y: usize, plane: u8, value: bool) -> bool {
+        let index = self.get_index(x, y);
+        let is_toggled_off = (self.pixels[index] & plane) != 0 && value;
+        if value {
+            self.pixels[index] ^= plane;
+        }
+        is_toggled_off
+    }
+
+    /// Move the selected planes by (dx,dy), leaving the other planes in place
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let width = self.width();
+        let height = self.height();
+        let planes = self.selected_planes;
+        let original = self.pixels[..width * height].to_vec();
+        for y in 0..height {
+            for x in 0..width {
+                let source_x = x as isize - dx;
+                let source_y = y as isize - dy;
+                let is_source_on_screen = (0..width as isize).contains(&source_x) && (0..height as isize).contains(&source_y);
+                let shifted = if is_source_on_screen {
+                    original[source_y as usize * width + source_x as usize] & planes
+                } else {
+                    0
+                };
+                self.pixels[y * width + x] = (original[y * width + x] & !planes) | shifted;
+            }
+        }
+    }
+
+    /// convert the 2 indices into one
+    fn get_index(&self, x: usize, y: usize) -> usize {
+        debug_assert!(x < self.width() && y < self.height());
+        y * self.width() + x
+    }
+}
+
+impl Default for FrameBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Display for FrameBuffer {
+    /// The active resolution as text, one line per row.
+    /// Unlit pixels are `.`, pixels of the first plane `#`, the second plane `+` and both planes `%`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for row in self.pixels().chunks(self.width()) {
+            let line: String = row.iter().map(|pixel| ['.', '#', '+', '%'][(pixel & Self::ALL_PLANES) as usize]).collect();
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// RGB colours for each of the 4 possible pixel values
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Palette {
+    colors: [[u8; 3]; 4],
+}
+
+impl Palette {
+    pub fn new(colors: [[u8; 3]; 4]) -> Palette {
+        Palette { colors }
+    }
+
+    /// Colour of a pixel value
+    pub fn get_color(&self, pixel: u8) -> [u8; 3] {
+        self.colors[(pixel & FrameBuffer::ALL_PLANES) as usize]
+    }
+}
+
+impl Default for Palette {
+    /// Black background, white for the first plane, greys for the second plane and for both planes
+    fn default() -> Self {
+        Palette::new([[0x00; 3], [0xff; 3], [0xaa; 3], [0x55; 3]])
+    }
+}
+
+impl FromStr for Palette {
+    type Err = String;
+
+    /// Parse 4 comma separated hex colours, such as `000000,ffffff,aaaaaa,555555`
+    fn from_str(palette: &str) -> Result<Self, Self::Err> {
+        let colors = palette
+            .split(',')
+            .map(|color| {
+                let color = color.trim().trim_start_matches('#');
+                let rgb = u32::from_str_radix(color, 16).map_err(|_| format!("Invalid colour {}", color))?;
+                if color.len() != 6 {
+                    return Err(format!("Colour {} is not 6 hex digits", color));
+                }
+                Ok([(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8])
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let colors: [[u8; 3]; 4] = colors
+            .try_into()
+            .map_err(|colors: Vec<_>| format!("Expected 4 colours, found {}", colors.len()))?;
+        Ok(Palette::new(colors))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FrameBuffer, Palette};
+
+    #[test]
+    fn test_default_is_low_resolution() {
+        let frame_buffer = FrameBuffer::new();
+        assert!(!frame_buffer.is_high_resolution());
+        assert_eq!(64, frame_buffer.width());
+        assert_eq!(32, frame_buffer.height());
+        assert_eq!(64 * 32, frame_buffer.pixels().len());
+        assert_eq!(1, frame_buffer.selected_planes());
+    }
+
+    #[test]
+    fn test_high_resolution_dimensions() {
+        let mut frame_buffer = FrameBuffer::new();
+        frame_buffer.set_high_resolution(true);
+        assert!(frame_buffer.is_high_resolution());
+        assert_eq!(128, frame_buffer.width());
+        assert_eq!(64, frame_buffer.height());
+        assert_eq!(128 * 64, frame_buffer.pixels().len());
+    }
+
+    #[test]
+    fn test_toggle_pixel_reports_toggle_off() {
+        let mut frame_buffer = FrameBuffer::new();
+        assert!(!frame_buffer.toggle_pixel(3, 4, 1, true));
+        assert_eq!(1, frame_buffer.pixels()[4 * 64 + 3]);
+        assert!(frame_buffer.toggle_pixel(3, 4, 1, true));
+        assert_eq!(0, frame_buffer.pixels()[4 * 64 + 3]);
+    }
+
+    #[test]
+    fn test_toggle_pixel_per_plane() {
+        let mut frame_buffer = FrameBuffer::new();
+        assert!(!frame_buffer.toggle_pixel(3, 4, 1, true));
+        assert!(!frame_buffer.toggle_pixel(3, 4, 2, true));
+        assert_eq!(3, frame_buffer.pixels()[4 * 64 + 3]);
+        assert!(frame_buffer.toggle_pixel(3, 4, 1, true));
+        assert_eq!(2, frame_buffer.pixels()[4 * 64 + 3]);
+    }
+
+    #[test]
+    fn test_resolution_switch_clears_screen() {
+        let mut frame_buffer = FrameBuffer::new();
+        frame_buffer.toggle_pixel(0, 0, 1, true);
+        frame_buffer.toggle_pixel(0, 1, 2, true);
+        frame_buffer.set_high_resolution(true);
+        assert!(frame_buffer.pixels().iter().all(|pixel| *pixel == 0));
+    }
+
+    #[test]
+    fn test_clear_only_selected_planes() {
+        let mut frame_buffer = FrameBuffer::new();
+        frame_buffer.toggle_pixel(0, 0, 1, true);
+        frame_buffer.toggle_pixel(0, 0, 2, true);
+        frame_buffer.select_planes(2);
+        frame_buffer.clear();
+        assert_eq!(1, frame_buffer.pixels()[0]);
+    }
+
+    #[test]
+    fn test_scroll_down() {
+        let mut frame_buffer = FrameBuffer::new();
+        frame_buffer.toggle_pixel(5, 0, 1, true);
+        frame_buffer.toggle_pixel(5, 31, 1, true);
+        frame_buffer.scroll_down(2);
+        assert_eq!(1, frame_buffer.pixels()[2 * 64 + 5]);
+        assert_eq!(1, frame_buffer.pixels().iter().filter(|pixel| **pixel != 0).count());
+    }
+
+    #[test]
+    fn test_scroll_right_and_left() {
+        let mut frame_buffer = FrameBuffer::new();
+        frame_buffer.set_high_resolution(true);
+        frame_buffer.toggle_pixel(0, 10, 1, true);
+        frame_buffer.toggle_pixel(127, 10, 1, true);
+        frame_buffer.scroll_right(4);
+        assert_eq!(1, frame_buffer.pixels()[10 * 128 + 4]);
+        assert_eq!(1, frame_buffer.pixels().iter().filter(|pixel| **pixel != 0).count());
+        frame_buffer.scroll_left(4);
+        assert_eq!(1, frame_buffer.pixels()[10 * 128]);
+        assert_eq!(1, frame_buffer.pixels().iter().filter(|pixel| **pixel != 0).count());
+    }
+
+    #[test]
+    fn test_scroll_only_selected_planes() {
+        let mut frame_buffer = FrameBuffer::new();
+        frame_buffer.toggle_pixel(5, 5, 1, true);
+        frame_buffer.toggle_pixel(5, 5, 2, true);
+        frame_buffer.select_planes(2);
+        frame_buffer.scroll_down(1);
+        assert_eq!(1, frame_buffer.pixels()[5 * 64 + 5]);
+        assert_eq!(2, frame_buffer.pixels()[6 * 64 + 5]);
+    }
+
+    #[test]
+    fn test_display_as_text() {
+        let mut frame_buffer = FrameBuffer::new();
+        frame_buffer.toggle_pixel(0, 0, 1, true);
+        frame_buffer.toggle_pixel(1, 0, 2, true);
+        frame_buffer.toggle_pixel(2, 0, 3, true);
+        let text = frame_buffer.to_string();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(32, lines.len());
+        assert_eq!(format!("#+%{}", ".".repeat(61)), lines[0]);
+        assert_eq!(".".repeat(64), lines[31]);
+    }
+
+    #[test]
+    fn test_palette_from_str() {
+        let palette: Palette = "000000,#ff0000,00ff00,0000ff".parse().unwrap();
+        assert_eq!([0, 0, 0], palette.get_color(0));
+        assert_eq!([0xff, 0, 0], palette.get_color(1));
+        assert_eq!([0, 0xff, 0], palette.get_color(2));
+        assert_eq!([0, 0, 0xff], palette.get_color(3));
+    }
+
+    #[test]
+    fn test_palette_from_str_invalid() {
+        assert!("000000,ffffff".parse::<Palette>().is_err());
+        assert!("000000,ffffff,aaaaaa,55555g".parse::<Palette>().is_err());
+        assert!("000000,ffffff,aaaaaa,555".parse::<Palette>().is_err());
+    }
+}
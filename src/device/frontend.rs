@@ -0,0 +1,198 @@
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use crate::device::framebuffer::FrameBuffer;
+use crate::device::keyboard::Keyboard;
+use crate::device::sound::SoundState;
+use crate::util::EmulatorResult;
+
+/// Receives the display at the end of every frame
+pub trait DisplaySink {
+    fn present(&mut self, frame_buffer: &FrameBuffer) -> EmulatorResult<()>;
+}
+
+/// Receives the sound timer, audio pattern and pitch at the end of every frame.
+/// Sound should play while the sound timer is non-zero.
+pub trait AudioSink {
+    fn update(&mut self, sound_state: &SoundState) -> EmulatorResult<()>;
+}
+
+/// Provides the state of the 16 key keypad
+pub trait InputSource {
+    /// Refresh the key state, called at the start of every frame
+    fn update(&mut self) -> EmulatorResult<()>;
+    fn is_key_down(&self, key: u8) -> bool;
+    /// Hold down the keys of a restored save state, one bit per key.
+    /// Sources following a physical keyboard ignore this, since the keys actually held take precedence.
+    fn restore_keys_down(&mut self, _keys_down: u16) {}
+}
+
+/// Shares the display with another thread, such as a frontend that must draw on the main thread
+impl DisplaySink for Arc<Mutex<FrameBuffer>> {
+    fn present(&mut self, frame_buffer: &FrameBuffer) -> EmulatorResult<()> {
+        self.lock()?.clone_from(frame_buffer);
+        Ok(())
+    }
+}
+
+/// Shares the sound state with another thread, such as an audio callback
+impl AudioSink for Arc<Mutex<SoundState>> {
+    fn update(&mut self, sound_state: &SoundState) -> EmulatorResult<()> {
+        *self.lock()? = *sound_state;
+        Ok(())
+    }
+}
+
+impl InputSource for Keyboard {
+    fn update(&mut self) -> EmulatorResult<()> {
+        self.update_keyboard_registers()
+    }
+
+    fn is_key_down(&self, key: u8) -> bool {
+        self.query_key_down(key)
+    }
+}
+
+/// Discards the display and sound and never has keys held, for running without any frontend
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NullFrontend;
+
+impl DisplaySink for NullFrontend {
+    fn present(&mut self, _frame_buffer: &FrameBuffer) -> EmulatorResult<()> {
+        Ok(())
+    }
+}
+
+impl AudioSink for NullFrontend {
+    fn update(&mut self, _sound_state: &SoundState) -> EmulatorResult<()> {
+        Ok(())
+    }
+}
+
+impl InputSource for NullFrontend {
+    fn update(&mut self) -> EmulatorResult<()> {
+        Ok(())
+    }
+
+    fn is_key_down(&self, _key: u8) -> bool {
+        false
+    }
+}
+
+/// A key press or release at the start of a frame, written as `FRAME:+KEY` or `FRAME:-KEY` with a hex key
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ScriptedKeyEvent {
+    pub frame: u64,
+    pub key: u8,
+    pub is_down: bool,
+}
+
+impl FromStr for ScriptedKeyEvent {
+    type Err = String;
+
+    fn from_str(event: &str) -> Result<Self, Self::Err> {
+        let (frame, key_change) = event.split_once(':').ok_or_else(|| format!("Expected FRAME:+KEY or FRAME:-KEY, found {}", event))?;
+        let frame = frame.parse().map_err(|_| format!("Invalid frame {}", frame))?;
+        let is_down = match key_change.chars().next() {
+            Some('+') => true,
+            Some('-') => false,
+            _ => return Err(format!("Key {} must start with + or -", key_change)),
+        };
+        let key = u8::from_str_radix(&key_change[1..], 16)
+            .ok()
+            .filter(|key| *key <= 0xf)
+            .ok_or_else(|| format!("Invalid key {}", &key_change[1..]))?;
+        Ok(ScriptedKeyEvent { frame, key, is_down })
+    }
+}
+
+/// Replays key presses and releases at given frames, for running without a keyboard
+#[derive(Clone, Debug, Default)]
+pub struct ScriptedInput {
+    /// Events ordered by frame
+    events: Vec<ScriptedKeyEvent>,
+    next_event: usize,
+    frame: u64,
+    keys_down: u16,
+}
+
+impl ScriptedInput {
+    pub fn new(mut events: Vec<ScriptedKeyEvent>) -> ScriptedInput {
+        events.sort_by_key(|event| event.frame);
+        ScriptedInput {
+            events,
+            ..ScriptedInput::default()
+        }
+    }
+}
+
+impl InputSource for ScriptedInput {
+    fn update(&mut self) -> EmulatorResult<()> {
+        while let Some(event) = self.events.get(self.next_event).filter(|event| event.frame <= self.frame) {
+            if event.is_down {
+                self.keys_down |= 1 << event.key;
+            } else {
+                self.keys_down &= !(1 << event.key);
+            }
+            self.next_event += 1;
+        }
+        self.frame += 1;
+        Ok(())
+    }
+
+    fn is_key_down(&self, key: u8) -> bool {
+        (self.keys_down & (1 << key)) != 0
+    }
+
+    fn restore_keys_down(&mut self, keys_down: u16) {
+        self.keys_down = keys_down;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::{AudioSink, DisplaySink, InputSource, ScriptedInput, ScriptedKeyEvent};
+    use crate::device::framebuffer::FrameBuffer;
+    use crate::device::sound::SoundState;
+
+    #[test]
+    fn test_shared_display() {
+        let mut shared_frame_buffer = Arc::new(Mutex::new(FrameBuffer::new()));
+        let mut frame_buffer = FrameBuffer::new();
+        frame_buffer.set_high_resolution(true);
+        frame_buffer.toggle_pixel(1, 1, 1, true);
+        shared_frame_buffer.present(&frame_buffer).unwrap();
+        assert_eq!(frame_buffer, *shared_frame_buffer.lock().unwrap());
+    }
+
+    #[test]
+    fn test_shared_audio() {
+        let mut shared_sound_state = Arc::new(Mutex::new(SoundState::default()));
+        let sound_state = SoundState { timer: 3, pattern: Some([0xaa; 16]), pitch: 80 };
+        shared_sound_state.update(&sound_state).unwrap();
+        assert_eq!(sound_state, *shared_sound_state.lock().unwrap());
+    }
+
+    #[test]
+    fn test_scripted_key_event_from_str() {
+        assert_eq!(ScriptedKeyEvent { frame: 30, key: 0xa, is_down: true }, "30:+a".parse().unwrap());
+        assert_eq!(ScriptedKeyEvent { frame: 0, key: 5, is_down: false }, "0:-5".parse().unwrap());
+        assert!("30:a".parse::<ScriptedKeyEvent>().is_err());
+        assert!("30:+10".parse::<ScriptedKeyEvent>().is_err());
+        assert!("x:+1".parse::<ScriptedKeyEvent>().is_err());
+        assert!("30".parse::<ScriptedKeyEvent>().is_err());
+    }
+
+    #[test]
+    fn test_scripted_input_applies_events_per_frame() {
+        let mut input = ScriptedInput::new(vec!["2:-5".parse().unwrap(), "1:+5".parse().unwrap()]);
+        input.update().unwrap();
+        assert!(!input.is_key_down(5));
+        input.update().unwrap();
+        assert!(input.is_key_down(5));
+        input.update().unwrap();
+        assert!(!input.is_key_down(5));
+    }
+}
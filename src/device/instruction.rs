@@ -1,13 +1,26 @@
 use byteorder::{BigEndian, ByteOrder};
 
-#[derive(Eq, PartialEq, Debug)]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Instruction {
     /// Invalid instruction that may be skipped or raise error
     InvalidInstruction,
+    /// 00CN - Scroll the screen down by N pixels (SUPER-CHIP)
+    ScrollDown(u8),
     /// 00E0 - Clear the screen
     ClearScreen,
     /// 00EE - Return from procedure
     ReturnFromProcedure,
+    /// 00FB - Scroll the screen right by 4 pixels (SUPER-CHIP)
+    ScrollRight,
+    /// 00FC - Scroll the screen left by 4 pixels (SUPER-CHIP)
+    ScrollLeft,
+    /// 00FD - Exit the interpreter (SUPER-CHIP)
+    Exit,
+    /// 00FE - Switch to the 64x32 low resolution mode (SUPER-CHIP)
+    DisableHighResolution,
+    /// 00FF - Switch to the 128x64 high resolution mode (SUPER-CHIP)
+    EnableHighResolution,
     /// 1NNN - Jump to location
     JumpTo(u16),
     /// 2NNN - Link and jump
@@ -18,6 +31,10 @@ pub enum Instruction {
     ConditionalInEqSkipNext(usize, u8),
     /// 5XY0 - If registers equal, Skip next
     ConditionalEqRegisterSkipNext(usize, usize),
+    /// 5XY2 - Store registers vx to vy to memory location pointed to by index (XO-CHIP)
+    StoreRegisterRangeToMemory(usize, usize),
+    /// 5XY3 - Load registers vx to vy from memory location pointed to by index (XO-CHIP)
+    LoadRegisterRangeFromMemory(usize, usize),
     /// 6XNN - Set register to value
     SetRegister(usize, u8),
     /// 7XNN - Add value to register
@@ -37,6 +54,12 @@ pub enum Instruction {
     SkipIfKeyPressed(usize),
     /// EXA1 - Check if key is not pressed
     SkipIfKeyNotPressed(usize),
+    /// F000 NNNN - Set index to a 16 bit address (XO-CHIP)
+    LongSetIndex(u16),
+    /// FN01 - Select the bitplanes N to draw on (XO-CHIP)
+    SelectPlanes(u8),
+    /// F002 - Load the 16 byte audio pattern pointed to by index (XO-CHIP)
+    LoadAudioPattern,
     /// FX07 - Get delay timer, put into register
     FetchDelayTimer(usize),
     /// FX15 - set delay timer as register
@@ -49,12 +72,20 @@ pub enum Instruction {
     GetKey(usize),
     /// FX29 - Set index to register-requested font char address in memory
     SetIndexToFontCharacter(usize),
+    /// FX3A - Set the audio pitch to the register value (XO-CHIP)
+    SetPitch(usize),
+    /// FX30 - Set index to register-requested big font char address in memory (SUPER-CHIP)
+    SetIndexToBigFontCharacter(usize),
     /// FX33 - Convert register val to bcd and store at location pointed by index
     DoBCDConversion(usize),
     /// FX55 - Store all registers from v0 to vx to memory location pointed to by index
     StoreRegistersToMemory(usize),
     /// FX65 - Load all registers from v0 to vx to memory location pointed to by index
     LoadRegistersFromMemory(usize),
+    /// FX75 - Store all registers from v0 to vx to the RPL user flags (SUPER-CHIP)
+    StoreRegistersToFlags(usize),
+    /// FX85 - Load all registers from v0 to vx from the RPL user flags (SUPER-CHIP)
+    LoadRegistersFromFlags(usize),
 
     // ALU operations going ahead
     /// 8XY0 - x=y
@@ -78,13 +109,29 @@ pub enum Instruction {
 }
 
 impl Instruction {
+    /// Longest instruction in bytes
+    pub const MAX_LENGTH: usize = 4;
+    /// First half of the 4 byte F000 NNNN instruction
+    pub const LONG_SET_INDEX_PREFIX: u16 = 0xF000;
+
+    /// Decode the instruction at the start of `location`, which holds 2 bytes or more.
+    /// A 4 byte instruction is only decoded if `location` holds all of it.
     pub fn decode_instruction(location: &[u8]) -> Instruction {
-        assert_eq!(location.len(), 2);
+        assert!(location.len() >= 2);
         let instruction = BigEndian::read_u16(location);
+        if instruction == Self::LONG_SET_INDEX_PREFIX && location.len() >= 4 {
+            return Instruction::LongSetIndex(BigEndian::read_u16(&location[2..4]));
+        }
         let outer_instruction_nibble = (instruction & 0xF000) >> 12;
         match outer_instruction_nibble {
+            0x0 if (instruction & 0xfff0) == 0xc0 => Instruction::ScrollDown((instruction & 0xf) as u8),
             0x0 if instruction == 0xe0 => Instruction::ClearScreen,
             0x0 if instruction == 0xee => Instruction::ReturnFromProcedure,
+            0x0 if instruction == 0xfb => Instruction::ScrollRight,
+            0x0 if instruction == 0xfc => Instruction::ScrollLeft,
+            0x0 if instruction == 0xfd => Instruction::Exit,
+            0x0 if instruction == 0xfe => Instruction::DisableHighResolution,
+            0x0 if instruction == 0xff => Instruction::EnableHighResolution,
             0x0 => {
                 log::warn!("Ignoring unsupported instruction {}", instruction);
                 Instruction::InvalidInstruction
@@ -102,10 +149,17 @@ impl Instruction {
                 Instruction::ConditionalInEqSkipNext(register as usize, val as u8)
             }
             0x5 => {
-                let register_x = (instruction & 0xf00) >> 8;
-                let register_y = (instruction & 0xf0) >> 4;
-
-                Instruction::ConditionalEqRegisterSkipNext(register_x as usize, register_y as usize)
+                let register_x = ((instruction & 0xf00) >> 8) as usize;
+                let register_y = ((instruction & 0xf0) >> 4) as usize;
+                match instruction & 0xf {
+                    0x0 => Instruction::ConditionalEqRegisterSkipNext(register_x, register_y),
+                    0x2 => Instruction::StoreRegisterRangeToMemory(register_x, register_y),
+                    0x3 => Instruction::LoadRegisterRangeFromMemory(register_x, register_y),
+                    _ => {
+                        log::error!("Encountered unexpected register instruction {}", instruction);
+                        Instruction::InvalidInstruction
+                    }
+                }
             }
             0x6 => Instruction::SetRegister(
                 ((instruction & 0x0f00) >> 8) as usize,
@@ -150,6 +204,13 @@ impl Instruction {
                 let x = (instruction & 0xf00) >> 8;
                 Instruction::SkipIfKeyNotPressed(x as usize)
             }
+            0xF if (instruction & 0xff) == 0x01 => {
+                let n = (instruction & 0xf00) >> 8;
+                Instruction::SelectPlanes(n as u8)
+            }
+            0xF if instruction == 0xF002 => {
+                Instruction::LoadAudioPattern
+            }
             0xF if (instruction & 0xff) == 0x07 => {
                 let x = (instruction & 0xf00) >> 8;
                 Instruction::FetchDelayTimer(x as usize)
@@ -174,6 +235,14 @@ impl Instruction {
                 let x = (instruction & 0xf00) >> 8;
                 Instruction::SetIndexToFontCharacter(x as usize)
             }
+            0xF if (instruction & 0xff) == 0x30 => {
+                let x = (instruction & 0xf00) >> 8;
+                Instruction::SetIndexToBigFontCharacter(x as usize)
+            }
+            0xF if (instruction & 0xff) == 0x3A => {
+                let x = (instruction & 0xf00) >> 8;
+                Instruction::SetPitch(x as usize)
+            }
             0xF if (instruction & 0xff) == 0x33 => {
                 let x = (instruction & 0xf00) >> 8;
                 Instruction::DoBCDConversion(x as usize)
@@ -186,6 +255,14 @@ impl Instruction {
                 let x = (instruction & 0xf00) >> 8;
                 Instruction::LoadRegistersFromMemory(x as usize)
             }
+            0xF if (instruction & 0xff) == 0x75 => {
+                let x = (instruction & 0xf00) >> 8;
+                Instruction::StoreRegistersToFlags(x as usize)
+            }
+            0xF if (instruction & 0xff) == 0x85 => {
+                let x = (instruction & 0xf00) >> 8;
+                Instruction::LoadRegistersFromFlags(x as usize)
+            }
             instruction_nibble => {
                 log::error!("Unimplemented instruction with nibble {:?}",instruction_nibble);
                 Instruction::InvalidInstruction
@@ -193,6 +270,78 @@ impl Instruction {
         }
     }
 
+    /// Encode the instruction, the inverse of [Instruction::decode_instruction].
+    /// An invalid instruction is encoded as 0000, which decodes as invalid.
+    pub fn encode_instruction(&self) -> Vec<u8> {
+        let xy = |x: usize, y: usize| ((x as u16 & 0xf) << 8) | ((y as u16 & 0xf) << 4);
+        let xnn = |x: usize, nn: u8| ((x as u16 & 0xf) << 8) | nn as u16;
+        let x = |x: usize| (x as u16 & 0xf) << 8;
+        let instruction = match *self {
+            Instruction::InvalidInstruction => 0x0000,
+            Instruction::ScrollDown(n) => 0x00c0 | (n as u16 & 0xf),
+            Instruction::ClearScreen => 0x00e0,
+            Instruction::ReturnFromProcedure => 0x00ee,
+            Instruction::ScrollRight => 0x00fb,
+            Instruction::ScrollLeft => 0x00fc,
+            Instruction::Exit => 0x00fd,
+            Instruction::DisableHighResolution => 0x00fe,
+            Instruction::EnableHighResolution => 0x00ff,
+            Instruction::JumpTo(address) => 0x1000 | (address & 0xfff),
+            Instruction::JumpAndLink(address) => 0x2000 | (address & 0xfff),
+            Instruction::ConditionalEqSkipNext(register, value) => 0x3000 | xnn(register, value),
+            Instruction::ConditionalInEqSkipNext(register, value) => 0x4000 | xnn(register, value),
+            Instruction::ConditionalEqRegisterSkipNext(register_x, register_y) => 0x5000 | xy(register_x, register_y),
+            Instruction::StoreRegisterRangeToMemory(register_x, register_y) => 0x5002 | xy(register_x, register_y),
+            Instruction::LoadRegisterRangeFromMemory(register_x, register_y) => 0x5003 | xy(register_x, register_y),
+            Instruction::SetRegister(register, value) => 0x6000 | xnn(register, value),
+            Instruction::AddValueToRegister(register, value) => 0x7000 | xnn(register, value),
+            Instruction::ConditionalInEqRegisterSkipNext(register_x, register_y) => 0x9000 | xy(register_x, register_y),
+            Instruction::SetIndex(address) => 0xa000 | (address & 0xfff),
+            // the register is the top nibble of the address, so it is not encoded separately
+            Instruction::JumpWithOffset(_, address) => 0xb000 | (address & 0xfff),
+            Instruction::RandomAnd(register, mask) => 0xc000 | xnn(register, mask),
+            Instruction::Draw(register_x, register_y, n) => 0xd000 | xy(register_x, register_y) | (n as u16 & 0xf),
+            Instruction::SkipIfKeyPressed(register) => 0xe09e | x(register),
+            Instruction::SkipIfKeyNotPressed(register) => 0xe0a1 | x(register),
+            Instruction::LongSetIndex(address) => {
+                return [Self::LONG_SET_INDEX_PREFIX.to_be_bytes(), address.to_be_bytes()].concat();
+            }
+            Instruction::SelectPlanes(planes) => 0xf001 | ((planes as u16 & 0xf) << 8),
+            Instruction::LoadAudioPattern => 0xf002,
+            Instruction::FetchDelayTimer(register) => 0xf007 | x(register),
+            Instruction::SetDelayTimer(register) => 0xf015 | x(register),
+            Instruction::SetSoundTimer(register) => 0xf018 | x(register),
+            Instruction::AddToIndex(register) => 0xf01e | x(register),
+            Instruction::GetKey(register) => 0xf00a | x(register),
+            Instruction::SetIndexToFontCharacter(register) => 0xf029 | x(register),
+            Instruction::SetPitch(register) => 0xf03a | x(register),
+            Instruction::SetIndexToBigFontCharacter(register) => 0xf030 | x(register),
+            Instruction::DoBCDConversion(register) => 0xf033 | x(register),
+            Instruction::StoreRegistersToMemory(register) => 0xf055 | x(register),
+            Instruction::LoadRegistersFromMemory(register) => 0xf065 | x(register),
+            Instruction::StoreRegistersToFlags(register) => 0xf075 | x(register),
+            Instruction::LoadRegistersFromFlags(register) => 0xf085 | x(register),
+            Instruction::Set(register_x, register_y) => 0x8000 | xy(register_x, register_y),
+            Instruction::Or(register_x, register_y) => 0x8001 | xy(register_x, register_y),
+            Instruction::And(register_x, register_y) => 0x8002 | xy(register_x, register_y),
+            Instruction::Xor(register_x, register_y) => 0x8003 | xy(register_x, register_y),
+            Instruction::Add(register_x, register_y) => 0x8004 | xy(register_x, register_y),
+            Instruction::Sub(register_x, register_y) => 0x8005 | xy(register_x, register_y),
+            Instruction::RShift(register_x, register_y) => 0x8006 | xy(register_x, register_y),
+            Instruction::RSub(register_x, register_y) => 0x8007 | xy(register_x, register_y),
+            Instruction::LShift(register_x, register_y) => 0x800e | xy(register_x, register_y),
+        };
+        instruction.to_be_bytes().to_vec()
+    }
+
+    /// Number of bytes taken by the instruction
+    pub fn get_length(&self) -> u16 {
+        match self {
+            Instruction::LongSetIndex(_) => 4,
+            _ => 2,
+        }
+    }
+
     fn decode_arithmetic_instruction(instruction: u16) -> Instruction {
         assert_eq!(instruction & 0xF000, 0x8000);
         let reg_x = ((instruction & 0xf00) >> 8) as usize;
@@ -221,6 +370,28 @@ mod tests {
     use crate::device::instruction::Instruction;
     use crate::device::instruction::Instruction::*;
 
+    #[test]
+    fn test_encode_is_inverse_of_decode() {
+        let instructions = [
+            ScrollDown(3), ClearScreen, ReturnFromProcedure, ScrollRight, ScrollLeft, Exit, DisableHighResolution, EnableHighResolution,
+            JumpTo(0x123), JumpAndLink(0xfaf), ConditionalEqSkipNext(0xf, 0xad), ConditionalInEqSkipNext(1, 2),
+            ConditionalEqRegisterSkipNext(1, 2), StoreRegisterRangeToMemory(3, 4), LoadRegisterRangeFromMemory(4, 3),
+            SetRegister(5, 0xff), AddValueToRegister(6, 1), ConditionalInEqRegisterSkipNext(7, 8), SetIndex(0x456),
+            JumpWithOffset(2, 0x234), RandomAnd(9, 0x0f), Draw(0xa, 0xb, 5), SkipIfKeyPressed(0xc), SkipIfKeyNotPressed(0xd),
+            LongSetIndex(0xbeef), SelectPlanes(3), LoadAudioPattern, FetchDelayTimer(1), SetDelayTimer(2), SetSoundTimer(3),
+            AddToIndex(4), GetKey(5), SetIndexToFontCharacter(6), SetPitch(7), SetIndexToBigFontCharacter(8),
+            DoBCDConversion(9), StoreRegistersToMemory(0xa), LoadRegistersFromMemory(0xb), StoreRegistersToFlags(0xc),
+            LoadRegistersFromFlags(0xd), Set(1, 2), Or(1, 2), And(1, 2), Xor(1, 2), Add(1, 2), Sub(1, 2), RShift(1, 2),
+            RSub(1, 2), LShift(1, 2),
+        ];
+        for instruction in instructions {
+            let bytes = instruction.encode_instruction();
+            assert_eq!(instruction.get_length() as usize, bytes.len());
+            assert_eq!(instruction, Instruction::decode_instruction(&bytes));
+        }
+        assert_eq!(vec![0xf0, 0x00, 0xbe, 0xef], LongSetIndex(0xbeef).encode_instruction());
+    }
+
     #[test]
     fn test_clear_screen() {
         let instruction_bytes = 0x00e0_u16.to_be_bytes();
@@ -235,6 +406,48 @@ mod tests {
         assert_eq!(ins, ReturnFromProcedure);
     }
 
+    #[test]
+    fn test_scroll_down() {
+        let instruction_bytes = 0x00c5_u16.to_be_bytes();
+        let ins = Instruction::decode_instruction(&instruction_bytes);
+        assert_eq!(ins, ScrollDown(5));
+    }
+
+    #[test]
+    fn test_scroll_right() {
+        let instruction_bytes = 0x00fb_u16.to_be_bytes();
+        let ins = Instruction::decode_instruction(&instruction_bytes);
+        assert_eq!(ins, ScrollRight);
+    }
+
+    #[test]
+    fn test_scroll_left() {
+        let instruction_bytes = 0x00fc_u16.to_be_bytes();
+        let ins = Instruction::decode_instruction(&instruction_bytes);
+        assert_eq!(ins, ScrollLeft);
+    }
+
+    #[test]
+    fn test_exit() {
+        let instruction_bytes = 0x00fd_u16.to_be_bytes();
+        let ins = Instruction::decode_instruction(&instruction_bytes);
+        assert_eq!(ins, Exit);
+    }
+
+    #[test]
+    fn test_disable_high_resolution() {
+        let instruction_bytes = 0x00fe_u16.to_be_bytes();
+        let ins = Instruction::decode_instruction(&instruction_bytes);
+        assert_eq!(ins, DisableHighResolution);
+    }
+
+    #[test]
+    fn test_enable_high_resolution() {
+        let instruction_bytes = 0x00ff_u16.to_be_bytes();
+        let ins = Instruction::decode_instruction(&instruction_bytes);
+        assert_eq!(ins, EnableHighResolution);
+    }
+
     #[test]
     fn test_other_0x0nnn_instructions_passthrough() {
         for instruction_hex in [0xf0u16, 0x0, 0x1, 0x10] {
@@ -286,6 +499,48 @@ mod tests {
         assert_eq!(ins, ConditionalEqRegisterSkipNext(0xf, 0xa));
     }
 
+    #[test]
+    fn test_store_register_range() {
+        let instruction_bytes = 0x53a2_u16.to_be_bytes();
+        let ins = Instruction::decode_instruction(&instruction_bytes);
+        assert_eq!(ins, StoreRegisterRangeToMemory(0x3, 0xa));
+    }
+
+    #[test]
+    fn test_load_register_range() {
+        let instruction_bytes = 0x5a33_u16.to_be_bytes();
+        let ins = Instruction::decode_instruction(&instruction_bytes);
+        assert_eq!(ins, LoadRegisterRangeFromMemory(0xa, 0x3));
+    }
+
+    #[test]
+    fn test_invalid_register_instruction() {
+        let instruction_bytes = 0x5a31_u16.to_be_bytes();
+        let ins = Instruction::decode_instruction(&instruction_bytes);
+        assert_eq!(ins, InvalidInstruction);
+    }
+
+    #[test]
+    fn test_long_set_index() {
+        let ins = Instruction::decode_instruction(&[0xf0, 0x00, 0xab, 0xcd]);
+        assert_eq!(ins, LongSetIndex(0xabcd));
+        assert_eq!(4, ins.get_length());
+    }
+
+    #[test]
+    fn test_long_set_index_needs_4_bytes() {
+        let instruction_bytes = 0xf000_u16.to_be_bytes();
+        let ins = Instruction::decode_instruction(&instruction_bytes);
+        assert_eq!(ins, InvalidInstruction);
+    }
+
+    #[test]
+    fn test_decode_ignores_trailing_bytes() {
+        let ins = Instruction::decode_instruction(&[0x6a, 0x12, 0xf0, 0x00]);
+        assert_eq!(ins, SetRegister(0xa, 0x12));
+        assert_eq!(2, ins.get_length());
+    }
+
     #[test]
     fn test_set_register_instruction() {
         let instruction_bytes = 0x6a00_u16.to_be_bytes();
@@ -417,6 +672,30 @@ mod tests {
         assert_eq!(ins, SkipIfKeyNotPressed(0xb))
     }
 
+    #[test]
+    fn test_select_planes() {
+        let instruction_bytes = 0xf301_u16.to_be_bytes();
+        let ins = Instruction::decode_instruction(&instruction_bytes);
+        assert_eq!(ins, SelectPlanes(0x3))
+    }
+
+    #[test]
+    fn test_load_audio_pattern() {
+        let instruction_bytes = 0xf002_u16.to_be_bytes();
+        let ins = Instruction::decode_instruction(&instruction_bytes);
+        assert_eq!(ins, LoadAudioPattern);
+        let instruction_bytes = 0xf102_u16.to_be_bytes();
+        let ins = Instruction::decode_instruction(&instruction_bytes);
+        assert_eq!(ins, InvalidInstruction)
+    }
+
+    #[test]
+    fn test_set_pitch() {
+        let instruction_bytes = 0xf43a_u16.to_be_bytes();
+        let ins = Instruction::decode_instruction(&instruction_bytes);
+        assert_eq!(ins, SetPitch(0x4))
+    }
+
     #[test]
     fn test_fetch_delay_timer() {
         let instruction_bytes = 0xfa07_u16.to_be_bytes();
@@ -454,6 +733,12 @@ mod tests {
         assert_eq!(ins, SetIndexToFontCharacter(0xb))
     }
     #[test]
+    fn test_set_index_to_big_font_char() {
+        let instruction_bytes = 0xf730_u16.to_be_bytes();
+        let ins = Instruction::decode_instruction(&instruction_bytes);
+        assert_eq!(ins, SetIndexToBigFontCharacter(0x7))
+    }
+    #[test]
     fn test_do_bcd_conversion() {
         let instruction_bytes = 0xfd33_u16.to_be_bytes();
         let ins = Instruction::decode_instruction(&instruction_bytes);
@@ -471,4 +756,16 @@ mod tests {
         let ins = Instruction::decode_instruction(&instruction_bytes);
         assert_eq!(ins, LoadRegistersFromMemory(0b1001))
     }
+    #[test]
+    fn test_store_regs_to_flags() {
+        let instruction_bytes = 0xf775_u16.to_be_bytes();
+        let ins = Instruction::decode_instruction(&instruction_bytes);
+        assert_eq!(ins, StoreRegistersToFlags(0x7))
+    }
+    #[test]
+    fn test_load_regs_from_flags() {
+        let instruction_bytes = 0xf385_u16.to_be_bytes();
+        let ins = Instruction::decode_instruction(&instruction_bytes);
+        assert_eq!(ins, LoadRegistersFromFlags(0x3))
+    }
 }
@@ -1,7 +1,18 @@
 pub mod timer;
 pub mod keyboard;
 pub mod instruction;
+pub mod framebuffer;
+pub mod font;
+pub mod sound;
+pub mod frame_limiter;
+pub mod frontend;
+pub mod save_state;
+pub mod rewind;
+#[cfg(test)]
+pub mod test_util;
+#[allow(clippy::module_inception)]
 mod device;
 mod registers;
 
 pub use device::*;
+pub use registers::{RegisterFile, RplFlags};
@@ -1,22 +1,55 @@
+use std::fmt::Display;
+
 use super::Device;
 
+/// SUPER-CHIP RPL user flags, 8 on SUPER-CHIP and 16 on XO-CHIP
+pub type RplFlags = [u8; RegisterFile::RPL_FLAG_COUNT];
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct RegisterFile {
     pub v: [u8; 0x10],
     /// program counter - only u12 technically.
     pub pc: u16,
     /// stack pointer
     pub i: u16,
+    /// RPL user flags, persisted outside the device
+    pub rpl: RplFlags,
 }
 
 impl RegisterFile {
     pub const DEFAULT_PC_VALUE: u16 = Device::ROM_START as u16;
+    pub const RPL_FLAG_COUNT: usize = 0x10;
+    pub const SUPER_CHIP_RPL_FLAG_COUNT: usize = 0x8;
 }
 
 impl Default for RegisterFile{
     fn default() -> Self {
-        Self { v: [0;0x10], pc: Self::DEFAULT_PC_VALUE, i: 0 }
+        Self { v: [0;0x10], pc: Self::DEFAULT_PC_VALUE, i: 0, rpl: [0; Self::RPL_FLAG_COUNT] }
+    }
+}
+
+impl Display for RegisterFile {
+    /// All registers on one line, in hex
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PC={:04X} I={:04X}", self.pc, self.i)?;
+        for (index, value) in self.v.iter().enumerate() {
+            write!(f, " V{:X}={:02X}", index, value)?;
+        }
+        Ok(())
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::RegisterFile;
+
+    #[test]
+    fn test_display() {
+        let mut v = [0; 0x10];
+        v[0xf] = 0xab;
+        let registers = RegisterFile { v, i: 0x123, ..RegisterFile::default() };
+        let text = registers.to_string();
+        assert!(text.starts_with("PC=0200 I=0123 V0=00 V1=00"));
+        assert!(text.ends_with(" VF=AB"));
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::VecDeque;
+
+use crate::device::save_state::SaveState;
+use crate::device::Device;
+use crate::util::EmulatorResult;
+
+/// Ring buffer of snapshots taken every few frames, for stepping the device back in time.
+/// Once full, the oldest snapshot is dropped for each new one.
+#[derive(Clone, Debug)]
+pub struct RewindBuffer {
+    snapshots: VecDeque<SaveState>,
+    capacity: usize,
+    /// Frames between snapshots
+    snapshot_interval: u32,
+    frames_since_snapshot: u32,
+    /// Rewound frames left until the next snapshot is restored
+    frames_until_restore: u32,
+}
+
+impl RewindBuffer {
+    /// Snapshots taken by default, one every 4 frames
+    pub const DEFAULT_SNAPSHOT_INTERVAL: u32 = 4;
+
+    pub fn new(capacity: usize, snapshot_interval: u32) -> RewindBuffer {
+        RewindBuffer {
+            // grown as snapshots are taken, as long durations would otherwise allocate gigabytes up front
+            snapshots: VecDeque::new(),
+            capacity,
+            snapshot_interval: snapshot_interval.max(1),
+            frames_since_snapshot: 0,
+            frames_until_restore: 0,
+        }
+    }
+
+    /// Buffer holding `seconds` of 60 Hz frames
+    pub fn with_duration(seconds: u32, snapshot_interval: u32) -> RewindBuffer {
+        Self::new((seconds.saturating_mul(60) / snapshot_interval.max(1)) as usize, snapshot_interval)
+    }
+
+    /// Count a frame run by the device, taking a snapshot every `snapshot_interval` frames
+    pub fn record_frame(&mut self, device: &Device) {
+        self.frames_until_restore = 0;
+        self.frames_since_snapshot += 1;
+        if self.frames_since_snapshot < self.snapshot_interval || self.capacity == 0 {
+            return;
+        }
+        self.frames_since_snapshot = 0;
+        if self.snapshots.len() == self.capacity {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(device.save_state());
+    }
+
+    /// Step back one frame instead of running it, restoring the previous snapshot every `snapshot_interval` frames
+    /// so that rewinding goes back as fast as the device runs. The display is presented after a restore.
+    /// Returns false once there is nothing left to rewind.
+    pub fn rewind_frame(&mut self, device: &mut Device) -> EmulatorResult<bool> {
+        if self.frames_until_restore > 0 {
+            self.frames_until_restore -= 1;
+            return Ok(true);
+        }
+        let Some(snapshot) = self.snapshots.pop_back() else {
+            return Ok(false);
+        };
+        device.load_state(&snapshot);
+        device.present()?;
+        self.frames_since_snapshot = 0;
+        self.frames_until_restore = self.snapshot_interval - 1;
+        Ok(true)
+    }
+
+    /// Number of snapshots available to rewind through
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RewindBuffer;
+    use crate::device::test_util::device_with_program;
+    use crate::device::Device;
+
+    fn get_counting_device() -> Device {
+        // add 1 to v0 once per frame
+        device_with_program(&[0x70, 0x01, 0x12, 0x00], 2)
+    }
+
+    #[test]
+    fn test_keeps_latest_snapshots() {
+        let mut device = get_counting_device();
+        let mut rewind_buffer = RewindBuffer::new(3, 2);
+        for _ in 0..10 {
+            device.run_frame().unwrap();
+            rewind_buffer.record_frame(&device);
+        }
+        assert_eq!(3, rewind_buffer.len());
+        assert_eq!(10, device.registers.v[0]);
+
+        // restores immediately, then holds each snapshot for the interval
+        assert!(rewind_buffer.rewind_frame(&mut device).unwrap());
+        assert_eq!(10, device.registers.v[0]);
+        assert!(rewind_buffer.rewind_frame(&mut device).unwrap());
+        assert_eq!(10, device.registers.v[0]);
+        assert!(rewind_buffer.rewind_frame(&mut device).unwrap());
+        assert_eq!(8, device.registers.v[0]);
+        rewind_buffer.rewind_frame(&mut device).unwrap();
+        rewind_buffer.rewind_frame(&mut device).unwrap();
+        assert_eq!(6, device.registers.v[0]);
+        rewind_buffer.rewind_frame(&mut device).unwrap();
+        assert!(!rewind_buffer.rewind_frame(&mut device).unwrap());
+        assert!(rewind_buffer.is_empty());
+        assert_eq!(6, device.registers.v[0]);
+    }
+
+    #[test]
+    fn test_zero_capacity_records_nothing() {
+        let mut device = get_counting_device();
+        let mut rewind_buffer = RewindBuffer::with_duration(0, RewindBuffer::DEFAULT_SNAPSHOT_INTERVAL);
+        for _ in 0..10 {
+            device.run_frame().unwrap();
+            rewind_buffer.record_frame(&device);
+        }
+        assert!(!rewind_buffer.rewind_frame(&mut device).unwrap());
+    }
+
+    #[test]
+    fn test_long_duration_does_not_overflow() {
+        let mut device = get_counting_device();
+        let mut rewind_buffer = RewindBuffer::with_duration(u32::MAX, 1);
+        device.run_frame().unwrap();
+        rewind_buffer.record_frame(&device);
+        assert!(rewind_buffer.rewind_frame(&mut device).unwrap());
+    }
+}
@@ -0,0 +1,328 @@
+use std::io::{Cursor, Read};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+
+use crate::device::font::{Font, FontLayout};
+use crate::device::framebuffer::FrameBuffer;
+use crate::device::sound::SoundState;
+use crate::device::timer::DeviceTimerManager;
+use crate::device::{Device, RegisterFile};
+use crate::util::{DeviceConfig, EmulatorError, EmulatorResult, QuirkConfig};
+
+/// A snapshot of the complete machine, taken with `Device::save_state` and restored with `Device::load_state`.
+///
+/// The binary format starts with the `P8SS` magic and a format version, followed by big endian fields.
+/// Files written by an older version of the format are rejected rather than misread.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SaveState {
+    pub registers: RegisterFile,
+    pub stack: Vec<u16>,
+    pub memory: Vec<u8>,
+    pub frame_buffer: FrameBuffer,
+    pub font_layout: FontLayout,
+    pub timer: DeviceTimerManager,
+    /// Keys held down, one bit per key
+    pub keys_down: u16,
+    pub device_config: DeviceConfig,
+    pub exited: bool,
+    pub rng: ChaCha8Rng,
+}
+
+impl SaveState {
+    pub const MAGIC: [u8; 4] = *b"P8SS";
+    pub const VERSION: u16 = 1;
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.memory.len() + FrameBuffer::MAX_SIZE + 256);
+        // writing to a Vec cannot fail
+        self.write(&mut bytes).expect("Failed to write save state to memory");
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> EmulatorResult<SaveState> {
+        let mut reader = Cursor::new(bytes);
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != Self::MAGIC {
+            return Err(EmulatorError::InvalidSaveState("Not a save state".into()));
+        }
+        let version = reader.read_u16::<BigEndian>()?;
+        if version != Self::VERSION {
+            return Err(EmulatorError::InvalidSaveState(format!("Unsupported version {}, expected {}", version, Self::VERSION)));
+        }
+        let save_state = Self::read(&mut reader).map_err(|err| match err {
+            EmulatorError::IOError(_) => EmulatorError::InvalidSaveState("The save state is cut off".into()),
+            err => err,
+        })?;
+        if reader.position() != bytes.len() as u64 {
+            return Err(EmulatorError::InvalidSaveState("Unexpected data after the end of the save state".into()));
+        }
+        save_state.validate().map_err(EmulatorError::InvalidSaveState)?;
+        Ok(save_state)
+    }
+
+    /// Check that the state is one the device could have been in, so that loading it cannot crash the device.
+    /// I is not checked: it may legitimately point past the end of memory, and memory accesses wrap.
+    fn validate(&self) -> Result<(), String> {
+        let memory_size = self.memory.len();
+        if self.device_config.get_instructions_per_frame() == 0 {
+            return Err("Instructions per frame must be at least 1".into());
+        }
+        if self.registers.pc as usize >= memory_size {
+            return Err(format!("pc {:#06X} is outside of memory", self.registers.pc));
+        }
+        if self.stack.len() > Device::STACK_SIZE {
+            return Err(format!("Stack depth {} is deeper than {}", self.stack.len(), Device::STACK_SIZE));
+        }
+        if let Some(address) = self.stack.iter().find(|address| **address as usize >= memory_size) {
+            return Err(format!("Return address {:#06X} is outside of memory", address));
+        }
+        let fonts = [
+            (self.font_layout.small_font_start, self.font_layout.small_glyph_height, Font::MAX_SMALL_GLYPH_HEIGHT),
+            (self.font_layout.big_font_start, self.font_layout.big_glyph_height, Font::BIG_GLYPH_HEIGHT),
+        ];
+        for (font_start, glyph_height, max_glyph_height) in fonts {
+            if glyph_height as usize > max_glyph_height {
+                return Err(format!("Font glyphs are {} bytes high, at most {} are supported", glyph_height, max_glyph_height));
+            }
+            if font_start as usize + glyph_height as usize * Font::GLYPH_COUNT > memory_size {
+                return Err(format!("Font at {:#06X} does not fit in memory", font_start));
+            }
+        }
+        if self.frame_buffer.is_high_resolution() && !self.device_config.has_high_resolution() {
+            return Err("High resolution is enabled on a device without it".into());
+        }
+        if self.frame_buffer.raw_pixels().iter().any(|pixel| pixel & !FrameBuffer::ALL_PLANES != 0) {
+            return Err("Pixels are set on planes that do not exist".into());
+        }
+        Ok(())
+    }
+
+    fn write(&self, writer: &mut Vec<u8>) -> std::io::Result<()> {
+        writer.extend_from_slice(&Self::MAGIC);
+        writer.write_u16::<BigEndian>(Self::VERSION)?;
+
+        writer.write_u16::<BigEndian>(Self::quirks_to_bits(self.device_config.get_quirks()))?;
+        writer.write_u8(self.device_config.should_halt_on_invalid() as u8)?;
+        writer.write_u32::<BigEndian>(self.device_config.get_instructions_per_frame())?;
+        writer.write_u8(self.device_config.is_xo_chip() as u8)?;
+        writer.write_u8(self.device_config.has_high_resolution() as u8)?;
+
+        writer.extend_from_slice(&self.registers.v);
+        writer.write_u16::<BigEndian>(self.registers.pc)?;
+        writer.write_u16::<BigEndian>(self.registers.i)?;
+        writer.extend_from_slice(&self.registers.rpl);
+
+        writer.write_u16::<BigEndian>(self.stack.len() as u16)?;
+        for address in &self.stack {
+            writer.write_u16::<BigEndian>(*address)?;
+        }
+        writer.write_u32::<BigEndian>(self.memory.len() as u32)?;
+        writer.extend_from_slice(&self.memory);
+
+        writer.write_u16::<BigEndian>(self.font_layout.small_font_start)?;
+        writer.write_u16::<BigEndian>(self.font_layout.small_glyph_height)?;
+        writer.write_u16::<BigEndian>(self.font_layout.big_font_start)?;
+        writer.write_u16::<BigEndian>(self.font_layout.big_glyph_height)?;
+
+        writer.write_u8(self.frame_buffer.is_high_resolution() as u8)?;
+        writer.write_u8(self.frame_buffer.selected_planes())?;
+        writer.extend_from_slice(self.frame_buffer.raw_pixels());
+
+        let sound_state = self.timer.get_sound_state();
+        writer.write_u8(self.timer.poll_value())?;
+        writer.write_u8(sound_state.timer)?;
+        writer.write_u8(sound_state.pitch)?;
+        writer.write_u8(sound_state.pattern.is_some() as u8)?;
+        writer.extend_from_slice(&sound_state.pattern.unwrap_or_default());
+
+        writer.write_u16::<BigEndian>(self.keys_down)?;
+        writer.write_u8(self.exited as u8)?;
+
+        writer.extend_from_slice(&self.rng.get_seed());
+        writer.write_u64::<BigEndian>(self.rng.get_stream())?;
+        writer.write_u128::<BigEndian>(self.rng.get_word_pos())?;
+        Ok(())
+    }
+
+    fn read(reader: &mut Cursor<&[u8]>) -> EmulatorResult<SaveState> {
+        let quirks = Self::quirks_from_bits(reader.read_u16::<BigEndian>()?);
+        let halt_on_invalid = reader.read_u8()? != 0;
+        let instructions_per_frame = reader.read_u32::<BigEndian>()?;
+        let is_xo_chip = reader.read_u8()? != 0;
+        let has_high_resolution = reader.read_u8()? != 0;
+        let device_config = DeviceConfig::new(quirks, instructions_per_frame)
+            .with_halt_on_invalid(halt_on_invalid)
+            .with_xo_chip(is_xo_chip)
+            .with_high_resolution(has_high_resolution);
+
+        let mut registers = RegisterFile::default();
+        reader.read_exact(&mut registers.v)?;
+        registers.pc = reader.read_u16::<BigEndian>()?;
+        registers.i = reader.read_u16::<BigEndian>()?;
+        reader.read_exact(&mut registers.rpl)?;
+
+        let stack_length = reader.read_u16::<BigEndian>()?;
+        let stack = (0..stack_length).map(|_| reader.read_u16::<BigEndian>()).collect::<std::io::Result<Vec<_>>>()?;
+        let memory_length = reader.read_u32::<BigEndian>()? as usize;
+        if memory_length != device_config.get_memory_size() {
+            return Err(EmulatorError::InvalidSaveState(format!("Memory is {} bytes, expected {}", memory_length, device_config.get_memory_size())));
+        }
+        let mut memory = vec![0u8; memory_length];
+        reader.read_exact(&mut memory)?;
+
+        let font_layout = FontLayout {
+            small_font_start: reader.read_u16::<BigEndian>()?,
+            small_glyph_height: reader.read_u16::<BigEndian>()?,
+            big_font_start: reader.read_u16::<BigEndian>()?,
+            big_glyph_height: reader.read_u16::<BigEndian>()?,
+        };
+
+        let high_resolution = reader.read_u8()? != 0;
+        let selected_planes = reader.read_u8()?;
+        let mut pixels = vec![0u8; FrameBuffer::MAX_SIZE];
+        reader.read_exact(&mut pixels)?;
+        let frame_buffer = FrameBuffer::from_raw_parts(high_resolution, selected_planes, &pixels);
+
+        let timer_value = reader.read_u8()?;
+        let sound_timer = reader.read_u8()?;
+        let pitch = reader.read_u8()?;
+        let has_pattern = reader.read_u8()? != 0;
+        let mut pattern = [0u8; SoundState::PATTERN_SIZE];
+        reader.read_exact(&mut pattern)?;
+        let sound_state = SoundState { timer: sound_timer, pattern: has_pattern.then_some(pattern), pitch };
+        let timer = DeviceTimerManager::with_state(timer_value, sound_state);
+
+        let keys_down = reader.read_u16::<BigEndian>()?;
+        let exited = reader.read_u8()? != 0;
+
+        let mut seed = [0u8; 32];
+        reader.read_exact(&mut seed)?;
+        let mut rng = ChaCha8Rng::from_seed(seed);
+        rng.set_stream(reader.read_u64::<BigEndian>()?);
+        rng.set_word_pos(reader.read_u128::<BigEndian>()?);
+
+        Ok(SaveState { registers, stack, memory, frame_buffer, font_layout, timer, keys_down, device_config, exited, rng })
+    }
+
+    /// One bit per quirk, in declaration order
+    fn quirks_to_bits(quirks: &QuirkConfig) -> u16 {
+        [
+            quirks.vf_reset,
+            quirks.memory_increment,
+            quirks.shift_in_place,
+            quirks.jump_with_vx,
+            quirks.index_overflow_flag,
+            quirks.lores_half_scroll,
+            quirks.count_collision_rows,
+            quirks.wrap_sprites,
+            quirks.display_wait,
+        ]
+        .iter()
+        .enumerate()
+        .fold(0, |bits, (index, is_set)| bits | ((*is_set as u16) << index))
+    }
+
+    fn quirks_from_bits(bits: u16) -> QuirkConfig {
+        let is_set = |index: u16| (bits & (1 << index)) != 0;
+        QuirkConfig {
+            vf_reset: is_set(0),
+            memory_increment: is_set(1),
+            shift_in_place: is_set(2),
+            jump_with_vx: is_set(3),
+            index_overflow_flag: is_set(4),
+            lores_half_scroll: is_set(5),
+            count_collision_rows: is_set(6),
+            wrap_sprites: is_set(7),
+            display_wait: is_set(8),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    use super::SaveState;
+    use crate::device::font::FontLayout;
+    use crate::device::framebuffer::FrameBuffer;
+    use crate::device::sound::SoundState;
+    use crate::device::test_util::device_with_program;
+    use crate::device::timer::DeviceTimerManager;
+    use crate::device::{Device, RegisterFile};
+    use crate::util::{DeviceConfig, EmulatorError, QuirkConfig};
+
+    fn get_test_save_state() -> SaveState {
+        let mut frame_buffer = FrameBuffer::new();
+        frame_buffer.select_planes(3);
+        frame_buffer.toggle_pixel(3, 4, 2, true);
+        let device_config = DeviceConfig::new(QuirkConfig::original_chip8(), 11).with_halt_on_invalid(true);
+        SaveState {
+            registers: RegisterFile { v: [0x42; 0x10], pc: 0x234, i: 0x345, rpl: [7; 0x10] },
+            stack: vec![0x202, 0x204],
+            memory: vec![0xa5; device_config.get_memory_size()],
+            frame_buffer,
+            font_layout: FontLayout { small_font_start: 0x50, small_glyph_height: 5, big_font_start: 0xa0, big_glyph_height: 10 },
+            timer: DeviceTimerManager::with_state(9, SoundState { timer: 3, pattern: Some([0x0f; 16]), pitch: 80 }),
+            keys_down: 0x8001,
+            device_config,
+            exited: false,
+            rng: ChaCha8Rng::seed_from_u64(12),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let save_state = get_test_save_state();
+        let bytes = save_state.to_bytes();
+        assert_eq!(&SaveState::MAGIC, &bytes[..4]);
+        assert_eq!(save_state, SaveState::from_bytes(&bytes).unwrap());
+    }
+
+    #[test]
+    fn test_rejects_other_versions_and_truncated_files() {
+        let mut bytes = get_test_save_state().to_bytes();
+        assert!(matches!(SaveState::from_bytes(&bytes[..bytes.len() - 1]), Err(EmulatorError::InvalidSaveState(_))));
+        assert!(matches!(SaveState::from_bytes(&bytes[..100]), Err(EmulatorError::InvalidSaveState(_))));
+        bytes[5] += 1;
+        assert!(matches!(SaveState::from_bytes(&bytes), Err(EmulatorError::InvalidSaveState(_))));
+        assert!(matches!(SaveState::from_bytes(b"RIFF\0\x01"), Err(EmulatorError::InvalidSaveState(_))));
+    }
+
+    #[test]
+    fn test_rejects_values_outside_device_limits() {
+        let invalid_states = [
+            SaveState { registers: RegisterFile { pc: 0x1000, ..get_test_save_state().registers }, ..get_test_save_state() },
+            SaveState { stack: vec![0x200; 17], ..get_test_save_state() },
+            SaveState { stack: vec![0xffff], ..get_test_save_state() },
+            SaveState { font_layout: FontLayout { small_font_start: 0xff0, ..get_test_save_state().font_layout }, ..get_test_save_state() },
+            SaveState { font_layout: FontLayout { big_glyph_height: 200, ..get_test_save_state().font_layout }, ..get_test_save_state() },
+            SaveState { device_config: DeviceConfig::new(QuirkConfig::original_chip8(), 0).with_halt_on_invalid(true), ..get_test_save_state() },
+        ];
+        for save_state in invalid_states {
+            let bytes = save_state.to_bytes();
+            assert!(matches!(SaveState::from_bytes(&bytes), Err(EmulatorError::InvalidSaveState(_))));
+        }
+    }
+
+    #[test]
+    fn test_device_states_can_be_reloaded() {
+        // a subroutine calling itself forever, and BFFF jumping to 0xFFF + VF past the end of 4 KiB
+        let programs: [&[u8]; 2] = [&[0x22, 0x00], &[0x6f, 0xff, 0xbf, 0xff]];
+        for program in programs {
+            let mut device = device_with_program(program, 20);
+            device.run_frame().unwrap();
+            let bytes = device.save_state().to_bytes();
+            let save_state = SaveState::from_bytes(&bytes).unwrap();
+            let mut other_device = device_with_program(&[], 20);
+            other_device.load_state(&save_state);
+            assert_eq!(device.registers.pc, other_device.registers.pc);
+        }
+        let mut device = device_with_program(&[0x22, 0x00], 20);
+        device.run_frame().unwrap();
+        assert_eq!(Device::STACK_SIZE, device.stack.len());
+    }
+}
@@ -0,0 +1,65 @@
+/// Sound state shared between the device and the audio output.
+/// The sound timer is counted down by the device timer; audio plays while it is non-zero.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SoundState {
+    pub timer: u8,
+    /// XO-CHIP 1-bit audio samples, played from the most significant bit of the first byte.
+    /// The default tone is played if no pattern was loaded.
+    pub pattern: Option<[u8; SoundState::PATTERN_SIZE]>,
+    /// XO-CHIP pitch register
+    pub pitch: u8,
+}
+
+impl SoundState {
+    pub const PATTERN_SIZE: usize = 16;
+    pub const PATTERN_BITS: usize = Self::PATTERN_SIZE * 8;
+    pub const DEFAULT_PITCH: u8 = 64;
+
+    /// Rate at which pattern bits are played, in bits per second
+    pub fn get_playback_rate(&self) -> f32 {
+        4000f32 * 2f32.powf((self.pitch as f32 - 64f32) / 48f32)
+    }
+
+    /// Value of the pattern bit at `position`, wrapping around the pattern
+    pub fn get_pattern_bit(pattern: &[u8; Self::PATTERN_SIZE], position: usize) -> bool {
+        let position = position % Self::PATTERN_BITS;
+        (pattern[position / 8] & (0x80 >> (position % 8))) != 0
+    }
+}
+
+impl Default for SoundState {
+    fn default() -> Self {
+        SoundState {
+            timer: 0,
+            pattern: None,
+            pitch: Self::DEFAULT_PITCH,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SoundState;
+
+    #[test]
+    fn test_playback_rate() {
+        let mut sound_state = SoundState::default();
+        assert_eq!(4000f32, sound_state.get_playback_rate());
+        sound_state.pitch = 112;
+        assert_eq!(8000f32, sound_state.get_playback_rate());
+        sound_state.pitch = 16;
+        assert_eq!(2000f32, sound_state.get_playback_rate());
+    }
+
+    #[test]
+    fn test_pattern_bit() {
+        let mut pattern = [0u8; 16];
+        pattern[0] = 0b1000_0001;
+        pattern[15] = 0b0000_0001;
+        assert!(SoundState::get_pattern_bit(&pattern, 0));
+        assert!(!SoundState::get_pattern_bit(&pattern, 1));
+        assert!(SoundState::get_pattern_bit(&pattern, 7));
+        assert!(SoundState::get_pattern_bit(&pattern, 127));
+        assert!(SoundState::get_pattern_bit(&pattern, 128));
+    }
+}
@@ -0,0 +1,17 @@
+//! Devices running small programs for tests, without frontends
+use crate::device::frontend::NullFrontend;
+use crate::device::Device;
+use crate::util::{DeviceConfig, QuirkConfig};
+
+/// A device with `program` loaded at the start of the ROM, running `instructions_per_frame`
+pub fn device_with_program(program: &[u8], instructions_per_frame: u32) -> Device {
+    let device_config = DeviceConfig::new(QuirkConfig::new_chip8(), instructions_per_frame);
+    let mut device = Device::new(Box::new(NullFrontend), Box::new(NullFrontend), Box::new(NullFrontend), device_config);
+    device.load_rom(program);
+    device
+}
+
+/// A device that adds 1 to v0, adds 1 to v1 and loops, 10 instructions per frame
+pub fn counting_device() -> Device {
+    device_with_program(&[0x70, 0x01, 0x71, 0x01, 0x12, 0x00], 10)
+}
@@ -1,96 +1,77 @@
+use crate::device::sound::SoundState;
 
-use std::sync::{Arc, Mutex};
-use std::sync::mpsc::SendError;
-use std::thread::{JoinHandle, sleep};
-use std::time::Duration;
-
-use crate::util::EmulatorResult;
-
-/// Manages the timer and the sound timer
+/// Manages the timer and the sound timer.
+/// Both are counted down once per 60 Hz frame by the device, in lockstep with the instructions.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
 pub struct DeviceTimerManager {
-    timer_left: Arc<Mutex<u8>>,
-    sound_left: Arc<Mutex<u8>>,
-    join_handle: Option<(JoinHandle<()>, std::sync::mpsc::Sender<()>)>,
+    timer_left: u8,
+    sound_state: SoundState,
 }
 
 impl DeviceTimerManager {
-    pub const TIMER_THREAD_NAME: &'static str = "Timer";
-    pub fn new(sound_timer: Arc<Mutex<u8>>) -> DeviceTimerManager {
-        DeviceTimerManager {
-            timer_left: Arc::new(Mutex::default()),
-            sound_left: sound_timer,
-            join_handle: None,
-        }
+    pub fn new() -> DeviceTimerManager {
+        DeviceTimerManager::default()
     }
 
-    pub fn start(&mut self) {
-        let timer_left_ref = self.timer_left.clone();
-        let sound_timer_ref = self.sound_left.clone();
-        let (sender, receiver) = std::sync::mpsc::channel();
-        // A 1/60th second
+    /// Timers with the given values, such as when restoring a save state
+    pub fn with_state(timer_left: u8, sound_state: SoundState) -> DeviceTimerManager {
+        DeviceTimerManager { timer_left, sound_state }
+    }
 
-        let res = std::thread::Builder::new().name(Self::TIMER_THREAD_NAME.into()).spawn(move || {
-            loop {
-                let val = receiver.try_recv();
-                if let Ok(()) = val {
-                    break;
-                } else if let Err(std::sync::mpsc::TryRecvError::Disconnected) = val {
-                    panic!("Disconnected");
-                }
-                {
-                    let mut timer_lock = timer_left_ref.lock().expect("Failed to lock");
-                    let mut sound_lock = sound_timer_ref.lock().expect("Failed to lock");
-                    if *timer_lock > 0 {
-                        *timer_lock -= 1;
-                    }
-                    if *sound_lock > 0 {
-                        log::trace!("Beep!");
-                        *sound_lock -= 1;
-                    }
-                }
-                sleep(Duration::from_secs_f32(1f32 / 60f32));
-            }
-        }).expect("Failed to start timer thread");
-        self.join_handle = Some((res, sender));
+    /// Count down both timers by one frame
+    pub fn tick(&mut self) {
+        if self.timer_left > 0 {
+            self.timer_left -= 1;
+        }
+        if self.sound_state.timer > 0 {
+            log::trace!("Beep!");
+            self.sound_state.timer -= 1;
+        }
     }
 
     /// Set a timer down tick from `val`
-    pub fn try_set_timer(&self, val: u8) -> EmulatorResult<()> {
-        let mut timer_val = self.timer_left.lock()?;
-        *timer_val = val;
-        Ok(())
+    pub fn set_timer(&mut self, val: u8) {
+        self.timer_left = val;
     }
 
-    pub fn try_set_sound(&self, val: u8) -> EmulatorResult<()> {
-        let mut timer_val = self.sound_left.lock()?;
-        *timer_val = val;
-        Ok(())
+    pub fn set_sound(&mut self, val: u8) {
+        self.sound_state.timer = val;
     }
 
-    pub fn poll_value(&self) -> EmulatorResult<u8> {
-        let res = self.timer_left.lock()?;
-        Ok(res.clone())
+    /// Replace the XO-CHIP audio pattern
+    pub fn set_sound_pattern(&mut self, pattern: [u8; SoundState::PATTERN_SIZE]) {
+        self.sound_state.pattern = Some(pattern);
     }
 
-    pub fn _stop(self) {
-        if let Some((u, _dead_sender)) = self.join_handle {
-            u.join().expect("Failed to close thread");
-        } else {
-            log::warn!("Nothing present!");
-        }
+    /// Set the XO-CHIP pitch register
+    pub fn set_sound_pitch(&mut self, pitch: u8) {
+        self.sound_state.pitch = pitch;
     }
-    pub fn send_stop_signal(&mut self) {
-        if let Some((_, x)) = &self.join_handle {
-            match x.send(()) {
-                Ok(_) => {
-                    log::trace!("Sent stop Signal")
-                }
-                Err(SendError(_)) => {
-                    log::info!("Thread already stopped!");
-                }
-            };
-        } else {
-            log::warn!("Nothing present!");
-        }
+
+    pub fn poll_value(&self) -> u8 {
+        self.timer_left
+    }
+
+    pub fn get_sound_state(&self) -> &SoundState {
+        &self.sound_state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DeviceTimerManager;
+
+    #[test]
+    fn test_tick_counts_down_to_zero() {
+        let mut timer = DeviceTimerManager::new();
+        timer.set_timer(2);
+        timer.set_sound(1);
+        timer.tick();
+        assert_eq!(1, timer.poll_value());
+        assert_eq!(0, timer.get_sound_state().timer);
+        timer.tick();
+        timer.tick();
+        assert_eq!(0, timer.poll_value());
+        assert_eq!(0, timer.get_sound_state().timer);
     }
 }
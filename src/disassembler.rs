@@ -0,0 +1,333 @@
+use std::collections::BTreeMap;
+
+use crate::device::instruction::Instruction;
+use crate::device::Device;
+
+/// Assembly syntax of the disassembly
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Syntax {
+    /// The syntax of the Octo assembler, such as `v0 := 5`
+    Octo,
+    /// The classic syntax of Cowgod's CHIP-8 reference, such as `LD V0, #05`
+    Cowgod,
+}
+
+/// A decoded instruction or a run of bytes that is never executed
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DisassembledItem {
+    Instruction(Instruction),
+    Data(Vec<u8>),
+}
+
+/// The instructions and data of a ROM, separated by following the control flow from the start of the program.
+/// Every address reached by a jump, call or skip is code and everything else is data.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Disassembly {
+    /// Items by address
+    items: BTreeMap<u16, DisassembledItem>,
+    /// Targets of jumps and calls, and whether they are called
+    labels: BTreeMap<u16, bool>,
+    rom: Vec<u8>,
+}
+
+impl Disassembly {
+    /// Longest run of data bytes on one line
+    const DATA_LINE_LENGTH: usize = 8;
+
+    pub fn new(rom: &[u8]) -> Disassembly {
+        let mut instructions = BTreeMap::new();
+        let mut labels = BTreeMap::new();
+        let mut is_claimed = vec![false; rom.len()];
+        let mut pending_addresses = vec![Device::ROM_START as u16];
+        while let Some(address) = pending_addresses.pop() {
+            let Some(offset) = (address as usize).checked_sub(Device::ROM_START).filter(|offset| offset + 2 <= rom.len()) else {
+                continue;
+            };
+            if instructions.contains_key(&address) {
+                continue;
+            }
+            let instruction = Instruction::decode_instruction(&rom[offset..(offset + Instruction::MAX_LENGTH).min(rom.len())]);
+            let length = instruction.get_length() as usize;
+            // invalid instructions and instructions overlapping others are not code
+            if instruction == Instruction::InvalidInstruction || is_claimed[offset..offset + length].iter().any(|claimed| *claimed) {
+                continue;
+            }
+            is_claimed[offset..offset + length].fill(true);
+            instructions.insert(address, instruction);
+
+            let next_address = address.wrapping_add(length as u16);
+            match instruction {
+                Instruction::JumpTo(target) => {
+                    labels.entry(target).or_insert(false);
+                    pending_addresses.push(target);
+                }
+                Instruction::JumpAndLink(target) => {
+                    labels.insert(target, true);
+                    pending_addresses.extend([next_address, target]);
+                }
+                // the offset is only known when running, so only the base of the jump table is followed
+                Instruction::JumpWithOffset(_, target) => {
+                    labels.entry(target).or_insert(false);
+                    pending_addresses.push(target);
+                }
+                Instruction::ReturnFromProcedure | Instruction::Exit => {}
+                Instruction::ConditionalEqSkipNext(..)
+                | Instruction::ConditionalInEqSkipNext(..)
+                | Instruction::ConditionalEqRegisterSkipNext(..)
+                | Instruction::ConditionalInEqRegisterSkipNext(..)
+                | Instruction::SkipIfKeyPressed(_)
+                | Instruction::SkipIfKeyNotPressed(_) => {
+                    let skipped_length = (next_address as usize)
+                        .checked_sub(Device::ROM_START)
+                        .and_then(|next_offset| rom.get(next_offset..(next_offset + Instruction::MAX_LENGTH).min(rom.len())))
+                        .filter(|next| next.len() >= 2)
+                        .map_or(2, |next| Instruction::decode_instruction(next).get_length());
+                    pending_addresses.extend([next_address, next_address.wrapping_add(skipped_length)]);
+                }
+                _ => pending_addresses.push(next_address),
+            }
+        }
+
+        let mut items = BTreeMap::new();
+        let mut offset = 0;
+        while offset < rom.len() {
+            let address = (Device::ROM_START + offset) as u16;
+            if let Some(instruction) = instructions.get(&address) {
+                items.insert(address, DisassembledItem::Instruction(*instruction));
+                offset += instruction.get_length() as usize;
+                continue;
+            }
+            let data_length = rom[offset..].iter().take(Self::DATA_LINE_LENGTH).enumerate()
+                .take_while(|(index, _)| !is_claimed[offset + index])
+                .count();
+            items.insert(address, DisassembledItem::Data(rom[offset..offset + data_length].to_vec()));
+            offset += data_length;
+        }
+        labels.retain(|address, _| matches!(items.get(address), Some(DisassembledItem::Instruction(_))));
+        Disassembly { items, labels, rom: rom.to_vec() }
+    }
+
+    pub fn items(&self) -> &BTreeMap<u16, DisassembledItem> {
+        &self.items
+    }
+
+    /// One line per item with its address, bytes and mnemonic, preceded by a line for each label
+    pub fn format(&self, syntax: Syntax) -> String {
+        let mut lines = Vec::new();
+        for (address, item) in &self.items {
+            if self.labels.contains_key(address) {
+                match syntax {
+                    Syntax::Octo => lines.push(format!(": {}", self.get_label(*address))),
+                    Syntax::Cowgod => lines.push(format!("{}:", self.get_label(*address))),
+                }
+            }
+            let (length, mnemonic) = match item {
+                DisassembledItem::Instruction(instruction) => (instruction.get_length() as usize, self.format_instruction(*instruction, syntax)),
+                DisassembledItem::Data(data) => (data.len(), Self::format_data(data, syntax)),
+            };
+            let offset = *address as usize - Device::ROM_START;
+            let bytes: String = self.rom[offset..offset + length].iter().map(|byte| format!("{:02X}", byte)).collect();
+            lines.push(format!("{:04X}  {:<16}  {}", address, bytes, mnemonic));
+        }
+        lines.join("\n")
+    }
+
+    fn get_label(&self, address: u16) -> String {
+        match self.labels.get(&address) {
+            Some(true) => format!("sub_{:04X}", address),
+            _ => format!("label_{:04X}", address),
+        }
+    }
+
+    /// The label of a jump or call target, or the address if it is not code
+    fn format_target(&self, address: u16, syntax: Syntax) -> String {
+        if self.labels.contains_key(&address) {
+            return self.get_label(address);
+        }
+        match syntax {
+            Syntax::Octo => format!("0x{:03X}", address),
+            Syntax::Cowgod => format!("#{:03X}", address),
+        }
+    }
+
+    fn format_data(data: &[u8], syntax: Syntax) -> String {
+        let bytes: Vec<String> = match syntax {
+            Syntax::Octo => data.iter().map(|byte| format!("0x{:02X}", byte)).collect(),
+            Syntax::Cowgod => data.iter().map(|byte| format!("#{:02X}", byte)).collect(),
+        };
+        match syntax {
+            Syntax::Octo => bytes.join(" "),
+            Syntax::Cowgod => format!("DB {}", bytes.join(", ")),
+        }
+    }
+
+    fn format_instruction(&self, instruction: Instruction, syntax: Syntax) -> String {
+        match syntax {
+            Syntax::Octo => self.format_octo(instruction),
+            Syntax::Cowgod => self.format_cowgod(instruction),
+        }
+    }
+
+    fn format_octo(&self, instruction: Instruction) -> String {
+        match instruction {
+            Instruction::InvalidInstruction => "# invalid".to_string(),
+            Instruction::ScrollDown(n) => format!("scroll-down {}", n),
+            Instruction::ClearScreen => "clear".to_string(),
+            Instruction::ReturnFromProcedure => "return".to_string(),
+            Instruction::ScrollRight => "scroll-right".to_string(),
+            Instruction::ScrollLeft => "scroll-left".to_string(),
+            Instruction::Exit => "exit".to_string(),
+            Instruction::DisableHighResolution => "lores".to_string(),
+            Instruction::EnableHighResolution => "hires".to_string(),
+            Instruction::JumpTo(target) => format!("jump {}", self.format_target(target, Syntax::Octo)),
+            Instruction::JumpAndLink(target) => format!(":call {}", self.format_target(target, Syntax::Octo)),
+            Instruction::ConditionalEqSkipNext(x, value) => format!("if v{:x} != 0x{:02X} then", x, value),
+            Instruction::ConditionalInEqSkipNext(x, value) => format!("if v{:x} == 0x{:02X} then", x, value),
+            Instruction::ConditionalEqRegisterSkipNext(x, y) => format!("if v{:x} != v{:x} then", x, y),
+            Instruction::StoreRegisterRangeToMemory(x, y) => format!("save v{:x} - v{:x}", x, y),
+            Instruction::LoadRegisterRangeFromMemory(x, y) => format!("load v{:x} - v{:x}", x, y),
+            Instruction::SetRegister(x, value) => format!("v{:x} := 0x{:02X}", x, value),
+            Instruction::AddValueToRegister(x, value) => format!("v{:x} += 0x{:02X}", x, value),
+            Instruction::ConditionalInEqRegisterSkipNext(x, y) => format!("if v{:x} == v{:x} then", x, y),
+            Instruction::SetIndex(address) => format!("i := 0x{:03X}", address),
+            Instruction::JumpWithOffset(_, target) => format!("jump0 {}", self.format_target(target, Syntax::Octo)),
+            Instruction::RandomAnd(x, mask) => format!("v{:x} := random 0x{:02X}", x, mask),
+            Instruction::Draw(x, y, n) => format!("sprite v{:x} v{:x} {}", x, y, n),
+            Instruction::SkipIfKeyPressed(x) => format!("if v{:x} -key then", x),
+            Instruction::SkipIfKeyNotPressed(x) => format!("if v{:x} key then", x),
+            Instruction::LongSetIndex(address) => format!("i := long 0x{:04X}", address),
+            Instruction::SelectPlanes(planes) => format!("plane {}", planes),
+            Instruction::LoadAudioPattern => "audio".to_string(),
+            Instruction::FetchDelayTimer(x) => format!("v{:x} := delay", x),
+            Instruction::SetDelayTimer(x) => format!("delay := v{:x}", x),
+            Instruction::SetSoundTimer(x) => format!("buzzer := v{:x}", x),
+            Instruction::AddToIndex(x) => format!("i += v{:x}", x),
+            Instruction::GetKey(x) => format!("v{:x} := key", x),
+            Instruction::SetIndexToFontCharacter(x) => format!("i := hex v{:x}", x),
+            Instruction::SetPitch(x) => format!("pitch := v{:x}", x),
+            Instruction::SetIndexToBigFontCharacter(x) => format!("i := bighex v{:x}", x),
+            Instruction::DoBCDConversion(x) => format!("bcd v{:x}", x),
+            Instruction::StoreRegistersToMemory(x) => format!("save v{:x}", x),
+            Instruction::LoadRegistersFromMemory(x) => format!("load v{:x}", x),
+            Instruction::StoreRegistersToFlags(x) => format!("saveflags v{:x}", x),
+            Instruction::LoadRegistersFromFlags(x) => format!("loadflags v{:x}", x),
+            Instruction::Set(x, y) => format!("v{:x} := v{:x}", x, y),
+            Instruction::Or(x, y) => format!("v{:x} |= v{:x}", x, y),
+            Instruction::And(x, y) => format!("v{:x} &= v{:x}", x, y),
+            Instruction::Xor(x, y) => format!("v{:x} ^= v{:x}", x, y),
+            Instruction::Add(x, y) => format!("v{:x} += v{:x}", x, y),
+            Instruction::Sub(x, y) => format!("v{:x} -= v{:x}", x, y),
+            Instruction::RShift(x, y) => format!("v{:x} >>= v{:x}", x, y),
+            Instruction::RSub(x, y) => format!("v{:x} =- v{:x}", x, y),
+            Instruction::LShift(x, y) => format!("v{:x} <<= v{:x}", x, y),
+        }
+    }
+
+    fn format_cowgod(&self, instruction: Instruction) -> String {
+        match instruction {
+            Instruction::InvalidInstruction => "; invalid".to_string(),
+            Instruction::ScrollDown(n) => format!("SCD {}", n),
+            Instruction::ClearScreen => "CLS".to_string(),
+            Instruction::ReturnFromProcedure => "RET".to_string(),
+            Instruction::ScrollRight => "SCR".to_string(),
+            Instruction::ScrollLeft => "SCL".to_string(),
+            Instruction::Exit => "EXIT".to_string(),
+            Instruction::DisableHighResolution => "LOW".to_string(),
+            Instruction::EnableHighResolution => "HIGH".to_string(),
+            Instruction::JumpTo(target) => format!("JP {}", self.format_target(target, Syntax::Cowgod)),
+            Instruction::JumpAndLink(target) => format!("CALL {}", self.format_target(target, Syntax::Cowgod)),
+            Instruction::ConditionalEqSkipNext(x, value) => format!("SE V{:X}, #{:02X}", x, value),
+            Instruction::ConditionalInEqSkipNext(x, value) => format!("SNE V{:X}, #{:02X}", x, value),
+            Instruction::ConditionalEqRegisterSkipNext(x, y) => format!("SE V{:X}, V{:X}", x, y),
+            Instruction::StoreRegisterRangeToMemory(x, y) => format!("SAVE V{:X}, V{:X}", x, y),
+            Instruction::LoadRegisterRangeFromMemory(x, y) => format!("LOAD V{:X}, V{:X}", x, y),
+            Instruction::SetRegister(x, value) => format!("LD V{:X}, #{:02X}", x, value),
+            Instruction::AddValueToRegister(x, value) => format!("ADD V{:X}, #{:02X}", x, value),
+            Instruction::ConditionalInEqRegisterSkipNext(x, y) => format!("SNE V{:X}, V{:X}", x, y),
+            Instruction::SetIndex(address) => format!("LD I, #{:03X}", address),
+            Instruction::JumpWithOffset(_, target) => format!("JP V0, {}", self.format_target(target, Syntax::Cowgod)),
+            Instruction::RandomAnd(x, mask) => format!("RND V{:X}, #{:02X}", x, mask),
+            Instruction::Draw(x, y, n) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::SkipIfKeyPressed(x) => format!("SKP V{:X}", x),
+            Instruction::SkipIfKeyNotPressed(x) => format!("SKNP V{:X}", x),
+            Instruction::LongSetIndex(address) => format!("LD I, LONG #{:04X}", address),
+            Instruction::SelectPlanes(planes) => format!("PLANE {}", planes),
+            Instruction::LoadAudioPattern => "AUDIO".to_string(),
+            Instruction::FetchDelayTimer(x) => format!("LD V{:X}, DT", x),
+            Instruction::SetDelayTimer(x) => format!("LD DT, V{:X}", x),
+            Instruction::SetSoundTimer(x) => format!("LD ST, V{:X}", x),
+            Instruction::AddToIndex(x) => format!("ADD I, V{:X}", x),
+            Instruction::GetKey(x) => format!("LD V{:X}, K", x),
+            Instruction::SetIndexToFontCharacter(x) => format!("LD F, V{:X}", x),
+            Instruction::SetPitch(x) => format!("PITCH V{:X}", x),
+            Instruction::SetIndexToBigFontCharacter(x) => format!("LD HF, V{:X}", x),
+            Instruction::DoBCDConversion(x) => format!("LD B, V{:X}", x),
+            Instruction::StoreRegistersToMemory(x) => format!("LD [I], V{:X}", x),
+            Instruction::LoadRegistersFromMemory(x) => format!("LD V{:X}, [I]", x),
+            Instruction::StoreRegistersToFlags(x) => format!("LD R, V{:X}", x),
+            Instruction::LoadRegistersFromFlags(x) => format!("LD V{:X}, R", x),
+            Instruction::Set(x, y) => format!("LD V{:X}, V{:X}", x, y),
+            Instruction::Or(x, y) => format!("OR V{:X}, V{:X}", x, y),
+            Instruction::And(x, y) => format!("AND V{:X}, V{:X}", x, y),
+            Instruction::Xor(x, y) => format!("XOR V{:X}, V{:X}", x, y),
+            Instruction::Add(x, y) => format!("ADD V{:X}, V{:X}", x, y),
+            Instruction::Sub(x, y) => format!("SUB V{:X}, V{:X}", x, y),
+            Instruction::RShift(x, y) => format!("SHR V{:X}, V{:X}", x, y),
+            Instruction::RSub(x, y) => format!("SUBN V{:X}, V{:X}", x, y),
+            Instruction::LShift(x, y) => format!("SHL V{:X}, V{:X}", x, y),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DisassembledItem, Disassembly, Syntax};
+    use crate::device::instruction::Instruction;
+
+    /// Calls a subroutine, loops, and has a sprite after the code
+    const ROM: [u8; 14] = [
+        0x22, 0x06, // 200: call 206
+        0x12, 0x00, // 202: jump 200
+        0x3c, 0x42, // 204: sprite data
+        0xa2, 0x04, // 206: i := 204
+        0x30, 0x01, // 208: skip if v0 == 1
+        0x00, 0xfd, // 20A: exit
+        0x00, 0xee, // 20C: return
+    ];
+
+    #[test]
+    fn test_separates_code_from_data() {
+        let disassembly = Disassembly::new(&ROM);
+        assert_eq!(Some(&DisassembledItem::Data(vec![0x3c, 0x42])), disassembly.items().get(&0x204));
+        assert_eq!(Some(&DisassembledItem::Instruction(Instruction::ReturnFromProcedure)), disassembly.items().get(&0x20c));
+        assert_eq!(7, disassembly.items().len());
+    }
+
+    #[test]
+    fn test_format_octo() {
+        let text = Disassembly::new(&ROM).format(Syntax::Octo);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(": label_0200", lines[0]);
+        assert_eq!("0200  2206              :call sub_0206", lines[1]);
+        assert_eq!("0202  1200              jump label_0200", lines[2]);
+        assert_eq!("0204  3C42              0x3C 0x42", lines[3]);
+        assert_eq!(": sub_0206", lines[4]);
+        assert_eq!("0208  3001              if v0 != 0x01 then", lines[6]);
+    }
+
+    #[test]
+    fn test_format_cowgod() {
+        let text = Disassembly::new(&ROM).format(Syntax::Cowgod);
+        assert!(text.contains("0200  2206              CALL sub_0206"));
+        assert!(text.contains("0204  3C42              DB #3C, #42"));
+        assert!(text.contains("sub_0206:\n0206  A204              LD I, #204"));
+        assert!(text.contains("020C  00EE              RET"));
+    }
+
+    #[test]
+    fn test_long_instruction_and_unreached_bytes() {
+        let rom = [0xf0, 0x00, 0x12, 0x34, 0x00, 0xfd, 0xff];
+        let text = Disassembly::new(&rom).format(Syntax::Octo);
+        assert_eq!("0200  F0001234          i := long 0x1234\n0204  00FD              exit\n0206  FF                0xFF", text);
+    }
+}
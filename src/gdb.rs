@@ -0,0 +1,478 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use crate::client_listener::{ClientConnection, ClientListener};
+use crate::debugger::{DebugCommand, Debugger};
+use crate::device::Device;
+use crate::util::EmulatorResult;
+
+/// Serves the GDB remote serial protocol over TCP, for attaching gdb-style frontends to the device.
+///
+/// Registers are V0-VF, I, PC and SP (the stack depth), described by a custom target description;
+/// multi-byte registers are little endian. Supports memory reads and writes, software breakpoints,
+/// single-stepping, continuing and interrupting.
+pub struct GdbServer {
+    client_listener: ClientListener,
+    debugger: Debugger,
+    /// Whether the client is waiting for the device to stop after continuing
+    is_waiting_for_stop: bool,
+}
+
+impl GdbServer {
+    const REGISTER_COUNT: usize = 19;
+    const INTERRUPT: u8 = 0x03;
+    const SIGINT: u8 = 2;
+    const SIGTRAP: u8 = 5;
+    const TARGET_DESCRIPTION: &'static str = concat!(
+        r#"<?xml version="1.0"?><!DOCTYPE target SYSTEM "gdb-target.dtd"><target version="1.0"><feature name="org.porcel8.chip8">"#,
+        r#"<reg name="v0" bitsize="8" type="uint8" regnum="0"/><reg name="v1" bitsize="8" type="uint8"/>"#,
+        r#"<reg name="v2" bitsize="8" type="uint8"/><reg name="v3" bitsize="8" type="uint8"/>"#,
+        r#"<reg name="v4" bitsize="8" type="uint8"/><reg name="v5" bitsize="8" type="uint8"/>"#,
+        r#"<reg name="v6" bitsize="8" type="uint8"/><reg name="v7" bitsize="8" type="uint8"/>"#,
+        r#"<reg name="v8" bitsize="8" type="uint8"/><reg name="v9" bitsize="8" type="uint8"/>"#,
+        r#"<reg name="va" bitsize="8" type="uint8"/><reg name="vb" bitsize="8" type="uint8"/>"#,
+        r#"<reg name="vc" bitsize="8" type="uint8"/><reg name="vd" bitsize="8" type="uint8"/>"#,
+        r#"<reg name="ve" bitsize="8" type="uint8"/><reg name="vf" bitsize="8" type="uint8"/>"#,
+        r#"<reg name="i" bitsize="16" type="data_ptr"/><reg name="pc" bitsize="16" type="code_ptr"/>"#,
+        r#"<reg name="sp" bitsize="8" type="uint8"/>"#,
+        r#"</feature></target>"#
+    );
+
+    /// Listen on `address`. The device stays paused until a client continues it.
+    pub fn bind(address: impl ToSocketAddrs) -> EmulatorResult<GdbServer> {
+        Ok(GdbServer {
+            client_listener: ClientListener::bind(address, "GDB")?,
+            debugger: Debugger::new(true),
+            is_waiting_for_stop: false,
+        })
+    }
+
+    pub fn local_addr(&self) -> EmulatorResult<SocketAddr> {
+        self.client_listener.local_addr()
+    }
+
+    /// Handle the packets received from the client, then run a frame unless paused and report if the device stopped.
+    /// Returns whether a frame ran.
+    pub fn run_frame(&mut self, device: &mut Device) -> EmulatorResult<bool> {
+        self.client_listener.accept()?;
+        self.handle_packets(device)?;
+        if self.debugger.is_paused() {
+            return Ok(false);
+        }
+        let breakpoint = self.debugger.run_frame(device)?;
+        if device.has_exited() {
+            self.report_stop("W00", device)?;
+        } else if breakpoint.is_some() {
+            self.report_stop(&format!("T{:02x}swbreak:;", Self::SIGTRAP), device)?;
+        }
+        Ok(true)
+    }
+
+    /// Read until the client goes quiet, answering every complete packet
+    fn handle_packets(&mut self, device: &mut Device) -> EmulatorResult<()> {
+        let is_closed = self.client_listener.receive();
+        let Some(connection) = self.client_listener.connection_mut() else {
+            return Ok(());
+        };
+        while let Some(packet) = Self::take_packet(connection) {
+            if let Some(response) = Self::handle_packet(&packet, &mut self.debugger, &mut self.is_waiting_for_stop, device)? {
+                Self::send_packet(connection, &response);
+            }
+        }
+        if is_closed || connection.is_lost() {
+            self.client_listener.disconnect();
+            Self::detach(&mut self.debugger, &mut self.is_waiting_for_stop, device)?;
+        }
+        Ok(())
+    }
+
+    /// Let the device run freely once the client is gone, without the breakpoints it set
+    fn detach(debugger: &mut Debugger, is_waiting_for_stop: &mut bool, device: &mut Device) -> EmulatorResult<()> {
+        debugger.clear_breakpoints();
+        *is_waiting_for_stop = false;
+        debugger.execute(DebugCommand::Continue, device)?;
+        Ok(())
+    }
+
+    /// Remove the next packet from the received data, acknowledging it. Interrupts are returned as a packet of their own.
+    fn take_packet(connection: &mut ClientConnection) -> Option<String> {
+        loop {
+            match connection.received.first() {
+                None => return None,
+                Some(&Self::INTERRUPT) => {
+                    connection.received.remove(0);
+                    return Some(char::from(Self::INTERRUPT).to_string());
+                }
+                Some(b'$') => break,
+                // acknowledgements and noise between packets
+                Some(_) => {
+                    connection.received.remove(0);
+                }
+            }
+        }
+        let end = connection.received.iter().position(|byte| *byte == b'#')?;
+        if connection.received.len() < end + 3 {
+            return None;
+        }
+        let packet: Vec<u8> = connection.received.drain(..end + 3).collect();
+        let data = &packet[1..end];
+        let checksum = std::str::from_utf8(&packet[end + 1..]).ok().and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+        if checksum != Some(Self::checksum(data)) {
+            // ask for the packet again and carry on with any data after it
+            connection.send(b"-");
+            return Self::take_packet(connection);
+        }
+        connection.send(b"+");
+        Some(String::from_utf8_lossy(data).into_owned())
+    }
+
+    /// The response to a packet, or None if the response is sent once the device stops
+    fn handle_packet(packet: &str, debugger: &mut Debugger, is_waiting_for_stop: &mut bool, device: &mut Device) -> EmulatorResult<Option<String>> {
+        let stop_reply = |signal: u8| format!("S{:02x}", signal);
+        let (command, arguments) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let response = match command {
+            "\u{3}" => {
+                debugger.execute(DebugCommand::Pause, device)?;
+                *is_waiting_for_stop = false;
+                stop_reply(Self::SIGINT)
+            }
+            "?" => stop_reply(Self::SIGTRAP),
+            "g" => Self::read_registers(device).iter().map(|byte| format!("{:02x}", byte)).collect(),
+            "G" => match Self::decode_hex(arguments) {
+                Some(bytes) if bytes.len() == Self::REGISTER_COUNT + 2 && Self::write_registers(device, &bytes) => "OK".to_string(),
+                _ => "E01".to_string(),
+            },
+            "p" => match Self::read_register(device, arguments) {
+                Some(bytes) => bytes.iter().map(|byte| format!("{:02x}", byte)).collect(),
+                None => "E01".to_string(),
+            },
+            "P" => match arguments.split_once('=').and_then(|(register, value)| Some((register, Self::decode_hex(value)?))) {
+                Some((register, value)) if Self::write_register(device, register, &value) => "OK".to_string(),
+                _ => "E01".to_string(),
+            },
+            "m" => match Self::parse_memory_range(device, arguments) {
+                Some(range) => device.memory[range].iter().map(|byte| format!("{:02x}", byte)).collect(),
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let write = arguments.split_once(':').and_then(|(range, data)| Some((Self::parse_memory_range(device, range)?, Self::decode_hex(data)?)));
+                match write {
+                    Some((range, data)) if range.len() == data.len() => {
+                        device.memory[range].copy_from_slice(&data);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "Z" | "z" => match arguments.strip_prefix("0,").and_then(|breakpoint| breakpoint.split(',').next()) {
+                Some(address) => match u16::from_str_radix(address, 16) {
+                    Ok(address) if command == "Z" => Self::ok(debugger.execute(DebugCommand::SetBreakpoint(address), device)?),
+                    Ok(address) => Self::ok(debugger.execute(DebugCommand::ClearBreakpoint(address), device)?),
+                    Err(_) => "E01".to_string(),
+                },
+                // only software breakpoints are supported
+                None => String::new(),
+            },
+            "s" => {
+                debugger.execute(DebugCommand::Pause, device)?;
+                debugger.execute(DebugCommand::Step(1), device)?;
+                if device.has_exited() { "W00".to_string() } else { stop_reply(Self::SIGTRAP) }
+            }
+            "c" => {
+                debugger.execute(DebugCommand::Continue, device)?;
+                *is_waiting_for_stop = true;
+                return Ok(None);
+            }
+            "H" | "T" => "OK".to_string(),
+            "D" => {
+                Self::detach(debugger, is_waiting_for_stop, device)?;
+                "OK".to_string()
+            }
+            // the emulator keeps running, as if detached; kill has no reply
+            "k" => {
+                Self::detach(debugger, is_waiting_for_stop, device)?;
+                return Ok(None);
+            }
+            "q" if arguments.starts_with("Supported") => "PacketSize=4000;qXfer:features:read+;swbreak+".to_string(),
+            "q" if arguments == "Attached" => "1".to_string(),
+            "q" if arguments == "C" => "QC1".to_string(),
+            "q" if arguments.starts_with("Xfer:features:read:target.xml:") => {
+                Self::read_target_description(&arguments["Xfer:features:read:target.xml:".len()..])
+            }
+            _ => String::new(),
+        };
+        Ok(Some(response))
+    }
+
+    fn report_stop(&mut self, stop_reply: &str, device: &mut Device) -> EmulatorResult<()> {
+        if !self.is_waiting_for_stop {
+            return Ok(());
+        }
+        self.is_waiting_for_stop = false;
+        let Some(connection) = self.client_listener.connection_mut() else {
+            return Ok(());
+        };
+        Self::send_packet(connection, stop_reply);
+        if connection.is_lost() {
+            self.client_listener.disconnect();
+            Self::detach(&mut self.debugger, &mut self.is_waiting_for_stop, device)?;
+        }
+        Ok(())
+    }
+
+    /// Debugger output is not sent to the client
+    fn ok(_debugger_output: String) -> String {
+        "OK".to_string()
+    }
+
+    /// V0-VF, I and PC little endian, then SP
+    fn read_registers(device: &Device) -> Vec<u8> {
+        let mut registers = device.registers.v.to_vec();
+        registers.extend_from_slice(&device.registers.i.to_le_bytes());
+        registers.extend_from_slice(&device.registers.pc.to_le_bytes());
+        registers.push(device.stack.len() as u8);
+        registers
+    }
+
+    /// Write all registers, returning false without writing any if pc or SP is invalid
+    fn write_registers(device: &mut Device, bytes: &[u8]) -> bool {
+        let pc = u16::from_le_bytes([bytes[0x12], bytes[0x13]]);
+        if !Self::is_valid_pc(device, pc) || !Self::is_valid_stack_depth(device, bytes[0x14]) {
+            return false;
+        }
+        device.registers.v.copy_from_slice(&bytes[..0x10]);
+        device.registers.i = u16::from_le_bytes([bytes[0x10], bytes[0x11]]);
+        device.registers.pc = pc;
+        device.stack.truncate(bytes[0x14] as usize);
+        true
+    }
+
+    fn read_register(device: &Device, register: &str) -> Option<Vec<u8>> {
+        let registers = Self::read_registers(device);
+        match usize::from_str_radix(register, 16).ok()? {
+            register @ 0..=0xf => Some(vec![registers[register]]),
+            0x10 => Some(registers[0x10..0x12].to_vec()),
+            0x11 => Some(registers[0x12..0x14].to_vec()),
+            0x12 => Some(vec![registers[0x14]]),
+            _ => None,
+        }
+    }
+
+    /// Write a register, returning false if it does not exist, the value has the wrong size or is invalid
+    fn write_register(device: &mut Device, register: &str, value: &[u8]) -> bool {
+        match (usize::from_str_radix(register, 16), value) {
+            (Ok(register @ 0..=0xf), [value]) => device.registers.v[register] = *value,
+            (Ok(0x10), [low, high]) => device.registers.i = u16::from_le_bytes([*low, *high]),
+            (Ok(0x11), [low, high]) if Self::is_valid_pc(device, u16::from_le_bytes([*low, *high])) => {
+                device.registers.pc = u16::from_le_bytes([*low, *high]);
+            }
+            (Ok(0x12), [depth]) if Self::is_valid_stack_depth(device, *depth) => device.stack.truncate(*depth as usize),
+            _ => return false,
+        }
+        true
+    }
+
+    /// Whether a whole instruction can be read at `pc`
+    fn is_valid_pc(device: &Device, pc: u16) -> bool {
+        (pc as usize) < device.memory.len() - 1
+    }
+
+    /// SP can only be lowered, dropping return addresses, since there is nothing to return to above it
+    fn is_valid_stack_depth(device: &Device, depth: u8) -> bool {
+        depth as usize <= device.stack.len()
+    }
+
+    /// `ADDR,LENGTH` in hex, if it is inside memory
+    fn parse_memory_range(device: &Device, range: &str) -> Option<std::ops::Range<usize>> {
+        let (address, length) = range.split_once(',')?;
+        let address = usize::from_str_radix(address, 16).ok()?;
+        let end = address.checked_add(usize::from_str_radix(length, 16).ok()?)?;
+        (end <= device.memory.len()).then_some(address..end)
+    }
+
+    /// `OFFSET,LENGTH` of the target description, prefixed with `l` for the last part or `m` otherwise
+    fn read_target_description(range: &str) -> String {
+        let description = Self::TARGET_DESCRIPTION.as_bytes();
+        let parsed_range = range.split_once(',').and_then(|(offset, length)| {
+            Some((usize::from_str_radix(offset, 16).ok()?, usize::from_str_radix(length, 16).ok()?))
+        });
+        let Some((offset, length)) = parsed_range else {
+            return "E01".to_string();
+        };
+        let start = offset.min(description.len());
+        let end = offset.saturating_add(length).min(description.len());
+        let prefix = if end == description.len() { 'l' } else { 'm' };
+        format!("{}{}", prefix, String::from_utf8_lossy(&description[start..end]))
+    }
+
+    fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+        if !hex.len().is_multiple_of(2) {
+            return None;
+        }
+        (0..hex.len()).step_by(2).map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok()).collect()
+    }
+
+    fn checksum(data: &[u8]) -> u8 {
+        data.iter().fold(0u8, |checksum, byte| checksum.wrapping_add(*byte))
+    }
+
+    /// Send `$DATA#CHECKSUM`, escaping the characters with a meaning in the protocol
+    fn send_packet(connection: &mut ClientConnection, data: &str) {
+        let mut escaped = Vec::with_capacity(data.len());
+        for byte in data.bytes() {
+            if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+                escaped.extend_from_slice(&[b'}', byte ^ 0x20]);
+            } else {
+                escaped.push(byte);
+            }
+        }
+        let mut packet = vec![b'$'];
+        packet.extend_from_slice(&escaped);
+        packet.extend_from_slice(format!("#{:02x}", Self::checksum(&escaped)).as_bytes());
+        connection.send(&packet);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    use super::GdbServer;
+    use crate::device::Device;
+    use crate::device::test_util::counting_device;
+
+    /// Send a packet, then run frames until a full response arrives
+    fn request(server: &mut GdbServer, device: &mut Device, client: &mut TcpStream, data: &str) -> String {
+        let checksum = data.bytes().fold(0u8, |checksum, byte| checksum.wrapping_add(byte));
+        client.write_all(format!("${}#{:02x}", data, checksum).as_bytes()).unwrap();
+        read_response(server, device, client)
+    }
+
+    fn read_response(server: &mut GdbServer, device: &mut Device, client: &mut TcpStream) -> String {
+        let mut received = Vec::new();
+        for _ in 0..100 {
+            server.run_frame(device).unwrap();
+            let mut buffer = [0u8; 1024];
+            if let Ok(length) = client.read(&mut buffer) {
+                received.extend_from_slice(&buffer[..length]);
+            }
+            let text = String::from_utf8_lossy(&received).trim_start_matches('+').to_string();
+            if let Some(end) = text.find('#').filter(|end| text.len() >= end + 3) {
+                return text[1..end].to_string();
+            }
+        }
+        panic!("No response, received {:?}", String::from_utf8_lossy(&received));
+    }
+
+    fn connect(server: &GdbServer) -> TcpStream {
+        let client = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        client
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut device = counting_device();
+        let mut server = GdbServer::bind("127.0.0.1:0").unwrap();
+        let mut client = connect(&server);
+        assert_eq!("S05", request(&mut server, &mut device, &mut client, "?"));
+        assert!(request(&mut server, &mut device, &mut client, "qSupported:swbreak+").contains("qXfer:features:read+"));
+        assert!(request(&mut server, &mut device, &mut client, "qXfer:features:read:target.xml:0,1000").starts_with("l<?xml"));
+
+        assert_eq!("7001710112", request(&mut server, &mut device, &mut client, "m200,5"));
+        assert_eq!("OK", request(&mut server, &mut device, &mut client, "M300,2:abcd"));
+        assert_eq!([0xab, 0xcd], device.memory[0x300..0x302]);
+        assert_eq!("E01", request(&mut server, &mut device, &mut client, "mfff,2"));
+
+        assert_eq!("OK", request(&mut server, &mut device, &mut client, "P3=2a"));
+        assert_eq!("OK", request(&mut server, &mut device, &mut client, "P10=3412"));
+        assert_eq!(0x1234, device.registers.i);
+        let registers = request(&mut server, &mut device, &mut client, "g");
+        assert_eq!("0000002a", &registers[..8]);
+        assert_eq!("3412000200", &registers[32..]);
+        assert_eq!("0002", request(&mut server, &mut device, &mut client, "p11"));
+    }
+
+    #[test]
+    fn test_breakpoint_step_and_continue() {
+        let mut device = counting_device();
+        let mut server = GdbServer::bind("127.0.0.1:0").unwrap();
+        let mut client = connect(&server);
+        assert_eq!("S05", request(&mut server, &mut device, &mut client, "s"));
+        assert_eq!((0x202, 1), (device.registers.pc, device.registers.v[0]));
+
+        assert_eq!("OK", request(&mut server, &mut device, &mut client, "Z0,204,2"));
+        assert_eq!("T05swbreak:;", request(&mut server, &mut device, &mut client, "c"));
+        assert_eq!((0x204, 1), (device.registers.pc, device.registers.v[1]));
+        assert_eq!("T05swbreak:;", request(&mut server, &mut device, &mut client, "c"));
+        assert_eq!((0x204, 2), (device.registers.pc, device.registers.v[1]));
+
+        assert_eq!("OK", request(&mut server, &mut device, &mut client, "z0,204,2"));
+        client.write_all(b"$c#63").unwrap();
+        server.run_frame(&mut device).unwrap();
+        client.write_all(&[0x03]).unwrap();
+        assert_eq!("S02", read_response(&mut server, &mut device, &mut client));
+        assert!(device.registers.v[1] > 2);
+    }
+
+    #[test]
+    fn test_rejects_invalid_pc_and_stack_depth() {
+        let mut device = counting_device();
+        device.stack.push(0x300);
+        let mut server = GdbServer::bind("127.0.0.1:0").unwrap();
+        let mut client = connect(&server);
+        assert_eq!("E01", request(&mut server, &mut device, &mut client, "P11=ff0f"));
+        assert_eq!("E01", request(&mut server, &mut device, &mut client, "P12=02"));
+        assert_eq!((0x200, 1), (device.registers.pc, device.stack.len()));
+        let registers = format!("{}0000ff0f00", "00".repeat(16));
+        assert_eq!("E01", request(&mut server, &mut device, &mut client, &format!("G{}", registers)));
+        assert_eq!(0x200, device.registers.pc);
+
+        assert_eq!("OK", request(&mut server, &mut device, &mut client, "P11=fe0f"));
+        assert_eq!("OK", request(&mut server, &mut device, &mut client, "P12=00"));
+        assert_eq!((0xffe, 0), (device.registers.pc, device.stack.len()));
+    }
+
+    #[test]
+    fn test_detach_and_disconnect_clear_breakpoints() {
+        let mut device = counting_device();
+        let mut server = GdbServer::bind("127.0.0.1:0").unwrap();
+        let mut client = connect(&server);
+        assert_eq!("OK", request(&mut server, &mut device, &mut client, "Z0,204,2"));
+        assert_eq!("OK", request(&mut server, &mut device, &mut client, "D"));
+        server.run_frame(&mut device).unwrap();
+        assert!(device.registers.v[1] > 1);
+        drop(client);
+
+        let mut client = connect(&server);
+        client.write_all(&[0x03]).unwrap();
+        assert_eq!("S02", read_response(&mut server, &mut device, &mut client));
+        assert_eq!("OK", request(&mut server, &mut device, &mut client, "Z0,204,2"));
+        drop(client);
+        let v1 = device.registers.v[1];
+        for _ in 0..3 {
+            server.run_frame(&mut device).unwrap();
+        }
+        assert!(device.registers.v[1] > v1 + 1);
+    }
+
+    #[test]
+    fn test_failed_writes_detach_the_client() {
+        let mut device = counting_device();
+        let mut server = GdbServer::bind("127.0.0.1:0").unwrap();
+        let mut client = connect(&server);
+        assert_eq!("OK", request(&mut server, &mut device, &mut client, "Z0,204,2"));
+        // closing with a response left unread resets the connection, so answering the packet sent before fails
+        client.write_all(b"$?#3f").unwrap();
+        server.run_frame(&mut device).unwrap();
+        client.write_all(b"$g#67").unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        drop(client);
+        std::thread::sleep(Duration::from_millis(10));
+        for _ in 0..3 {
+            server.run_frame(&mut device).unwrap();
+        }
+        assert!(device.registers.v[1] > 1);
+    }
+}
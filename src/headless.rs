@@ -0,0 +1,60 @@
+use std::io::Write;
+use std::path::PathBuf;
+
+use porcel8::device::Device;
+use porcel8::device::frontend::{NullFrontend, ScriptedInput};
+use porcel8::rpl::RplFlagStore;
+use porcel8::util::EmulatorResult;
+
+use crate::args::Porcel8ProgramArgs;
+use crate::create_device;
+
+/// Run the ROM for a number of frames or instructions without initialising SDL,
+/// then write the registers and the display
+pub fn run_headless(args: &Porcel8ProgramArgs, rom: &[u8]) -> EmulatorResult<()> {
+    let input = ScriptedInput::new(args.key_event.clone());
+    let mut device = create_device(args, rom, Box::new(NullFrontend), Box::new(NullFrontend), Box::new(input))?;
+    // flags from earlier runs would make runs differ, so only use them if asked to
+    let mut rpl_flag_store = args.flags_dir.clone().map(|flags_dir| RplFlagStore::new(PathBuf::from(flags_dir), rom));
+    if let Some(rpl_flag_store) = &mut rpl_flag_store {
+        device.registers.rpl = rpl_flag_store.load()?;
+    }
+
+    let result = match args.frames {
+        Some(frames) => run_frames(&mut device, frames),
+        None => run_cycles(&mut device, args.cycles.expect("clap requires --frames or --cycles with --headless")),
+    };
+    // save on failures too, the flags written before them are kept
+    if let Some(rpl_flag_store) = &mut rpl_flag_store {
+        rpl_flag_store.save_changed(&device.registers.rpl)?;
+    }
+    result?;
+
+    let dump = format!("{}\n{}", device.registers, device.frame_buffer);
+    match &args.dump {
+        Some(dump_file) => std::fs::write(dump_file, dump)?,
+        None => std::io::stdout().write_all(dump.as_bytes())?,
+    }
+    Ok(())
+}
+
+fn run_frames(device: &mut Device, frames: u64) -> EmulatorResult<()> {
+    for _ in 0..frames {
+        if device.has_exited() {
+            break;
+        }
+        device.run_frame()?;
+    }
+    Ok(())
+}
+
+/// Run whole frames, cutting the last one short to execute exactly `cycles` instructions
+fn run_cycles(device: &mut Device, cycles: u64) -> EmulatorResult<()> {
+    let instructions_per_frame = device.device_config.get_instructions_per_frame() as u64;
+    let mut remaining_cycles = cycles;
+    while remaining_cycles > 0 && !device.has_exited() {
+        let frame_instructions = remaining_cycles.min(instructions_per_frame) as u32;
+        remaining_cycles -= device.run_partial_frame(frame_instructions)? as u64;
+    }
+    Ok(())
+}
@@ -0,0 +1,16 @@
+//! Core of the porcel8 CHIP-8, SUPER-CHIP and XO-CHIP emulator: the device, instruction decoding,
+//! configuration and errors. It does not depend on any frontend; the SDL frontend is the `porcel8` binary.
+pub mod assembler;
+pub mod cartridge;
+pub mod client_listener;
+pub mod dap;
+pub mod debugger;
+pub mod device;
+pub mod disassembler;
+pub mod gdb;
+pub mod platform;
+pub mod rom;
+pub mod rpl;
+pub mod state_store;
+pub mod symbol_map;
+pub mod util;
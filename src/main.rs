@@ -1,157 +1,117 @@
-use std::sync::{Arc, Mutex};
-use std::sync::mpsc::Sender;
-use std::thread;
-use std::thread::JoinHandle;
 use clap::Parser;
 use log::LevelFilter;
-use sdl2::audio::{AudioQueue, AudioSpecDesired};
-use sdl2::event::Event;
-use sdl2::EventPump;
-use sdl2::keyboard::Keycode;
-use sdl2::pixels::Color;
-use sdl2::render::BlendMode;
-
-use sdl2::render::WindowCanvas;
 use simple_logger::SimpleLogger;
-use util::DeviceConfig;
 
-use crate::args::Porcel8ProgramArgs;
-use crate::device::Device;
+use porcel8::assembler::Program;
+use porcel8::device::Device;
+use porcel8::device::font::Font;
+use porcel8::device::frontend::{AudioSink, DisplaySink, InputSource};
+use porcel8::disassembler::Disassembly;
+use porcel8::rom;
+use porcel8::util::{DeviceConfig, EmulatorResult};
 
-use crate::util::EmulatorResult;
-use crate::sdl_adapters::sdl_audio_adapter::SdlAudioAdapter;
-use crate::sdl_adapters::sdl_graphics_adapter::SdlGraphicsAdapter;
-use crate::sdl_adapters::sdl_keyboard_adapter::SdlKeyboardAdapter;
+use crate::args::{Porcel8Command, Porcel8ProgramArgs};
 
 mod args;
-mod device;
-mod util;
+mod headless;
+#[cfg(feature = "sdl")]
 mod sdl_adapters;
-mod rom;
-
-const WINDOW_TITLE: &str = "porcel8";
+#[cfg(feature = "sdl")]
+mod windowed;
 
 fn main() -> EmulatorResult<()> {
+    let mut args = Porcel8ProgramArgs::parse();
+    // tools print to the standard output, so they run before the logger writes to it
+    if let Some(command) = &args.command {
+        return run_command(command);
+    }
     SimpleLogger::new().with_level(LevelFilter::Info).env().init().unwrap();
-    let Porcel8ProgramArgs { filename, new_chip8_behaviour, draw_scale, halt_on_invalid, do_instruction_throttling, ips_throttling_rate: ipms_throttling_rate } = Porcel8ProgramArgs::parse();
 
     log::info!("Started emulator");
+    let rom = load_rom(&mut args)?;
+    if args.headless {
+        return headless::run_headless(&args, &rom);
+    }
+    run_windowed(&args, &rom)
+}
 
-    let (mut canvas, mut event_pump, audio_queue) = try_initiate_sdl(draw_scale)?;
-
-    let (mut timer, mut sdl_aud_adapter) = SdlAudioAdapter::new_timers(SdlAudioAdapter::AUDIO_FREQUENCY, 0.85, audio_queue);
-
-    let (frame_buffer_for_display, frame_buffer_for_device) = get_frame_buffer_references();
-    let (sdl_kb_adapter, device_keyboard) = SdlKeyboardAdapter::new_keyboard();
-
-    timer.start();
-    let device_config = DeviceConfig::new(new_chip8_behaviour, halt_on_invalid, do_instruction_throttling, ipms_throttling_rate);
-    let device = Device::new(timer, frame_buffer_for_device, device_keyboard, device_config);
-
-    let (device_termination_signal_sender, compute_handle) = start_compute_thread(filename, device)?;
-
-
-    let mut sdl_graphics_adapter = SdlGraphicsAdapter::new();
+#[cfg(feature = "sdl")]
+fn run_windowed(args: &Porcel8ProgramArgs, rom: &[u8]) -> EmulatorResult<()> {
+    windowed::run_windowed(args, rom)
+}
 
-    canvas.set_draw_color(Color::BLACK);
-    canvas.clear();
+#[cfg(not(feature = "sdl"))]
+fn run_windowed(_args: &Porcel8ProgramArgs, _rom: &[u8]) -> EmulatorResult<()> {
+    Err(porcel8::util::EmulatorError::InvalidConfiguration("porcel8 was built without the sdl feature, only --headless is available".into()))
+}
 
-    // Compute a frame time offset
-    // Thread will sleep for 60fps - (time spent computing)
-    let mut frame_timer = std::time::Instant::now();
-    'running: loop {
-        let last_time = frame_timer.elapsed();
-        for event in event_pump.poll_iter() {
-            match event {
-                Event::Quit { .. } |
-                Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
-                    device_termination_signal_sender.send(())?;
-                    break 'running;
-                }
-                Event::KeyDown { keycode: Some(keycode), repeat: false, .. } => {
-                    sdl_kb_adapter.process_key_down(keycode)?;
-                }
-                Event::KeyUp { keycode: Some(keycode), repeat: false, .. } => {
-                    sdl_kb_adapter.process_key_up(keycode)?;
-                }
-                _ => {}
-            }
+/// Run a tool instead of the emulator
+fn run_command(command: &Porcel8Command) -> EmulatorResult<()> {
+    match command {
+        Porcel8Command::Disasm { rom, syntax } => {
+            let (rom, _) = rom::load_rom(rom.clone(), rom::XO_CHIP_ROM_SIZE)?;
+            println!("{}", Disassembly::new(&rom).format((*syntax).into()));
         }
-
-        // lock and draw framebuffer
-        {
-            let lock = frame_buffer_for_display.lock()?;
-            sdl_graphics_adapter.draw_screen(lock, &mut canvas)?;
+        Porcel8Command::Asm { source, output, symbols } => {
+            let program = Program::assemble(&std::fs::read_to_string(source)?, source)?;
+            std::fs::write(output, program.rom())?;
+            if let Some(symbols) = symbols {
+                std::fs::write(symbols, program.symbol_map().to_string())?;
+            }
         }
-        canvas.present();
-        sdl_aud_adapter.process_push_audio()?;
-        let sleep_duration = SdlGraphicsAdapter::FRAME_RATE_TIMING - last_time;
-        thread::sleep(sleep_duration);
-        frame_timer = std::time::Instant::now();
     }
-
-    compute_handle.join().expect("Failed to close compute thread");
     Ok(())
 }
 
-fn start_compute_thread(filename: String, mut device: Device) -> EmulatorResult<(Sender<()>, JoinHandle<()>)> {
-    device.set_default_font();
-
-    let rom = rom::load_rom(filename)?;
-    device.load_rom(&rom);
-
-    let (device_termination_signal_sender, device_termination_signal_sender_receiver) = std::sync::mpsc::channel();
-    let compute_handle = thread::Builder::new().name("Compute".to_string()).spawn(move || {
-
-        loop {
-            let val = device_termination_signal_sender_receiver.try_recv();
-            if let Ok(()) = val {
-                break;
-            } else if let Err(std::sync::mpsc::TryRecvError::Disconnected) = val {
-                panic!("Disconnected");
-            }
-            device.cycle().expect("Failed to execute");
-        }
-    })?;
-    Ok((device_termination_signal_sender, compute_handle))
+/// Load the ROM named by the arguments, applying the options of an Octo cartridge to them
+fn load_rom(args: &mut Porcel8ProgramArgs) -> EmulatorResult<Vec<u8>> {
+    let max_rom_size = if args.is_xo_chip() { rom::XO_CHIP_ROM_SIZE } else { rom::ROM_SIZE };
+    let (rom, cartridge_options) = rom::load_rom(args.filename.clone().unwrap_or_default(), max_rom_size)?;
+    if cartridge_options.is_some() {
+        log::info!("Using the options of the Octo cartridge");
+    }
+    args.cartridge_options = cartridge_options;
+    Ok(rom)
 }
 
-
-fn get_frame_buffer_references() -> (Arc<Mutex<Box<[bool; 2048]>>>, Arc<Mutex<Box<[bool; 2048]>>>) {
-    let arc = Arc::new(Mutex::new(vec![false; Device::FRAME_BUFFER_SIZE].into_boxed_slice().try_into().unwrap()));
-    let arc2 = Arc::clone(&arc);
-    (arc, arc2)
+/// Create a device from the arguments, with the fonts and ROM loaded
+fn create_device(
+    args: &Porcel8ProgramArgs,
+    rom: &[u8],
+    display: Box<dyn DisplaySink + Send>,
+    audio: Box<dyn AudioSink + Send>,
+    input: Box<dyn InputSource + Send>,
+) -> EmulatorResult<Device> {
+    let device_config = DeviceConfig::new(args.get_quirk_config(), args.get_instructions_per_frame())
+        .with_halt_on_invalid(args.halt_on_invalid)
+        .with_xo_chip(args.is_xo_chip())
+        .with_high_resolution(args.has_high_resolution());
+    let mut device = Device::new(display, audio, input, device_config);
+    let seed = args.seed.unwrap_or_else(rand::random);
+    log::info!("Random seed {}", seed);
+    device.set_random_seed(seed);
+    load_fonts(&mut device, args.get_default_fonts(), args.small_font.clone(), args.big_font.clone())?;
+    device.load_rom(rom);
+    Ok(device)
 }
 
-/// Initiate SDL resources:
-/// 1. A window canvas for drawing
-/// 2. An event pump for use as an event loop,
-/// 3. An Audio queue for sound
-fn try_initiate_sdl(draw_scale: f32) -> EmulatorResult<(WindowCanvas, EventPump, AudioQueue<f32>)> {
-    let sdl_context = sdl2::init().unwrap();
-    let video_subsystem = sdl_context.video().unwrap();
-    let audio_subsystem = sdl_context.audio().unwrap();
-    let wanted_spec = AudioSpecDesired {
-        channels: Some(1),
-        samples: None,
-        freq: Some(SdlAudioAdapter::SAMPLING_FREQ),
+/// Install the default fonts, or the font files if specified
+fn load_fonts(device: &mut Device, default_fonts: (Font, Font), small_font_file: Option<String>, big_font_file: Option<String>) -> EmulatorResult<()> {
+    let (default_small_font, default_big_font) = default_fonts;
+    if small_font_file.is_none() && big_font_file.is_none() {
+        device.set_font(&default_small_font, &default_big_font);
+        log::info!("Loaded default font");
+        return Ok(());
+    }
+    let small_font = match small_font_file {
+        Some(small_font_file) => Font::new_small(std::fs::read(small_font_file)?)?,
+        None => default_small_font,
     };
-
-    let audio_queue = audio_subsystem.open_queue::<f32, _>(None, &wanted_spec)?;
-
-    let window_width = (Device::FRAME_BUFFER_WIDTH as f32 * draw_scale) as u32;
-    let window_height = (Device::FRAME_BUFFER_HEIGHT as f32 * draw_scale) as u32;
-
-    let window = video_subsystem.window(WINDOW_TITLE, window_width, window_height)
-        .position_centered()
-        .build()?;
-    let mut canvas = window.into_canvas().build()?;
-
-    canvas.set_scale(draw_scale, draw_scale)?;
-
-    canvas.set_blend_mode(BlendMode::None);
-    canvas.clear();
-    canvas.present();
-    let event_pump = sdl_context.event_pump()?;
-    Ok((canvas, event_pump, audio_queue))
+    let big_font = match big_font_file {
+        Some(big_font_file) => Font::new_big(std::fs::read(big_font_file)?)?,
+        None => default_big_font,
+    };
+    device.set_font(&small_font, &big_font);
+    log::info!("Loaded custom font");
+    Ok(())
 }
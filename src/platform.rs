@@ -0,0 +1,131 @@
+use crate::device::font::Font;
+use crate::util::QuirkConfig;
+
+/// CHIP-8 implementations that can be emulated with a single option.
+/// Each one sets the quirks, memory size, display modes, fonts and instruction rate of the original.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Platform {
+    /// CHIP-8 on the COSMAC VIP
+    CosmacVip,
+    /// CHIP-48 on the HP-48
+    Chip48,
+    /// SUPER-CHIP 1.0 on the HP-48
+    SuperChip10,
+    /// SUPER-CHIP 1.1 on the HP-48
+    SuperChip11,
+    /// SUPER-CHIP as implemented by modern interpreters such as Octo
+    SuperChipModern,
+    /// XO-CHIP as implemented by Octo
+    XoChip,
+}
+
+impl Platform {
+    pub fn get_quirks(&self) -> QuirkConfig {
+        let super_chip_quirks = QuirkConfig {
+            vf_reset: false,
+            memory_increment: false,
+            shift_in_place: true,
+            jump_with_vx: true,
+            index_overflow_flag: false,
+            lores_half_scroll: false,
+            count_collision_rows: false,
+            wrap_sprites: false,
+            display_wait: false,
+        };
+        match self {
+            Platform::CosmacVip => QuirkConfig::original_chip8(),
+            Platform::Chip48 | Platform::SuperChip10 => QuirkConfig {
+                memory_increment: true,
+                count_collision_rows: *self == Platform::SuperChip10,
+                ..super_chip_quirks
+            },
+            Platform::SuperChip11 => QuirkConfig {
+                lores_half_scroll: true,
+                count_collision_rows: true,
+                ..super_chip_quirks
+            },
+            Platform::SuperChipModern => super_chip_quirks,
+            Platform::XoChip => QuirkConfig {
+                memory_increment: true,
+                shift_in_place: false,
+                jump_with_vx: false,
+                wrap_sprites: true,
+                ..super_chip_quirks
+            },
+        }
+    }
+
+    /// Whether the platform has 64 KiB of memory and the other XO-CHIP extensions
+    pub fn is_xo_chip(&self) -> bool {
+        *self == Platform::XoChip
+    }
+
+    /// Whether the platform supports the 128x64 mode
+    pub fn has_high_resolution(&self) -> bool {
+        !matches!(self, Platform::CosmacVip | Platform::Chip48)
+    }
+
+    /// Instructions per 60 Hz frame that programs for the platform usually expect
+    pub fn get_instructions_per_frame(&self) -> u32 {
+        match self {
+            Platform::CosmacVip => 11,
+            Platform::Chip48 => 15,
+            Platform::SuperChip10 | Platform::SuperChip11 | Platform::SuperChipModern => 30,
+            Platform::XoChip => 1000,
+        }
+    }
+
+    /// The small and big fonts of the platform
+    pub fn get_fonts(&self) -> (Font, Font) {
+        match self {
+            Platform::CosmacVip | Platform::Chip48 => (Font::vip_small(), Font::default_big()),
+            Platform::SuperChip10 | Platform::SuperChip11 | Platform::SuperChipModern => (Font::default_small(), Font::schip_big()),
+            Platform::XoChip => (Font::default_small(), Font::default_big()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Platform;
+
+    #[test]
+    fn test_vip_quirks() {
+        let platform = Platform::CosmacVip;
+        let quirks = platform.get_quirks();
+        assert!(quirks.vf_reset);
+        assert!(quirks.memory_increment);
+        assert!(!quirks.shift_in_place);
+        assert!(!quirks.jump_with_vx);
+        assert!(quirks.display_wait);
+        assert!(!platform.has_high_resolution());
+        assert!(!platform.is_xo_chip());
+    }
+
+    #[test]
+    fn test_super_chip_quirks() {
+        let legacy_quirks = Platform::SuperChip11.get_quirks();
+        assert!(!legacy_quirks.vf_reset);
+        assert!(!legacy_quirks.memory_increment);
+        assert!(legacy_quirks.shift_in_place);
+        assert!(legacy_quirks.jump_with_vx);
+        assert!(legacy_quirks.lores_half_scroll);
+        assert!(legacy_quirks.count_collision_rows);
+
+        let modern_quirks = Platform::SuperChipModern.get_quirks();
+        assert!(!modern_quirks.lores_half_scroll);
+        assert!(!modern_quirks.count_collision_rows);
+        assert!(Platform::SuperChipModern.has_high_resolution());
+    }
+
+    #[test]
+    fn test_xo_chip_quirks() {
+        let quirks = Platform::XoChip.get_quirks();
+        assert!(quirks.memory_increment);
+        assert!(!quirks.shift_in_place);
+        assert!(!quirks.jump_with_vx);
+        assert!(quirks.wrap_sprites);
+        assert!(Platform::XoChip.is_xo_chip());
+        assert!(Platform::XoChip.has_high_resolution());
+    }
+}
@@ -1,12 +1,64 @@
 use std::fs::File;
 use std::io::Read;
-use crate::util::EmulatorResult;
+use std::path::Path;
+use crate::assembler::{Program, OCTO_SOURCE_EXTENSION};
+use crate::cartridge::{Cartridge, CartridgeOptions};
+use crate::device::Device;
+use crate::util::{EmulatorError, EmulatorResult};
 
-pub const ROM_SIZE: usize = 4096 - 0x200;
+pub const ROM_SIZE: usize = Device::DEVICE_MEMORY_SIZE - Device::ROM_START;
+pub const XO_CHIP_ROM_SIZE: usize = Device::XO_CHIP_MEMORY_SIZE - Device::ROM_START;
 
-pub fn load_rom(rom_file_location: String) -> EmulatorResult<[u8; ROM_SIZE]> {
-    let mut rom_slice = [0u8; ROM_SIZE];
-    let mut file = File::open(rom_file_location)?;
-    file.read(&mut rom_slice)?;
-    Ok(rom_slice)
+/// Load up to `max_rom_size` bytes of a rom file. Octo source files are assembled first.
+/// Octo cartridges are assembled from the source they hold and also return their options,
+/// which allow them the size of an XO-CHIP ROM if they ask for XO-CHIP.
+pub fn load_rom(rom_file_location: String, max_rom_size: usize) -> EmulatorResult<(Vec<u8>, Option<CartridgeOptions>)> {
+    let mut rom_bytes = Vec::with_capacity(max_rom_size);
+    let mut cartridge_options = None;
+    let mut max_rom_size = max_rom_size;
+    if Path::new(&rom_file_location).extension().is_some_and(|extension| extension == OCTO_SOURCE_EXTENSION) {
+        let source = std::fs::read_to_string(&rom_file_location)?;
+        rom_bytes.extend_from_slice(Program::assemble(&source, &rom_file_location)?.rom());
+    } else {
+        let file = File::open(&rom_file_location)?;
+        file.take(max_rom_size as u64 + 1).read_to_end(&mut rom_bytes)?;
+        if Cartridge::is_cartridge(&rom_bytes) {
+            // the image is usually larger than the ROM limit
+            let gif = std::fs::read(&rom_file_location)?;
+            let cartridge = Cartridge::decode(&gif)?;
+            // cartridges hold Octo source, which may use more of the language than porcel8 assembles
+            let program = Program::assemble(cartridge.program(), &rom_file_location).map_err(|err| {
+                EmulatorError::InvalidCartridge(format!("its program could not be assembled by porcel8's subset of Octo: {}", err))
+            })?;
+            rom_bytes = program.rom().to_vec();
+            if cartridge.options().is_xo_chip {
+                max_rom_size = max_rom_size.max(XO_CHIP_ROM_SIZE);
+            }
+            cartridge_options = Some(cartridge.options().clone());
+        }
+    }
+    if rom_bytes.len() > max_rom_size {
+        log::warn!("ROM is larger than {} bytes, truncating", max_rom_size);
+        rom_bytes.truncate(max_rom_size);
+    }
+    Ok((rom_bytes, cartridge_options))
+}
+
+/// FNV-1a hash of a ROM, stable across builds unlike the std hasher.
+/// Names the files kept per ROM, such as RPL flags and save states.
+pub fn hash_rom(rom: &[u8]) -> u64 {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+    rom.iter().fold(FNV_OFFSET_BASIS, |hash, byte| (hash ^ *byte as u64).wrapping_mul(FNV_PRIME))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::hash_rom;
+
+    #[test]
+    fn test_hash_differs_per_rom() {
+        assert_eq!(hash_rom(&[1, 2, 3]), hash_rom(&[1, 2, 3]));
+        assert_ne!(hash_rom(&[1, 2, 3]), hash_rom(&[1, 2, 4]));
+    }
 }
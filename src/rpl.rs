@@ -0,0 +1,97 @@
+use std::path::PathBuf;
+use crate::device::RplFlags;
+use crate::rom;
+use crate::util::{self, EmulatorResult};
+
+/// Persists the SUPER-CHIP RPL user flags of a ROM between runs.
+/// Each ROM gets its own file, named after a hash of the ROM contents.
+#[derive(Debug)]
+pub struct RplFlagStore {
+    flag_file: PathBuf,
+    /// Flags last loaded or saved, to only write the file when they change
+    saved_flags: Option<RplFlags>,
+}
+
+impl RplFlagStore {
+    const FLAG_FILE_EXTENSION: &'static str = "rpl";
+
+    pub fn new(flag_directory: PathBuf, rom: &[u8]) -> RplFlagStore {
+        let flag_file = flag_directory.join(format!("{:016x}.{}", rom::hash_rom(rom), Self::FLAG_FILE_EXTENSION));
+        RplFlagStore { flag_file, saved_flags: None }
+    }
+
+    /// Default directory for flag files, following the XDG base directory convention
+    pub fn default_directory() -> PathBuf {
+        util::data_directory().join("flags")
+    }
+
+    /// Load the saved flags, or all zero flags if this ROM has not saved any yet
+    pub fn load(&mut self) -> EmulatorResult<RplFlags> {
+        let mut flags = RplFlags::default();
+        if self.flag_file.exists() {
+            let saved_flags = std::fs::read(&self.flag_file)?;
+            let length = saved_flags.len().min(flags.len());
+            flags[..length].copy_from_slice(&saved_flags[..length]);
+            log::info!("Loaded RPL flags from {}", self.flag_file.display());
+        }
+        self.saved_flags = Some(flags);
+        Ok(flags)
+    }
+
+    pub fn save(&mut self, flags: &RplFlags) -> EmulatorResult<()> {
+        if let Some(flag_directory) = self.flag_file.parent() {
+            std::fs::create_dir_all(flag_directory)?;
+        }
+        std::fs::write(&self.flag_file, flags)?;
+        self.saved_flags = Some(*flags);
+        log::info!("Saved RPL flags to {}", self.flag_file.display());
+        Ok(())
+    }
+
+    /// Save the flags if they differ from those last loaded or saved.
+    /// Called after every frame, so flags written by the program survive the emulator being killed.
+    pub fn save_changed(&mut self, flags: &RplFlags) -> EmulatorResult<()> {
+        if self.saved_flags.as_ref() == Some(flags) {
+            return Ok(());
+        }
+        self.save(flags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RplFlagStore;
+
+    #[test]
+    fn test_save_then_load() {
+        let flag_directory = std::env::temp_dir().join(format!("porcel8-rpl-test-{}", std::process::id()));
+        let mut store = RplFlagStore::new(flag_directory.clone(), &[0x12, 0x34]);
+        assert_eq!([0u8; 16], store.load().unwrap());
+
+        let flags = [7u8; 16];
+        store.save(&flags).unwrap();
+        assert_eq!(flags, store.load().unwrap());
+
+        let mut other_rom_store = RplFlagStore::new(flag_directory.clone(), &[0x12, 0x35]);
+        assert_eq!([0u8; 16], other_rom_store.load().unwrap());
+        std::fs::remove_dir_all(flag_directory).unwrap();
+    }
+
+    #[test]
+    fn test_save_changed_only_writes_new_flags() {
+        let flag_directory = std::env::temp_dir().join(format!("porcel8-rpl-changed-test-{}", std::process::id()));
+        let mut store = RplFlagStore::new(flag_directory.clone(), &[0x12, 0x34]);
+        let flags = store.load().unwrap();
+        store.save_changed(&flags).unwrap();
+        assert!(!flag_directory.exists());
+
+        let mut flags = flags;
+        flags[3] = 5;
+        store.save_changed(&flags).unwrap();
+        assert_eq!(flags, RplFlagStore::new(flag_directory.clone(), &[0x12, 0x34]).load().unwrap());
+
+        std::fs::remove_dir_all(&flag_directory).unwrap();
+        store.save_changed(&flags).unwrap();
+        assert!(!flag_directory.exists());
+    }
+}
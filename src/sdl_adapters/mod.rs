@@ -1,3 +1,12 @@
+use std::fmt::Display;
+use porcel8::util::EmulatorError;
+
 pub mod sdl_graphics_adapter;
 pub mod sdl_audio_adapter;
-pub mod sdl_keyboard_adapter;
\ No newline at end of file
+pub mod sdl_keyboard_adapter;
+
+/// Convert an SDL error into an emulator error.
+/// The SDL error types are foreign to the emulator library, so they cannot implement `From`.
+pub fn sdl_error(err: impl Display) -> EmulatorError {
+    EmulatorError::SdlError(err.to_string())
+}
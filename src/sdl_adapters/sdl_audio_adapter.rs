@@ -1,13 +1,16 @@
 use std::sync::{Arc, Mutex};
 use sdl2::audio::AudioQueue;
-use crate::device::timer::DeviceTimerManager;
-use crate::util::EmulatorResult;
+use porcel8::device::sound::SoundState;
+use porcel8::util::EmulatorResult;
 
-/// An Audio adapter using `AudioQueue`. Generates a square wave of specified frequency
+/// An Audio adapter using `AudioQueue`. Generates a square wave of specified frequency,
+/// or plays the XO-CHIP audio pattern if one was loaded
 pub struct SdlAudioAdapter {
-    sound_timer: Arc<Mutex<u8>>,
+    sound_state: Arc<Mutex<SoundState>>,
     phase_inc: f32,
     phase: f32,
+    /// Position in the audio pattern, in bits
+    pattern_position: f32,
     volume: f32,
     audio_queue: AudioQueue<f32>,
     internal_buffer: Vec<f32>,
@@ -18,15 +21,15 @@ impl SdlAudioAdapter {
     pub const SAMPLING_FREQ:i32 = 15360;
     pub const AUDIO_FREQUENCY: f32 = 440.0;
     pub const SAMPLES_PER_FRAME: usize = (Self::SAMPLING_FREQ as usize / 60) * 2;
-    pub fn new_timers(freq: f32,
+    /// Creates a paired adapter and the sound state it plays, to be updated by the device
+    pub fn new_audio(freq: f32,
                  volume: f32,
-                 audio_queue: AudioQueue<f32>) ->(DeviceTimerManager,SdlAudioAdapter){
-        let device_sound_timer = Arc::new(Mutex::default());
-        let device_timer_manager = DeviceTimerManager::new(device_sound_timer.clone());
-        let sdl_audio_adapter = SdlAudioAdapter::new(device_sound_timer,freq,volume,audio_queue);
-        (device_timer_manager, sdl_audio_adapter)
+                 audio_queue: AudioQueue<f32>) ->(Arc<Mutex<SoundState>>,SdlAudioAdapter){
+        let device_sound_state = Arc::new(Mutex::default());
+        let sdl_audio_adapter = SdlAudioAdapter::new(device_sound_state.clone(),freq,volume,audio_queue);
+        (device_sound_state, sdl_audio_adapter)
     }
-    fn new(sound_timer: Arc<Mutex<u8>>,
+    fn new(sound_state: Arc<Mutex<SoundState>>,
                freq: f32,
                volume: f32,
                audio_queue: AudioQueue<f32>) -> SdlAudioAdapter {
@@ -34,9 +37,10 @@ impl SdlAudioAdapter {
         // ensure frequency isn't too low
         assert!(((2.0*freq) as i32) < Self::SAMPLING_FREQ);
         SdlAudioAdapter {
-            sound_timer,
+            sound_state,
             internal_buffer: vec![0f32; Self::SAMPLES_PER_FRAME],
             phase: 0f32,
+            pattern_position: 0f32,
             phase_inc: freq/Self::SAMPLING_FREQ as f32,
             volume,
             audio_queue,
@@ -44,12 +48,15 @@ impl SdlAudioAdapter {
     }
     pub fn process_push_audio(&mut self) -> EmulatorResult<()> {
         // fill the audio vector.
-        let sound_timer = {
-            let sound_timer = self.sound_timer.lock().expect("Could not lock to play audio");
-            sound_timer.clone()
+        let sound_state = {
+            let sound_state = self.sound_state.lock().expect("Could not lock to play audio");
+            sound_state.clone()
         };
-        if sound_timer>0 && self.audio_queue.size() < Self::SAMPLING_FREQ as u32 {
-            self.fill_audio();
+        if sound_state.timer>0 && self.audio_queue.size() < Self::SAMPLING_FREQ as u32 {
+            match sound_state.pattern {
+                Some(pattern) => self.fill_audio_pattern(&pattern, sound_state.get_playback_rate()),
+                None => self.fill_audio(),
+            }
             self.audio_queue.queue_audio(&self.internal_buffer)?;
         }
         Ok(())
@@ -69,4 +76,20 @@ impl SdlAudioAdapter {
             self.phase = (self.phase + self.phase_inc) % 1.0;
         }
     }
+
+    /// Play the pattern bits at `playback_rate` bits per second
+    fn fill_audio_pattern(&mut self, pattern: &[u8; SoundState::PATTERN_SIZE], playback_rate: f32) {
+        let out = &mut self.internal_buffer;
+        let position_inc = playback_rate / Self::SAMPLING_FREQ as f32;
+
+        for x in out.iter_mut() {
+            *x = if SoundState::get_pattern_bit(pattern, self.pattern_position as usize) {
+                self.volume
+            } else {
+                -self.volume
+            };
+
+            self.pattern_position = (self.pattern_position + position_inc) % SoundState::PATTERN_BITS as f32;
+        }
+    }
 }
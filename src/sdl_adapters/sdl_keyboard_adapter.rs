@@ -1,7 +1,7 @@
 use std::sync::mpsc::Sender;
-use crate::device::keyboard::{Key, Keyboard, KeyboardEvent};
-use crate::device::keyboard::KeyboardEvent::{KeyDown, KeyUp};
-use crate::util::EmulatorResult;
+use porcel8::device::keyboard::{Key, Keyboard, KeyboardEvent};
+use porcel8::device::keyboard::KeyboardEvent::{KeyDown, KeyUp};
+use porcel8::util::EmulatorResult;
 
 #[derive(Debug)]
 pub struct SdlKeyboardAdapter {
@@ -63,7 +63,7 @@ impl SdlKeyboardAdapter {
 
 #[cfg(test)]
 mod tests{
-    use crate::device::keyboard::Key;
+    use porcel8::device::keyboard::Key;
 
     use super::SdlKeyboardAdapter;
 
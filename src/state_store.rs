@@ -0,0 +1,67 @@
+use std::path::PathBuf;
+use crate::device::save_state::SaveState;
+use crate::rom;
+use crate::util::{self, EmulatorError, EmulatorResult};
+
+/// Keeps numbered save state slots for a ROM.
+/// Each ROM gets its own files, named after a hash of the ROM contents and the slot.
+#[derive(Debug)]
+pub struct SaveStateStore {
+    state_directory: PathBuf,
+    rom_hash: u64,
+}
+
+impl SaveStateStore {
+    const STATE_FILE_EXTENSION: &'static str = "p8s";
+
+    pub fn new(state_directory: PathBuf, rom: &[u8]) -> SaveStateStore {
+        SaveStateStore { state_directory, rom_hash: rom::hash_rom(rom) }
+    }
+
+    /// Default directory for save states, following the XDG base directory convention
+    pub fn default_directory() -> PathBuf {
+        util::data_directory().join("states")
+    }
+
+    pub fn save(&self, slot: u8, save_state: &SaveState) -> EmulatorResult<()> {
+        std::fs::create_dir_all(&self.state_directory)?;
+        let state_file = self.get_state_file(slot);
+        std::fs::write(&state_file, save_state.to_bytes())?;
+        log::info!("Saved state to {}", state_file.display());
+        Ok(())
+    }
+
+    pub fn load(&self, slot: u8) -> EmulatorResult<SaveState> {
+        let state_file = self.get_state_file(slot);
+        if !state_file.exists() {
+            return Err(EmulatorError::InvalidSaveState(format!("Slot {} is empty", slot)));
+        }
+        let save_state = SaveState::from_bytes(&std::fs::read(&state_file)?)?;
+        log::info!("Loaded state from {}", state_file.display());
+        Ok(save_state)
+    }
+
+    fn get_state_file(&self, slot: u8) -> PathBuf {
+        self.state_directory.join(format!("{:016x}.{}.{}", self.rom_hash, slot, Self::STATE_FILE_EXTENSION))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SaveStateStore;
+    use crate::device::test_util::counting_device;
+
+    #[test]
+    fn test_save_then_load_slot() {
+        let state_directory = std::env::temp_dir().join(format!("porcel8-state-test-{}", std::process::id()));
+        let store = SaveStateStore::new(state_directory.clone(), &[0x12, 0x34]);
+        assert!(store.load(1).is_err());
+
+        let mut device = counting_device();
+        device.registers.v[3] = 9;
+        store.save(1, &device.save_state()).unwrap();
+        assert_eq!(device.save_state(), store.load(1).unwrap());
+        assert!(store.load(2).is_err());
+        std::fs::remove_dir_all(state_directory).unwrap();
+    }
+}
@@ -0,0 +1,114 @@
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::util::{EmulatorError, EmulatorResult};
+
+/// Maps program addresses to the source lines they were assembled from, for debugging by line.
+///
+/// The text format has one `ADDRESS LINE FILE` entry per line, with a hex address, such as `0202 14 game.8o`.
+/// The file is last and runs to the end of the line, so that paths may contain spaces.
+/// Empty lines and lines starting with `#` are ignored.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SymbolMap {
+    /// Entries ordered by address
+    entries: Vec<SourceLocation>,
+}
+
+/// The source line of an instruction
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SourceLocation {
+    pub address: u16,
+    pub file: String,
+    pub line: u32,
+}
+
+impl SymbolMap {
+    pub fn new(mut entries: Vec<SourceLocation>) -> SymbolMap {
+        entries.sort_by_key(|entry| entry.address);
+        SymbolMap { entries }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> EmulatorResult<SymbolMap> {
+        std::fs::read_to_string(path)?.parse().map_err(EmulatorError::InvalidConfiguration)
+    }
+
+    pub fn entries(&self) -> &[SourceLocation] {
+        &self.entries
+    }
+
+    /// Address of the first instruction assembled from `line` of `file`.
+    /// The file matches if one path ends with the other, so relative paths in the map match absolute paths.
+    pub fn get_address(&self, file: &str, line: u32) -> Option<u16> {
+        self.entries
+            .iter()
+            .find(|entry| entry.line == line && Self::is_same_file(&entry.file, file))
+            .map(|entry| entry.address)
+    }
+
+    /// Source line of the instruction at `address`
+    pub fn get_location(&self, address: u16) -> Option<&SourceLocation> {
+        self.entries.iter().find(|entry| entry.address == address)
+    }
+
+    fn is_same_file(map_file: &str, file: &str) -> bool {
+        Path::new(file).ends_with(map_file) || Path::new(map_file).ends_with(file)
+    }
+}
+
+impl FromStr for SymbolMap {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let entries = text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+            .map(|(index, line)| {
+                let invalid = || format!("Line {} of the symbol map is not ADDRESS LINE FILE: {}", index + 1, line);
+                let mut fields = line.trim().splitn(3, ' ');
+                let address = fields.next().and_then(|address| u16::from_str_radix(address, 16).ok()).ok_or_else(invalid)?;
+                let line = fields.next().and_then(|line| line.parse().ok()).ok_or_else(invalid)?;
+                let file = fields.next().filter(|file| !file.is_empty()).ok_or_else(invalid)?.to_string();
+                Ok(SourceLocation { address, file, line })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(SymbolMap::new(entries))
+    }
+}
+
+impl Display for SymbolMap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for entry in &self.entries {
+            writeln!(f, "{:04X} {} {}", entry.address, entry.line, entry.file)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SymbolMap;
+
+    #[test]
+    fn test_parse_and_look_up() {
+        let symbol_map: SymbolMap = "# game\n0202 14 src/game.8o\n\n0200 12 src/game.8o\n".parse().unwrap();
+        assert_eq!(0x200, symbol_map.entries()[0].address);
+        assert_eq!(Some(0x202), symbol_map.get_address("/home/user/project/src/game.8o", 14));
+        assert_eq!(None, symbol_map.get_address("/home/user/project/src/other.8o", 14));
+        assert_eq!(12, symbol_map.get_location(0x200).unwrap().line);
+        assert!(symbol_map.get_location(0x204).is_none());
+        assert!("0200 1".parse::<SymbolMap>().is_err());
+        assert!("0200 game.8o 1".parse::<SymbolMap>().is_err());
+        assert!("zz 1 game.8o".parse::<SymbolMap>().is_err());
+        assert_eq!("0200 12 src/game.8o\n0202 14 src/game.8o\n", symbol_map.to_string());
+    }
+
+    #[test]
+    fn test_paths_with_spaces() {
+        let symbol_map: SymbolMap = "0200 3 my games/space game.8o\n".parse().unwrap();
+        assert_eq!("my games/space game.8o", symbol_map.entries()[0].file);
+        assert_eq!(Some(0x200), symbol_map.get_address("/home/user/my games/space game.8o", 3));
+        assert_eq!(symbol_map, symbol_map.to_string().parse().unwrap());
+    }
+}
@@ -1,47 +1,133 @@
+use crate::device::Device;
 use crate::device::keyboard::KeyboardEvent;
-use sdl2::video::WindowBuildError;
-use sdl2::IntegerOrSdlError;
 use std::fmt::Display;
+use std::path::PathBuf;
 use std::sync::mpsc::SendError;
 use std::sync::PoisonError;
-use std::time::Duration;
 
 pub type EmulatorResult<T> = Result<T, EmulatorError>;
 
+/// Directory for files kept between runs, following the XDG base directory convention
+pub fn data_directory() -> PathBuf {
+    std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("share")))
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("porcel8")
+}
+
+/// Behaviours that differ between CHIP-8 implementations.
+/// ROMs written for one interpreter may rely on any combination of these.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct QuirkConfig {
+    /// 8XY1, 8XY2 and 8XY3 reset VF to 0, like the COSMAC VIP
+    pub vf_reset: bool,
+    /// FX55 and FX65 leave the index register pointing past the last register, like the COSMAC VIP
+    pub memory_increment: bool,
+    /// 8XY6 and 8XYE shift VX in place instead of shifting VY into VX, like CHIP-48 and SUPER-CHIP
+    pub shift_in_place: bool,
+    /// BXNN jumps to XNN + VX instead of NNN + V0, like CHIP-48 and SUPER-CHIP
+    pub jump_with_vx: bool,
+    /// FX1E sets VF when the index overflows past the end of memory, like the Amiga interpreter
+    pub index_overflow_flag: bool,
+    /// Scroll by half the pixels in low resolution mode, like SUPER-CHIP 1.1
+    pub lores_half_scroll: bool,
+    /// Set VF to the number of colliding or clipped rows in high resolution mode, like SUPER-CHIP 1.1
+    pub count_collision_rows: bool,
+    /// Sprites leaving an edge of the screen reappear on the opposite edge instead of being clipped, like XO-CHIP
+    pub wrap_sprites: bool,
+    /// DXYN waits for the next 60 Hz frame before drawing, like the COSMAC VIP
+    pub display_wait: bool,
+}
+
+impl QuirkConfig {
+    /// Behaviour of the CHIP-8 interpreter on the COSMAC VIP
+    pub fn original_chip8() -> QuirkConfig {
+        QuirkConfig {
+            vf_reset: true,
+            memory_increment: true,
+            shift_in_place: false,
+            jump_with_vx: false,
+            index_overflow_flag: false,
+            lores_half_scroll: false,
+            count_collision_rows: false,
+            wrap_sprites: false,
+            display_wait: true,
+        }
+    }
+
+    /// Updated behaviour, as seen in CHIP-48 and SUPER-CHIP
+    pub fn new_chip8() -> QuirkConfig {
+        QuirkConfig {
+            vf_reset: false,
+            memory_increment: false,
+            shift_in_place: true,
+            jump_with_vx: true,
+            index_overflow_flag: true,
+            lores_half_scroll: false,
+            count_collision_rows: false,
+            wrap_sprites: false,
+            display_wait: false,
+        }
+    }
+}
+
 /// CHIP-8 Device configuration
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct DeviceConfig {
-    is_new_chip8: bool,
+    quirks: QuirkConfig,
     halt_on_invalid: bool,
-    /// None if disabled, target instruction time otherwise
-    throttling_time: Option<Duration>,
+    /// Enable the XO-CHIP extensions, such as 64 KiB of memory
+    is_xo_chip: bool,
+    /// Allow switching to the SUPER-CHIP 128x64 mode
+    has_high_resolution: bool,
+    /// Number of instructions executed in each 60 Hz frame
+    instructions_per_frame: u32,
 }
 
 impl DeviceConfig {
-    pub fn new(
-        is_new_chip8: bool,
-        halt_on_invalid: bool,
-        do_instruction_throttling: bool,
-        ips_throttling_rate: u64,
-    ) -> DeviceConfig {
+    /// A CHIP-8 device with the given quirks, without the SUPER-CHIP and XO-CHIP extensions
+    pub fn new(quirks: QuirkConfig, instructions_per_frame: u32) -> DeviceConfig {
         DeviceConfig {
-            is_new_chip8,
-            halt_on_invalid,
-            throttling_time: if do_instruction_throttling {
-                Some(Duration::from_micros(1_000_000 / ips_throttling_rate))
-            } else {
-                None
-            },
+            quirks,
+            halt_on_invalid: false,
+            is_xo_chip: false,
+            has_high_resolution: false,
+            instructions_per_frame,
         }
     }
-    pub fn is_new_chip8(&self) -> bool {
-        self.is_new_chip8
+    /// Stop the device on invalid instructions instead of skipping them
+    pub fn with_halt_on_invalid(self, halt_on_invalid: bool) -> DeviceConfig {
+        DeviceConfig { halt_on_invalid, ..self }
+    }
+    pub fn with_xo_chip(self, is_xo_chip: bool) -> DeviceConfig {
+        DeviceConfig { is_xo_chip, ..self }
+    }
+    pub fn with_high_resolution(self, has_high_resolution: bool) -> DeviceConfig {
+        DeviceConfig { has_high_resolution, ..self }
+    }
+    pub fn get_quirks(&self) -> &QuirkConfig {
+        &self.quirks
     }
     pub fn should_halt_on_invalid(&self) -> bool {
         self.halt_on_invalid
     }
-    pub fn get_throttling_config(&self) -> Option<Duration> {
-        self.throttling_time
+    pub fn is_xo_chip(&self) -> bool {
+        self.is_xo_chip
+    }
+    pub fn has_high_resolution(&self) -> bool {
+        self.has_high_resolution
+    }
+    /// Size of the device memory, 64 KiB for XO-CHIP and 4 KiB otherwise
+    pub fn get_memory_size(&self) -> usize {
+        if self.is_xo_chip {
+            Device::XO_CHIP_MEMORY_SIZE
+        } else {
+            Device::DEVICE_MEMORY_SIZE
+        }
+    }
+    pub fn get_instructions_per_frame(&self) -> u32 {
+        self.instructions_per_frame
     }
 }
 
@@ -50,6 +136,10 @@ pub enum EmulatorError {
     SdlError(String),
     IOError(String),
     MutexInvalidState(String),
+    InvalidConfiguration(String),
+    InvalidSaveState(String),
+    InvalidAssembly(String),
+    InvalidCartridge(String),
 }
 
 impl Display for EmulatorError{
@@ -58,6 +148,10 @@ impl Display for EmulatorError{
             EmulatorError::SdlError(err) => write!(f,"Error with SDL: {}",err),
             EmulatorError::IOError(io_err) => write!(f,"IO Error: {}",io_err),
             EmulatorError::MutexInvalidState(invalid_mutex_err) => write!(f,"Issue from mutex: {}",invalid_mutex_err),
+            EmulatorError::InvalidConfiguration(config_err) => write!(f,"Invalid configuration: {}",config_err),
+            EmulatorError::InvalidSaveState(save_state_err) => write!(f,"Invalid save state: {}",save_state_err),
+            EmulatorError::InvalidAssembly(assembly_err) => write!(f,"Invalid assembly: {}",assembly_err),
+            EmulatorError::InvalidCartridge(cartridge_err) => write!(f,"Invalid Octo cartridge: {}",cartridge_err),
         }
     }
 }
@@ -73,20 +167,6 @@ impl From<String> for EmulatorError {
     }
 }
 
-impl From<WindowBuildError> for EmulatorError {
-    fn from(value: WindowBuildError) -> Self {
-        Self::SdlError(value.to_string())
-    }
-}
-
-impl From<IntegerOrSdlError> for EmulatorError {
-    fn from(value: IntegerOrSdlError) -> Self {
-        match value {
-            IntegerOrSdlError::IntegerOverflows(x, y) => Self::SdlError(format!("{} - {}", x, y)),
-            IntegerOrSdlError::SdlError(str) => Self::SdlError(str),
-        }
-    }
-}
 impl From<std::io::Error> for EmulatorError {
     fn from(value: std::io::Error) -> Self {
         Self::IOError(value.to_string())
@@ -109,23 +189,34 @@ impl From<SendError<KeyboardEvent>> for EmulatorError {
 
 #[cfg(test)]
 mod tests{
-    use std::time::Duration;
-
-    use super::DeviceConfig;
+    use super::{DeviceConfig, QuirkConfig};
 
     #[test]
     fn test_device_config_all_false(){
-        let device_config = DeviceConfig::new(false, false, false, 800);
-        assert_eq!(false,device_config.is_new_chip8());
-        assert_eq!(false,device_config.should_halt_on_invalid());
-        assert!(device_config.get_throttling_config().is_none());
+        let device_config = DeviceConfig::new(QuirkConfig::original_chip8(), 10);
+        assert!(!device_config.has_high_resolution());
+        assert!(!device_config.get_quirks().shift_in_place);
+        assert!(!device_config.get_quirks().jump_with_vx);
+        assert!(!device_config.should_halt_on_invalid());
+        assert_eq!(10, device_config.get_instructions_per_frame());
+        assert!(!device_config.get_quirks().lores_half_scroll);
+        assert!(!device_config.get_quirks().count_collision_rows);
+        assert!(!device_config.is_xo_chip());
+        assert_eq!(4096, device_config.get_memory_size());
     }
     #[test]
-    fn test_device_config_throttling_enabled_and_new_chip8(){
-        let device_config = DeviceConfig::new(true, false, true, 100);
-        const EXPECTED_INSTRUCTION_TIME_MS:u64 = 10;
-        assert_eq!(true,device_config.is_new_chip8());
-        assert_eq!(false,device_config.should_halt_on_invalid());
-        assert_eq!(Some(Duration::from_millis(EXPECTED_INSTRUCTION_TIME_MS)),device_config.get_throttling_config());
-    }
-}
\ No newline at end of file
+    fn test_device_config_new_chip8(){
+        let device_config = DeviceConfig::new(QuirkConfig::new_chip8(), 15).with_halt_on_invalid(true).with_high_resolution(true);
+        assert!(device_config.get_quirks().shift_in_place);
+        assert!(!device_config.get_quirks().memory_increment);
+        assert!(device_config.should_halt_on_invalid());
+        assert!(device_config.has_high_resolution());
+        assert_eq!(15, device_config.get_instructions_per_frame());
+    }
+    #[test]
+    fn test_device_config_xo_chip_memory(){
+        let device_config = DeviceConfig::new(QuirkConfig::new_chip8(), 15).with_xo_chip(true).with_high_resolution(true);
+        assert!(device_config.is_xo_chip());
+        assert_eq!(65536, device_config.get_memory_size());
+    }
+}
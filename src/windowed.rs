@@ -0,0 +1,365 @@
+//! The windowed frontend: SDL draws the display, plays the sound and reads the keyboard
+//! while the device runs on a compute thread.
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::event::Event;
+use sdl2::EventPump;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
+use sdl2::render::{BlendMode, WindowCanvas};
+
+use porcel8::dap::DapServer;
+use porcel8::debugger::{DebugCommand, Debugger};
+use porcel8::device::Device;
+use porcel8::device::frame_limiter::FrameLimiter;
+use porcel8::device::framebuffer::FrameBuffer;
+use porcel8::device::rewind::RewindBuffer;
+use porcel8::gdb::GdbServer;
+use porcel8::rpl::RplFlagStore;
+use porcel8::state_store::SaveStateStore;
+use porcel8::symbol_map::SymbolMap;
+use porcel8::util::{EmulatorError, EmulatorResult};
+
+use crate::args::Porcel8ProgramArgs;
+use crate::create_device;
+use crate::sdl_adapters::sdl_error;
+use crate::sdl_adapters::sdl_audio_adapter::SdlAudioAdapter;
+use crate::sdl_adapters::sdl_graphics_adapter::SdlGraphicsAdapter;
+use crate::sdl_adapters::sdl_keyboard_adapter::SdlKeyboardAdapter;
+
+const WINDOW_TITLE: &str = "porcel8";
+/// Number of save state slots, saved with F1 onwards and loaded with the keys after them
+const SAVE_SLOT_COUNT: u8 = 4;
+/// Held down to rewind
+const REWIND_KEY: Keycode = Keycode::Backspace;
+
+/// A server that debuggers attach to, which controls when the device runs
+enum DebugServer {
+    Gdb(GdbServer),
+    Dap(DapServer),
+}
+
+impl DebugServer {
+    /// Run a frame if the client lets the device run, returning whether it ran
+    fn run_frame(&mut self, device: &mut Device) -> EmulatorResult<bool> {
+        match self {
+            DebugServer::Gdb(gdb_server) => gdb_server.run_frame(device),
+            DebugServer::Dap(dap_server) => dap_server.run_frame(device),
+        }
+    }
+}
+
+/// Requests from the main loop to the compute thread
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ComputeCommand {
+    Terminate,
+    SaveState(u8),
+    LoadState(u8),
+    /// Start or stop rewinding instead of running frames
+    SetRewinding(bool),
+    Debug(DebugCommand),
+}
+
+/// Run the ROM in a window until it is closed or the program exits
+pub fn run_windowed(args: &Porcel8ProgramArgs, rom: &[u8]) -> EmulatorResult<()> {
+
+    let (mut canvas, mut event_pump, audio_queue) = try_initiate_sdl(args.draw_scale)?;
+
+    let (sound_state_for_device, mut sdl_aud_adapter) = SdlAudioAdapter::new_audio(SdlAudioAdapter::AUDIO_FREQUENCY, 0.85, audio_queue);
+
+    let (frame_buffer_for_display, frame_buffer_for_device) = get_frame_buffer_references();
+    let (sdl_kb_adapter, device_keyboard) = SdlKeyboardAdapter::new_keyboard();
+
+    let mut device = create_device(args, rom, Box::new(frame_buffer_for_device), Box::new(sound_state_for_device), Box::new(device_keyboard))?;
+    let mut rpl_flag_store = RplFlagStore::new(args.flags_dir.clone().map(PathBuf::from).unwrap_or_else(RplFlagStore::default_directory), rom);
+    device.registers.rpl = rpl_flag_store.load()?;
+    let save_state_store = SaveStateStore::new(args.states_dir.clone().map(PathBuf::from).unwrap_or_else(SaveStateStore::default_directory), rom);
+
+    let rewind_buffer = RewindBuffer::with_duration(args.rewind_seconds, RewindBuffer::DEFAULT_SNAPSHOT_INTERVAL);
+
+    // the debugger starts paused, so that breakpoints can be set before the program runs
+    let debugger = args.debug.then(|| Debugger::new(true));
+    let debug_server = create_debug_server(args)?;
+
+    let (compute_command_sender, device_stopped_receiver, compute_handle) = start_compute_thread(device, rpl_flag_store, save_state_store, rewind_buffer, debugger, debug_server, args.do_instruction_throttling)?;
+    if args.debug {
+        start_debug_console(compute_command_sender.clone())?;
+    }
+
+
+    let mut sdl_graphics_adapter = SdlGraphicsAdapter::new(args.get_palette());
+
+    canvas.set_draw_color(Color::BLACK);
+    canvas.clear();
+
+    // Compute a frame time offset
+    // Thread will sleep for 60fps - (time spent computing)
+    let mut frame_timer = std::time::Instant::now();
+    'running: loop {
+        let last_time = frame_timer.elapsed();
+        // the program exited or failed, release the compute thread and close the window
+        if device_stopped_receiver.try_recv().is_ok() {
+            send_compute_command(&compute_command_sender, ComputeCommand::Terminate)?;
+            break 'running;
+        }
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. } |
+                Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
+                    send_compute_command(&compute_command_sender, ComputeCommand::Terminate)?;
+                    break 'running;
+                }
+                Event::KeyDown { keycode: Some(REWIND_KEY), repeat: false, .. } => {
+                    send_compute_command(&compute_command_sender, ComputeCommand::SetRewinding(true))?;
+                }
+                Event::KeyUp { keycode: Some(REWIND_KEY), repeat: false, .. } => {
+                    send_compute_command(&compute_command_sender, ComputeCommand::SetRewinding(false))?;
+                }
+                Event::KeyDown { keycode: Some(keycode), repeat: false, .. } if get_save_state_command(keycode).is_some() => {
+                    send_compute_command(&compute_command_sender, get_save_state_command(keycode).unwrap())?;
+                }
+                Event::KeyDown { keycode: Some(keycode), repeat: false, .. } => {
+                    sdl_kb_adapter.process_key_down(keycode)?;
+                }
+                Event::KeyUp { keycode: Some(keycode), repeat: false, .. } => {
+                    sdl_kb_adapter.process_key_up(keycode)?;
+                }
+                _ => {}
+            }
+        }
+
+        // lock and draw framebuffer
+        {
+            let lock = frame_buffer_for_display.lock()?;
+            sdl_graphics_adapter.draw_screen(lock, &mut canvas)?;
+        }
+        canvas.present();
+        sdl_aud_adapter.process_push_audio()?;
+        let sleep_duration = SdlGraphicsAdapter::FRAME_RATE_TIMING - last_time;
+        thread::sleep(sleep_duration);
+        frame_timer = std::time::Instant::now();
+    }
+
+    // Errors from the device become a failure exit status
+    compute_handle.join().expect("Failed to close compute thread")
+}
+
+/// Save or load command for a function key: F1 to F4 save, F5 to F8 load
+fn get_save_state_command(keycode: Keycode) -> Option<ComputeCommand> {
+    const SAVE_KEYS: [Keycode; SAVE_SLOT_COUNT as usize] = [Keycode::F1, Keycode::F2, Keycode::F3, Keycode::F4];
+    const LOAD_KEYS: [Keycode; SAVE_SLOT_COUNT as usize] = [Keycode::F5, Keycode::F6, Keycode::F7, Keycode::F8];
+    let slot = |keys: &[Keycode]| keys.iter().position(|key| *key == keycode).map(|index| index as u8 + 1);
+    slot(&SAVE_KEYS).map(ComputeCommand::SaveState).or_else(|| slot(&LOAD_KEYS).map(ComputeCommand::LoadState))
+}
+
+fn send_compute_command(compute_command_sender: &Sender<ComputeCommand>, command: ComputeCommand) -> EmulatorResult<()> {
+    compute_command_sender.send(command).map_err(|err| EmulatorError::IOError(format!("Could not send {:?} to the compute thread: {}", command, err)))
+}
+
+/// Command sender, stopped notification receiver and the handle of the compute thread
+type ComputeThreadHandles = (Sender<ComputeCommand>, Receiver<()>, JoinHandle<EmulatorResult<()>>);
+
+/// Run the device on a separate thread until it is terminated, the program exits or an error occurs.
+/// Save and load commands are handled between frames, and failures to save or load are only logged.
+/// While rewinding, frames step back through the rewind buffer instead of running.
+/// With a debugger, frames only run while it is not paused, and its output is printed to the standard output.
+/// A GDB or debug adapter server controls the device in the same way, between frames.
+/// Frames are paced to 60 Hz if `do_frame_limiting` is set.
+/// The returned receiver is notified if the device stopped on its own.
+fn start_compute_thread(
+    mut device: Device,
+    mut rpl_flag_store: RplFlagStore,
+    save_state_store: SaveStateStore,
+    mut rewind_buffer: RewindBuffer,
+    mut debugger: Option<Debugger>,
+    mut debug_server: Option<DebugServer>,
+    do_frame_limiting: bool,
+) -> EmulatorResult<ComputeThreadHandles> {
+    let (compute_command_sender, compute_command_receiver) = std::sync::mpsc::channel();
+    let (device_stopped_sender, device_stopped_receiver) = std::sync::mpsc::channel();
+    let compute_handle = thread::Builder::new().name("Compute".to_string()).spawn(move || {
+        let mut result = Ok(());
+        let mut is_terminated = false;
+        let mut is_rewinding = false;
+        let mut frame_limiter = FrameLimiter::new();
+        let mut is_paused = false;
+        'running: loop {
+            // while paused, block until a command arrives instead of spinning, waking up to let debug clients in
+            let mut wait_time = if is_paused { Device::FRAME_TIME } else { Duration::ZERO };
+            loop {
+                let command = compute_command_receiver.recv_timeout(wait_time);
+                wait_time = Duration::ZERO;
+                match command {
+                    Ok(ComputeCommand::Terminate) => {
+                        is_terminated = true;
+                        break 'running;
+                    }
+                    Ok(ComputeCommand::SaveState(slot)) => {
+                        if let Err(err) = save_state_store.save(slot, &device.save_state()) {
+                            log::warn!("Could not save state to slot {}: {}", slot, err);
+                        }
+                    }
+                    Ok(ComputeCommand::LoadState(slot)) => match save_state_store.load(slot) {
+                        Ok(save_state) => device.load_state(&save_state),
+                        Err(err) => log::warn!("Could not load state from slot {}: {}", slot, err),
+                    },
+                    Ok(ComputeCommand::SetRewinding(rewinding)) => is_rewinding = rewinding,
+                    Ok(ComputeCommand::Debug(command)) => {
+                        let Some(debugger) = debugger.as_mut() else {
+                            continue;
+                        };
+                        match debugger.execute(command, &mut device) {
+                            Ok(output) => println!("{}", output),
+                            Err(err) => {
+                                log::error!("Failed to execute: {}", err);
+                                result = Err(err);
+                                break 'running;
+                            }
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => panic!("Disconnected"),
+                }
+            }
+            let frame_result = if is_rewinding {
+                rewind_buffer.rewind_frame(&mut device).map(|_| true)
+            } else if let Some(debugger) = debugger.as_mut() {
+                run_debugged_frame(&mut device, debugger, &mut rewind_buffer)
+            } else if let Some(debug_server) = debug_server.as_mut() {
+                debug_server.run_frame(&mut device).inspect(|has_run| if *has_run { rewind_buffer.record_frame(&device) })
+            } else {
+                device.run_frame().map(|_| {
+                    rewind_buffer.record_frame(&device);
+                    true
+                })
+            };
+            match frame_result {
+                Ok(has_run) => is_paused = !has_run,
+                Err(err) => {
+                    log::error!("Failed to execute: {}", err);
+                    result = Err(err);
+                    break;
+                }
+            }
+            if let Err(err) = rpl_flag_store.save_changed(&device.registers.rpl) {
+                log::warn!("Could not save RPL flags: {}", err);
+            }
+            if device.has_exited() {
+                break;
+            }
+            if do_frame_limiting && !is_paused {
+                frame_limiter.wait_for_next_frame();
+            }
+        }
+        if let Err(err) = rpl_flag_store.save_changed(&device.registers.rpl) {
+            log::warn!("Could not save RPL flags: {}", err);
+        }
+        if !is_terminated {
+            // keep the device (and its keyboard) alive until the main loop stops sending events
+            device_stopped_sender.send(()).expect("Main loop disconnected");
+            while compute_command_receiver.recv().expect("Main loop disconnected") != ComputeCommand::Terminate {}
+        }
+        result
+    })?;
+    Ok((compute_command_sender, device_stopped_receiver, compute_handle))
+}
+
+/// Run a frame unless the debugger is paused, printing where a breakpoint stopped it.
+/// Returns whether a frame ran.
+fn run_debugged_frame(device: &mut Device, debugger: &mut Debugger, rewind_buffer: &mut RewindBuffer) -> EmulatorResult<bool> {
+    if debugger.is_paused() {
+        return Ok(false);
+    }
+    if let Some(breakpoint_message) = debugger.run_frame(device)? {
+        println!("{}", breakpoint_message);
+    }
+    rewind_buffer.record_frame(device);
+    Ok(true)
+}
+
+/// Listen for GDB or debug adapter clients on localhost, if either port is given
+fn create_debug_server(args: &Porcel8ProgramArgs) -> EmulatorResult<Option<DebugServer>> {
+    if let Some(port) = args.gdb {
+        return Ok(Some(DebugServer::Gdb(GdbServer::bind(("127.0.0.1", port))?)));
+    }
+    let Some(port) = args.dap else {
+        return Ok(None);
+    };
+    let mut dap_server = DapServer::bind(("127.0.0.1", port))?;
+    if let Some(symbols_file) = &args.symbols {
+        dap_server.set_symbol_map(SymbolMap::load(symbols_file)?);
+    }
+    Ok(Some(DebugServer::Dap(dap_server)))
+}
+
+/// Read debugger commands from the standard input on a separate thread, sending them to the compute thread
+fn start_debug_console(compute_command_sender: Sender<ComputeCommand>) -> EmulatorResult<()> {
+    println!("Paused, enter debugger commands or help");
+    thread::Builder::new().name("Debug console".to_string()).spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            match line.parse::<DebugCommand>() {
+                Ok(command) => {
+                    if compute_command_sender.send(ComputeCommand::Debug(command)).is_err() {
+                        break;
+                    }
+                }
+                Err(err) => println!("{}", err),
+            }
+        }
+    })?;
+    Ok(())
+}
+
+fn get_frame_buffer_references() -> (Arc<Mutex<FrameBuffer>>, Arc<Mutex<FrameBuffer>>) {
+    let arc = Arc::new(Mutex::new(FrameBuffer::new()));
+    let arc2 = Arc::clone(&arc);
+    (arc, arc2)
+}
+
+/// Initiate SDL resources:
+/// 1. A window canvas for drawing
+/// 2. An event pump for use as an event loop,
+/// 3. An Audio queue for sound
+fn try_initiate_sdl(draw_scale: f32) -> EmulatorResult<(WindowCanvas, EventPump, AudioQueue<f32>)> {
+    let sdl_context = sdl2::init().unwrap();
+    let video_subsystem = sdl_context.video().unwrap();
+    let audio_subsystem = sdl_context.audio().unwrap();
+    let wanted_spec = AudioSpecDesired {
+        channels: Some(1),
+        samples: None,
+        freq: Some(SdlAudioAdapter::SAMPLING_FREQ),
+    };
+
+    let audio_queue = audio_subsystem.open_queue::<f32, _>(None, &wanted_spec)?;
+
+    // The window is sized for the low resolution mode and is not resized when the program switches resolution:
+    // the high resolution mode has the same 2:1 aspect ratio, so the graphics adapter draws it at half the scale instead
+    let window_width = (FrameBuffer::LOW_RES_WIDTH as f32 * draw_scale) as u32;
+    let window_height = (FrameBuffer::LOW_RES_HEIGHT as f32 * draw_scale) as u32;
+
+    let window = video_subsystem.window(WINDOW_TITLE, window_width, window_height)
+        .position_centered()
+        .build()
+        .map_err(sdl_error)?;
+    let mut canvas = window.into_canvas().build().map_err(sdl_error)?;
+
+    canvas.set_logical_size(FrameBuffer::LOW_RES_WIDTH as u32, FrameBuffer::LOW_RES_HEIGHT as u32).map_err(sdl_error)?;
+
+    canvas.set_blend_mode(BlendMode::None);
+    canvas.clear();
+    canvas.present();
+    let event_pump = sdl_context.event_pump()?;
+    Ok((canvas, event_pump, audio_queue))
+}